@@ -0,0 +1,23 @@
+internal-error = Wegen einer technischen Störung konnte Ihre Nachricht leider nicht geliefert werden.
+client-error-missing-fields = Einige Pflichtfelder des Formulars fehlen.
+client-error-invalid-email = Die eingegebene E-Mail-Adresse ist ungültig.
+client-error-invalid-field = Einige Felder des Formulars enthalten ungültige Werte.
+client-error-invalid-language = Die Sprache des Formulars ist ungültig.
+client-error-invalid-form-id = Das abgeschickte Formular konnte nicht zugeordnet werden.
+client-error-request-too-large = Ihre Nachricht ist zu lang. Bitte kürzen Sie sie und versuchen Sie es erneut.
+client-error-captcha-invalid = Die Spam-Prüfung ist fehlgeschlagen. Bitte versuchen Sie es erneut.
+client-error-captcha-expired = Die Spam-Prüfung ist abgelaufen oder wurde bereits verwendet. Bitte laden Sie die Seite neu und versuchen Sie es erneut.
+client-error-retry-token-invalid = Der Link zum erneuten Senden Ihrer Nachricht ist abgelaufen. Bitte füllen Sie das Formular erneut aus.
+client-error-missing-consent = Bitte stimmen Sie der Datenschutzerklärung zu, damit wir Ihre Nachricht bearbeiten können.
+client-error-sender-blocked = Ihre Nachricht konnte nicht angenommen werden. Bitte kontaktieren Sie uns auf anderem Wege.
+client-error-submission-in-progress = Ihre Nachricht wird bereits gesendet. Bitte warten Sie einen Moment, bevor Sie sie erneut absenden.
+client-error-subscription-token-invalid = Der Bestätigungslink ist ungültig oder abgelaufen. Bitte melden Sie sich erneut an.
+subscription-confirmation-subject = Bitte bestätigen Sie Ihre Anmeldung
+subscription-confirmation-body =
+    Sie haben sich für Neuigkeiten von hovinen.tech angemeldet. Bitte bestätigen Sie Ihre Anmeldung, indem Sie den folgenden Link öffnen:
+
+    { $link }
+
+    Falls Sie sich nicht angemeldet haben, können Sie diese E-Mail ignorieren. Sie werden dann nicht angemeldet.
+error-page-retry-button = Erneut senden
+error-page-mailto-link = Nachricht stattdessen mit Ihrem E-Mail-Programm senden
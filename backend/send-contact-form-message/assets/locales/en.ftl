@@ -0,0 +1,23 @@
+internal-error = Due to an internal error, your message unfortunately could not be delivered.
+client-error-missing-fields = Some required fields of the form are missing.
+client-error-invalid-email = The email address you entered is not valid.
+client-error-invalid-field = Some fields of the form contain invalid values.
+client-error-invalid-language = The language of the form is not valid.
+client-error-invalid-form-id = The form you submitted could not be identified.
+client-error-request-too-large = Your message is too long. Please shorten it and try again.
+client-error-captcha-invalid = The anti-spam check failed. Please try again.
+client-error-captcha-expired = The anti-spam check has expired or was already used. Please reload the page and try again.
+client-error-retry-token-invalid = The link to send your message again has expired. Please fill in the form again.
+client-error-missing-consent = Please accept the privacy notice so that we can process your message.
+client-error-sender-blocked = Your message could not be accepted. Please contact us by other means.
+client-error-submission-in-progress = Your message is already being sent. Please wait a moment before submitting it again.
+client-error-subscription-token-invalid = The confirmation link is invalid or has expired. Please subscribe again.
+subscription-confirmation-subject = Please confirm your subscription
+subscription-confirmation-body =
+    You asked to receive news from hovinen.tech. Please confirm your subscription by opening the following link:
+
+    { $link }
+
+    If you did not ask for this, you can ignore this email and you will not be subscribed.
+error-page-retry-button = Try sending again
+error-page-mailto-link = Send the message from your email program instead
@@ -0,0 +1,304 @@
+use crate::{secrets::load_aws_config, FROM_ADDRESS, TO_ADDRESS};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Client;
+use serde::Serialize;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+const ALERT_SUBJECT: &str = "Contact form backend: internal error";
+const DEFAULT_DEDUPLICATION_WINDOW: Duration = Duration::from_secs(60 * 60);
+/// How long sending an alert may take. Alerts are sent while the visitor waits for the error page,
+/// so an unresponsive channel must not hold up the response for long.
+const ALERT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Notifies the site owner of internal errors through a channel other than the one which failed.
+///
+/// The channel is chosen at startup from the environment:
+///
+///  * `ALERT_WEBHOOK_URL`: POST a JSON document `{"subject": ..., "description": ...}` to the
+///    given URL.
+///  * `ALERT_SNS_TOPIC_ARN`: publish to the given SNS topic.
+///  * `ALERT_SMTP_URL`: send an email through the given (fallback) SMTP server to
+///    `ALERT_EMAIL_TO`, or to the usual recipient if that is not set. Credentials may be included
+///    in the URL.
+///
+/// If none of these is set, internal errors are only logged.
+///
+/// An alert of the same [`AlertKind`] as one sent within the deduplication window
+/// (`ALERT_DEDUPLICATION_WINDOW_SECS`, one hour by default) is suppressed, so that a broken
+/// configuration does not produce one alert per visitor. Alerts which could not be sent are not
+/// counted. Since the record of sent alerts is held in memory, this only applies within one warm
+/// instance.
+pub struct Alerter {
+    channel: Option<AlertChannel>,
+    deduplication_window: Duration,
+    recent_alerts: Mutex<HashMap<AlertKind, Instant>>,
+}
+
+/// The problem which an alert reports. Repeated alerts are recognised by their kind rather than
+/// their description, which may differ between occurrences of the same problem, e.g. in the relay
+/// or the error message from upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    MissingPayload,
+    TokenKeyUnavailable,
+    CaptchaVerification,
+    MessageConstruction,
+    Delivery,
+    SenderRules,
+    DkimSigning,
+    Subscription,
+}
+
+enum AlertChannel {
+    Webhook(String),
+    Sns(aws_sdk_sns::Client, String),
+    Smtp(AsyncSmtpTransport<Tokio1Executor>, Mailbox),
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    subject: &'a str,
+    description: &'a str,
+}
+
+impl Alerter {
+    pub async fn from_environment() -> Self {
+        let channel = match AlertChannel::from_environment().await {
+            Ok(channel) => channel,
+            Err(error) => {
+                error!("Invalid alerting configuration, alerts will not be sent: {error}");
+                None
+            }
+        };
+        let deduplication_window = std::env::var("ALERT_DEDUPLICATION_WINDOW_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_DEDUPLICATION_WINDOW);
+        Self {
+            channel,
+            deduplication_window,
+            recent_alerts: Default::default(),
+        }
+    }
+
+    pub async fn alert(&self, kind: AlertKind, description: &str) {
+        let Some(channel) = self.channel.as_ref() else {
+            return;
+        };
+        if !self.record_alert(kind).await {
+            info!("Suppressing repeated alert: {description}");
+            return;
+        }
+        let result = match tokio::time::timeout(ALERT_TIMEOUT, channel.send(description)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("Timed out after {ALERT_TIMEOUT:?}").into()),
+        };
+        if let Err(error) = result {
+            error!("Unable to send alert: {error}");
+            self.forget_alert(kind).await;
+        }
+    }
+
+    /// Records that an alert of the given kind is about to be sent.
+    ///
+    /// Returns `false` if an alert of the same kind was already sent, or is being sent, within the
+    /// deduplication window, in which case it should be suppressed.
+    async fn record_alert(&self, kind: AlertKind) -> bool {
+        let now = Instant::now();
+        let mut recent_alerts = self.recent_alerts.lock().await;
+        recent_alerts.retain(|_, sent_at| now.duration_since(*sent_at) < self.deduplication_window);
+        match recent_alerts.entry(kind) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+
+    /// Removes the record of an alert which could not be sent, so that the next occurrence of the
+    /// error is alerted again.
+    async fn forget_alert(&self, kind: AlertKind) {
+        self.recent_alerts.lock().await.remove(&kind);
+    }
+}
+
+impl AlertChannel {
+    async fn from_environment() -> Result<Option<Self>, lambda_http::Error> {
+        if let Ok(url) = std::env::var("ALERT_WEBHOOK_URL") {
+            Ok(Some(Self::Webhook(url)))
+        } else if let Ok(topic_arn) = std::env::var("ALERT_SNS_TOPIC_ARN") {
+            let config = load_aws_config(None).await;
+            Ok(Some(Self::Sns(
+                aws_sdk_sns::Client::new(&config),
+                topic_arn,
+            )))
+        } else if let Ok(smtp_url) = std::env::var("ALERT_SMTP_URL") {
+            let recipient = std::env::var("ALERT_EMAIL_TO")
+                .as_deref()
+                .unwrap_or(TO_ADDRESS)
+                .parse()?;
+            let transport = AsyncSmtpTransport::<Tokio1Executor>::from_url(&smtp_url)?.build();
+            Ok(Some(Self::Smtp(transport, recipient)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn send(&self, description: &str) -> Result<(), lambda_http::Error> {
+        match self {
+            AlertChannel::Webhook(url) => {
+                Client::new()
+                    .post(url)
+                    .json(&WebhookPayload {
+                        subject: ALERT_SUBJECT,
+                        description,
+                    })
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            AlertChannel::Sns(client, topic_arn) => {
+                client
+                    .publish()
+                    .topic_arn(topic_arn)
+                    .subject(ALERT_SUBJECT)
+                    .message(description)
+                    .send()
+                    .await?;
+            }
+            AlertChannel::Smtp(transport, recipient) => {
+                let message = Message::builder()
+                    .from(FROM_ADDRESS.parse()?)
+                    .to(recipient.clone())
+                    .subject(ALERT_SUBJECT)
+                    .header(ContentType::TEXT_PLAIN)
+                    .body(description.to_string())?;
+                transport.send(message).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertChannel, AlertKind, Alerter};
+    use googletest::prelude::*;
+    use serial_test::serial;
+    use std::time::Duration;
+    use test_support::fake_webhook::FakeWebhook;
+    use tokio::time::timeout;
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_description_to_webhook() {
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let subject = alerter_with_window(Duration::from_secs(60));
+
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_webhook.last_request_body()).await,
+            ok(ok(contains_substring("SMTP server down")))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn suppresses_repeated_alert_within_deduplication_window() {
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let subject = alerter_with_window(Duration::from_secs(60));
+
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+
+        expect_that!(fake_webhook.request_count(), eq(1));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn suppresses_alert_of_same_kind_with_other_description() {
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let subject = alerter_with_window(Duration::from_secs(60));
+
+        subject
+            .alert(AlertKind::Delivery, "smtp://a.example: connection refused")
+            .await;
+        subject
+            .alert(AlertKind::Delivery, "smtp://b.example: connection refused")
+            .await;
+
+        expect_that!(fake_webhook.request_count(), eq(1));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_distinct_alerts_within_deduplication_window() {
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let subject = alerter_with_window(Duration::from_secs(60));
+
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+        subject
+            .alert(
+                AlertKind::CaptchaVerification,
+                "Incorrect FriendlyCaptcha secret",
+            )
+            .await;
+
+        expect_that!(fake_webhook.request_count(), eq(2));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_suppress_alert_after_failed_attempt() {
+        let fake_webhook = FakeWebhook::new();
+        let subject = alerter_with_window(Duration::from_secs(60));
+
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+        fake_webhook.start().await;
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+
+        expect_that!(fake_webhook.request_count(), eq(1));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn repeats_alert_after_deduplication_window() {
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let subject = alerter_with_window(Duration::ZERO);
+
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+        subject.alert(AlertKind::Delivery, "SMTP server down").await;
+
+        expect_that!(fake_webhook.request_count(), eq(2));
+    }
+
+    fn alerter_with_window(deduplication_window: Duration) -> Alerter {
+        Alerter {
+            channel: Some(AlertChannel::Webhook(FakeWebhook::url())),
+            deduplication_window,
+            recent_alerts: Default::default(),
+        }
+    }
+}
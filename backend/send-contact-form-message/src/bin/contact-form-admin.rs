@@ -0,0 +1,90 @@
+//! Operates a deployment of the contact form handler.
+//!
+//! Usage:
+//!
+//!  * `contact-form-admin send-test <url> [--email <address>] [--language <language>]
+//!    [--form-id <id>]`: sends a test submission through the deployment at `<url>`;
+//!  * `contact-form-admin print-config`: prints the configuration from the environment;
+//!  * `contact-form-admin validate-templates [<directory>]`: checks the templates in the
+//!    directory, or in the configured template source;
+//!  * `contact-form-admin check-secrets`: checks that the secrets exist and have the expected form;
+//!  * `contact-form-admin provision-secrets <file>`: stores the secrets from a file in the format of
+//!    `SECRETS_FILE` in AWS Secrets Manager.
+//!
+//! The deployment is described by the same environment variables as for the handler itself.
+
+use lambda_http::Error;
+use send_contact_form_message::admin::{
+    check_secrets, print_configuration, provision_secrets, send_test_submission,
+    validate_templates, TestSubmission,
+};
+use std::path::PathBuf;
+
+const DEFAULT_TEST_EMAIL: &str = "contact-form-admin@hovinen.tech";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_target(false)
+        .without_time()
+        .init();
+
+    match Command::parse(std::env::args().skip(1))? {
+        Command::SendTest(submission) => send_test_submission(&submission).await,
+        Command::PrintConfig => print_configuration().await,
+        Command::ValidateTemplates(directory) => validate_templates(directory.as_deref()).await,
+        Command::CheckSecrets => check_secrets().await,
+        Command::ProvisionSecrets(path) => provision_secrets(&path).await,
+    }
+}
+
+enum Command {
+    SendTest(TestSubmission),
+    PrintConfig,
+    ValidateTemplates(Option<PathBuf>),
+    CheckSecrets,
+    ProvisionSecrets(PathBuf),
+}
+
+impl Command {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let command = args.next().ok_or(
+            "Missing command, expected one of send-test, print-config, validate-templates, \
+            check-secrets, provision-secrets",
+        )?;
+        let command = match command.as_str() {
+            "send-test" => {
+                let mut submission = TestSubmission {
+                    url: args.next().ok_or("Missing URL for send-test")?,
+                    email: DEFAULT_TEST_EMAIL.into(),
+                    language: "en".into(),
+                    form_id: None,
+                };
+                while let Some(arg) = args.next() {
+                    let mut value = || args.next().ok_or(format!("Missing value for {arg}"));
+                    match arg.as_str() {
+                        "--email" => submission.email = value()?,
+                        "--language" => submission.language = value()?,
+                        "--form-id" => submission.form_id = Some(value()?),
+                        _ => return Err(format!("Unrecognised argument {arg}").into()),
+                    }
+                }
+                return Ok(Self::SendTest(submission));
+            }
+            "print-config" => Self::PrintConfig,
+            "validate-templates" => Self::ValidateTemplates(args.next().map(PathBuf::from)),
+            "check-secrets" => Self::CheckSecrets,
+            "provision-secrets" => Self::ProvisionSecrets(
+                args.next()
+                    .ok_or("Missing file for provision-secrets")?
+                    .into(),
+            ),
+            _ => return Err(format!("Unrecognised command {command}").into()),
+        };
+        if let Some(arg) = args.next() {
+            return Err(format!("Unrecognised argument {arg}").into());
+        }
+        Ok(command)
+    }
+}
@@ -0,0 +1,122 @@
+//! Serves the contact form handler over plain HTTP, for local development alongside
+//! `jekyll serve` and for hosting outside of AWS Lambda.
+//!
+//! Usage: `contact-form-server [--port <port>] [--dev]`
+//!
+//! The port defaults to the environment variable `PORT`, or 3000 if that is not set. With `--dev`,
+//! emails are printed to standard output instead of being sent.
+
+use axum::{
+    body::{Body as AxumBody, Bytes},
+    extract::{ConnectInfo, DefaultBodyLimit, State},
+    http::request::Parts,
+    response::{IntoResponse, Response as AxumResponse},
+    Router,
+};
+use lambda_http::{http::StatusCode, Body, Error, Request};
+use send_contact_form_message::{
+    secrets::{CachingSecretRepository, ConfiguredSecretRepository},
+    ContactFormMessageHandler, PeerAddress,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+const DEFAULT_PORT: u16 = 3000;
+
+type Handler = ContactFormMessageHandler<CachingSecretRepository<ConfiguredSecretRepository>>;
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_target(false)
+        .init();
+
+    let options = Options::parse(std::env::args().skip(1))?;
+    let mut handler = Handler::new().await;
+    if options.dev_mode {
+        info!("Development mode: printing emails instead of sending them");
+        handler = handler.printing_emails();
+    }
+
+    // Larger bodies are rejected with 413 Payload Too Large before they are read completely.
+    let body_limit = DefaultBodyLimit::max(handler.max_request_bytes());
+    let app = Router::new()
+        .fallback(serve_request)
+        .layer(body_limit)
+        .with_state(Arc::new(handler));
+    let listener = TcpListener::bind(format!("0.0.0.0:{}", options.port)).await?;
+    info!("Listening on port {}", options.port);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+struct Options {
+    port: u16,
+    dev_mode: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut options = Options {
+            port: std::env::var("PORT")
+                .ok()
+                .map(|port| port.parse())
+                .transpose()?
+                .unwrap_or(DEFAULT_PORT),
+            dev_mode: false,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dev" => options.dev_mode = true,
+                "--port" => {
+                    options.port = args.next().ok_or("Missing value for --port")?.parse()?;
+                }
+                _ => return Err(format!("Unrecognised argument {arg}").into()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+async fn serve_request(
+    State(handler): State<Arc<Handler>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    parts: Parts,
+    body: Bytes,
+) -> AxumResponse {
+    let mut event = into_lambda_request(parts, body);
+    // The handler takes the visitor's address from the connection, or from the proxies in
+    // TRUSTED_PROXIES in front of this server, rather than from headers which the client controls.
+    event.extensions_mut().insert(PeerAddress(peer.ip()));
+    match handler.handle(event).await {
+        Ok(response) => response
+            .map(|body| match body {
+                Body::Empty => AxumBody::empty(),
+                Body::Text(text) => AxumBody::from(text),
+                Body::Binary(bytes) => AxumBody::from(bytes),
+            })
+            .into_response(),
+        Err(error) => {
+            error!("Error handling request: {error}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn into_lambda_request(parts: Parts, bytes: Bytes) -> Request {
+    let body = if bytes.is_empty() {
+        Body::Empty
+    } else {
+        match String::from_utf8(bytes.to_vec()) {
+            Ok(text) => Body::Text(text),
+            Err(error) => Body::Binary(error.into_bytes()),
+        }
+    };
+    Request::from_parts(parts, body)
+}
@@ -0,0 +1,246 @@
+use chrono::{SecondsFormat, Utc};
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use lambda_http::{http::HeaderValue, request::RequestContext, Request, RequestExt};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::IpAddr;
+
+pub const IP_HASH_KEY_NAME: &str = "consent-ip-hash-key";
+
+const UNSPECIFIED_PRIVACY_POLICY_VERSION: &str = "unspecified";
+
+/// Returns the version of the privacy policy for forms whose schema does not name one, as
+/// configured by the environment variable `PRIVACY_POLICY_VERSION`.
+pub fn default_privacy_policy_version() -> String {
+    std::env::var("PRIVACY_POLICY_VERSION").unwrap_or(UNSPECIFIED_PRIVACY_POLICY_VERSION.into())
+}
+
+/// The key with which IP addresses are hashed, stored in the secret `consent-ip-hash-key`.
+///
+/// A keyed hash is used because the space of IP addresses is small enough to reverse a plain hash
+/// by brute force.
+#[derive(Deserialize)]
+pub struct IpHashKey {
+    #[serde(rename = "CONSENT_IP_HASH_KEY")]
+    key: String,
+}
+
+impl IpHashKey {
+    pub fn hash(&self, ip: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(ip.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// Proof that the visitor accepted the privacy notice, recorded in the headers of the notification
+/// email.
+#[derive(Serialize, Clone, Debug)]
+pub struct ConsentRecord {
+    pub privacy_policy_version: String,
+    /// When the consent was given, in RFC 3339 format.
+    pub timestamp: String,
+    /// The keyed hash of the visitor's IP address, if both are available.
+    pub ip_hash: Option<String>,
+    pub language: String,
+}
+
+impl ConsentRecord {
+    pub fn now(privacy_policy_version: String, ip_hash: Option<String>, language: String) -> Self {
+        Self {
+            privacy_policy_version,
+            timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            ip_hash,
+            language,
+        }
+    }
+
+    /// Returns the names and values of the headers which record the consent.
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            (
+                "X-Privacy-Consent-Policy-Version",
+                self.privacy_policy_version.clone(),
+            ),
+            ("X-Privacy-Consent-Timestamp", self.timestamp.clone()),
+            ("X-Privacy-Consent-Language", self.language.clone()),
+        ];
+        if let Some(ip_hash) = &self.ip_hash {
+            headers.push(("X-Privacy-Consent-IP-Hash", ip_hash.clone()));
+        }
+        headers
+    }
+}
+
+/// The address of the peer of the TCP connection over which a request arrived, which servers
+/// other than API Gateway and Application Load Balancers add to the request's extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddress(pub IpAddr);
+
+/// Returns the IP address of the visitor, taking only addresses which infrastructure vouches for:
+///
+///  * the source IP reported by API Gateway or a Lambda Function URL;
+///  * behind an Application Load Balancer, the last address in `X-Forwarded-For`, which the load
+///    balancer appended;
+///  * otherwise the [`PeerAddress`] of the connection.
+///
+/// If that address belongs to one of the networks in the comma-separated environment variable
+/// `TRUSTED_PROXIES`, the address which that proxy appended to `X-Forwarded-For` is taken instead,
+/// and so on. Addresses further to the left were supplied by the client and are never used, since
+/// anyone can put any address there.
+pub fn client_ip(event: &Request) -> Option<String> {
+    let source_ip = match event.request_context_ref() {
+        Some(RequestContext::ApiGatewayV1(context)) => context.identity.source_ip.clone(),
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.clone(),
+        Some(RequestContext::WebSocket(context)) => context.identity.source_ip.clone(),
+        _ => None,
+    };
+    if source_ip.is_some() {
+        return source_ip;
+    }
+    let mut forwarded: Vec<Option<IpAddr>> = event
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+        .map(|value| value.split(',').map(|ip| ip.trim().parse().ok()).collect())
+        .unwrap_or_default();
+    let mut ip = match event.request_context_ref() {
+        Some(RequestContext::Alb(_)) => forwarded.pop()??,
+        _ => event.extensions().get::<PeerAddress>()?.0,
+    };
+    let trusted_proxies = trusted_proxies();
+    while trusted_proxies.iter().any(|network| network.contains(&ip)) {
+        let Some(Some(forwarded_ip)) = forwarded.pop() else {
+            break;
+        };
+        ip = forwarded_ip;
+    }
+    Some(ip.to_string())
+}
+
+fn trusted_proxies() -> Vec<IpNet> {
+    std::env::var("TRUSTED_PROXIES")
+        .map(|value| {
+            value
+                .split(',')
+                .filter_map(|network| {
+                    let network = network.trim();
+                    network.parse().ok().or_else(|| {
+                        let ip: IpAddr = network.parse().ok()?;
+                        Some(IpNet::from(ip))
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, IpHashKey, PeerAddress};
+    use googletest::prelude::*;
+    use lambda_http::{http::HeaderValue, Body, Request};
+    use serial_test::serial;
+    use test_support::TemporaryEnv;
+
+    #[test]
+    fn hashes_ip_with_key() -> Result<()> {
+        let key = IpHashKey {
+            key: "arbitrary key".into(),
+        };
+        let other_key = IpHashKey {
+            key: "other key".into(),
+        };
+
+        verify_that!(
+            key.hash("192.0.2.1"),
+            all!(
+                not(contains_substring("192.0.2.1")),
+                not(eq(other_key.hash("192.0.2.1"))),
+                eq(key.hash("192.0.2.1"))
+            )
+        )
+    }
+
+    #[test]
+    fn ignores_forwarded_addresses_without_trusted_proxy() -> Result<()> {
+        let mut event = Request::new(Body::Empty);
+        event.headers_mut().append(
+            "X-Forwarded-For",
+            HeaderValue::from_static("198.51.100.7, 192.0.2.1"),
+        );
+
+        verify_that!(client_ip(&event), none())
+    }
+
+    #[test]
+    fn takes_client_ip_from_peer_address() -> Result<()> {
+        let mut event = Request::new(Body::Empty);
+        event
+            .headers_mut()
+            .append("X-Forwarded-For", HeaderValue::from_static("198.51.100.7"));
+        event
+            .extensions_mut()
+            .insert(PeerAddress("192.0.2.1".parse().unwrap()));
+
+        verify_that!(client_ip(&event), some(eq("192.0.2.1")))
+    }
+
+    #[test]
+    #[serial]
+    fn takes_client_ip_appended_by_trusted_proxy() -> Result<()> {
+        let _env = TemporaryEnv::new("TRUSTED_PROXIES", "10.0.0.0/8, 172.16.0.1");
+        let mut event = Request::new(Body::Empty);
+        event.headers_mut().append(
+            "X-Forwarded-For",
+            HeaderValue::from_static("198.51.100.7, 192.0.2.1, 172.16.0.1"),
+        );
+        event
+            .extensions_mut()
+            .insert(PeerAddress("10.0.0.2".parse().unwrap()));
+
+        verify_that!(client_ip(&event), some(eq("192.0.2.1")))
+    }
+
+    #[test]
+    fn takes_client_ip_appended_by_load_balancer() -> Result<()> {
+        let event = lambda_http::request::from_str(
+            r#"{
+                "requestContext": {
+                    "elb": {"targetGroupArn": "arn:aws:elasticloadbalancing:eu-north-1:123456789012:targetgroup/contact/0123456789abcdef"}
+                },
+                "httpMethod": "POST",
+                "path": "/",
+                "headers": {"x-forwarded-for": "198.51.100.7, 192.0.2.1"},
+                "body": ""
+            }"#,
+        )
+        .unwrap();
+
+        verify_that!(client_ip(&event), some(eq("192.0.2.1")))
+    }
+
+    #[test]
+    fn takes_client_ip_from_http_api_request_context() -> Result<()> {
+        let event = lambda_http::request::from_str(
+            r#"{
+                "version": "2.0",
+                "routeKey": "$default",
+                "rawPath": "/",
+                "headers": {"x-forwarded-for": "198.51.100.2"},
+                "requestContext": {
+                    "http": {"method": "POST", "path": "/", "sourceIp": "192.0.2.1"}
+                }
+            }"#,
+        )
+        .unwrap();
+
+        verify_that!(client_ip(&event), some(eq("192.0.2.1")))
+    }
+}
@@ -0,0 +1,70 @@
+use lambda_http::Error;
+use lettre::message::{
+    dkim::{
+        DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm,
+        DkimSigningKey,
+    },
+    header::HeaderName,
+};
+use serde::Deserialize;
+
+pub const DKIM_SIGNING_KEY_NAME: &str = "dkim-signing-key";
+
+const SIGNED_HEADERS: [&str; 5] = ["From", "Reply-To", "To", "Subject", "Date"];
+
+/// Returns whether outgoing messages should be DKIM-signed, as configured by the environment
+/// variable `DKIM_SIGNING_ENABLED`.
+pub fn dkim_signing_enabled() -> bool {
+    std::env::var("DKIM_SIGNING_ENABLED").is_ok_and(|value| value == "true")
+}
+
+/// The DKIM selector and private key, stored in the secret `dkim-signing-key`.
+///
+/// `DKIM_PRIVATE_KEY` is a PKCS#1 PEM key for `rsa` (the default algorithm) or the base64-encoded
+/// raw key for `ed25519`. `DKIM_DOMAIN` defaults to the domain of the sender address.
+#[derive(Deserialize)]
+pub struct DkimSigningKeySecret {
+    #[serde(rename = "DKIM_SELECTOR")]
+    selector: String,
+    #[serde(rename = "DKIM_PRIVATE_KEY")]
+    private_key: String,
+    #[serde(rename = "DKIM_ALGORITHM", default)]
+    algorithm: DkimAlgorithm,
+    #[serde(rename = "DKIM_DOMAIN", default)]
+    domain: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum DkimAlgorithm {
+    #[default]
+    Rsa,
+    Ed25519,
+}
+
+impl DkimSigningKeySecret {
+    pub fn into_config(self, default_domain: &str) -> Result<DkimConfig, Error> {
+        let algorithm = match self.algorithm {
+            DkimAlgorithm::Rsa => DkimSigningAlgorithm::Rsa,
+            DkimAlgorithm::Ed25519 => DkimSigningAlgorithm::Ed25519,
+        };
+        let private_key = DkimSigningKey::new(&self.private_key, algorithm)
+            .map_err(|error| format!("Invalid DKIM private key: {error}"))?;
+        // Relaxed header canonicalization lets the signature survive relays which refold headers.
+        // It also covers the folding of the signature header itself, which lettre changes after
+        // computing the signature.
+        Ok(DkimConfig::new(
+            self.selector,
+            self.domain.unwrap_or(default_domain.into()),
+            private_key,
+            SIGNED_HEADERS
+                .into_iter()
+                .map(HeaderName::new_from_ascii_str)
+                .collect(),
+            DkimCanonicalization {
+                header: DkimCanonicalizationType::Relaxed,
+                body: DkimCanonicalizationType::Relaxed,
+            },
+        ))
+    }
+}
@@ -1,42 +1,153 @@
-use crate::BASE_HOST;
+use crate::{
+    form_schema::FormField,
+    template_source::{TemplateFile, TemplateVariants},
+    BASE_HOST,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 use serde::Serialize;
 use serde_json::Value;
+use std::collections::BTreeSet;
 use tinytemplate::{error::Error, format, TinyTemplate};
+use tracing::info;
 
-const SEND_ERROR_TEMPLATE_NAME_EN: &str = "send-error-template-en";
-const SEND_ERROR_TEMPLATE_NAME_DE: &str = "send-error-template-de";
-const SEND_ERROR_TEMPLATE_EN: &str = include_str!(concat!(
-    env!("CARGO_MANIFEST_DIR"),
-    "/assets/send-error.html"
-));
-const SEND_ERROR_TEMPLATE_DE: &str = include_str!(concat!(
-    env!("CARGO_MANIFEST_DIR"),
-    "/assets/send-error.de.html"
-));
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+const SEND_ERROR_TEMPLATE_NAME: &str = "send-error";
+
+/// The characters which are percent-encoded in the fields of a `mailto:` URL: all but the
+/// unreserved characters of RFC 3986.
+const MAILTO_FIELD: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// What the error page tells the visitor.
+pub struct ErrorPageContent<'a> {
+    /// The explanation of what went wrong, from the message catalogue.
+    pub explanation: &'a str,
+    pub subject: &'a str,
+    pub body: &'a str,
+    /// The fields of the form beyond the subject and body.
+    pub fields: &'a [FormField],
+    /// A token with which the visitor can send the message again without solving another captcha,
+    /// if one could be issued.
+    pub retry_token: Option<&'a str>,
+    /// The label of the button which sends the message again, from the message catalogue.
+    pub retry_label: &'a str,
+    /// A `mailto:` URL with which the visitor can send the message from their email program.
+    pub mailto_url: &'a str,
+    /// The text of the link to the `mailto:` URL, from the message catalogue.
+    pub mailto_label: &'a str,
+}
 
 #[derive(Serialize)]
 struct Context {
     site_root: String,
+    explanation: String,
     subject: String,
     body: String,
+    fields: Vec<FormField>,
+    retry_token: Option<String>,
+    retry_label: String,
+    mailto_url: String,
+    mailto_label: String,
+}
+
+/// The error page templates for each site and language, loaded at cold start.
+///
+/// The template source contains `send-error.html` for the default language (English),
+/// `send-error.<language>.html` for any further languages, and optionally
+/// `sites/<host>/send-error[.<language>].html` to override these for the site at `<host>`. Other
+/// files are ignored.
+pub struct ErrorPages {
+    templates: TemplateVariants,
+}
+
+impl ErrorPages {
+    /// Collects the error page templates from the loaded template files and checks that each of
+    /// them renders.
+    pub fn from_files(files: &[TemplateFile]) -> Result<Self, lambda_http::Error> {
+        let templates = TemplateVariants::collect(SEND_ERROR_TEMPLATE_NAME, files);
+        for file in templates.files() {
+            render_template(&file.content, &Context::sample())
+                .map_err(|error| format!("Invalid template {}: {error}", file.path))?;
+        }
+        if !templates.has_default() {
+            return Err(format!("Missing template {SEND_ERROR_TEMPLATE_NAME}.html").into());
+        }
+        let error_pages = Self { templates };
+        info!(
+            "Loaded error pages for languages {:?}",
+            error_pages.languages()
+        );
+        Ok(error_pages)
+    }
+
+    /// Returns the languages for which there is a template, not counting site overrides.
+    pub fn languages(&self) -> BTreeSet<&str> {
+        self.templates.languages()
+    }
+
+    /// Renders the error page in the given language, falling back to its more general forms and
+    /// then to the default language, e.g. `de-AT`, `de`, `en`. If there are templates for the
+    /// given site, they take precedence.
+    pub fn render(&self, content: &ErrorPageContent, language: &str, site: Option<&str>) -> String {
+        let template = self
+            .templates
+            .select(language, site)
+            .expect("Template for default language is checked when loading");
+        let context = Context {
+            site_root: format!(
+                "https://{}",
+                self.templates.known_site(site).unwrap_or(BASE_HOST)
+            ),
+            explanation: content.explanation.into(),
+            subject: content.subject.into(),
+            body: content.body.into(),
+            fields: content.fields.to_vec(),
+            retry_token: content.retry_token.map(String::from),
+            retry_label: content.retry_label.into(),
+            mailto_url: content.mailto_url.into(),
+            mailto_label: content.mailto_label.into(),
+        };
+        render_template(template, &context).expect("Templates are checked when loading")
+    }
 }
 
-pub fn render_error_page<'a>(subject: &'a str, body: &'a str, language: &'a str) -> String {
+impl Context {
+    fn sample() -> Self {
+        Self {
+            site_root: format!("https://{BASE_HOST}"),
+            explanation: "Explanation".into(),
+            subject: "Subject".into(),
+            body: "Body".into(),
+            fields: vec![FormField {
+                label: "Label".into(),
+                value: "Value".into(),
+            }],
+            retry_token: Some("Token".into()),
+            retry_label: "Retry".into(),
+            mailto_url: "mailto:someone@example.com".into(),
+            mailto_label: "Send by email".into(),
+        }
+    }
+}
+
+/// Returns a `mailto:` URL which prefills the subject and body of a message to the given address.
+pub fn mailto_url(to: &str, subject: &str, body: &str) -> String {
+    format!(
+        "mailto:{to}?subject={}&body={}",
+        utf8_percent_encode(subject, MAILTO_FIELD),
+        utf8_percent_encode(body, MAILTO_FIELD)
+    )
+}
+
+fn render_template(template: &str, context: &Context) -> Result<String, Error> {
     let mut tt = TinyTemplate::new();
     tt.add_formatter("render_paragraphs", render_paragraphs);
-    tt.add_template(SEND_ERROR_TEMPLATE_NAME_EN, SEND_ERROR_TEMPLATE_EN)
-        .unwrap();
-    tt.add_template(SEND_ERROR_TEMPLATE_NAME_DE, SEND_ERROR_TEMPLATE_DE)
-        .unwrap();
-    let context = Context {
-        site_root: format!("https://{BASE_HOST}"),
-        subject: subject.into(),
-        body: body.into(),
-    };
-    match language {
-        "de" => tt.render(SEND_ERROR_TEMPLATE_NAME_DE, &context).unwrap(),
-        _ => tt.render(SEND_ERROR_TEMPLATE_NAME_EN, &context).unwrap(),
-    }
+    tt.add_template(SEND_ERROR_TEMPLATE_NAME, template)?;
+    tt.render(SEND_ERROR_TEMPLATE_NAME, context)
 }
 
 fn render_paragraphs(value: &Value, output: &mut String) -> Result<(), Error> {
@@ -50,28 +161,34 @@ fn render_paragraphs(value: &Value, output: &mut String) -> Result<(), Error> {
 
 #[cfg(test)]
 mod tests {
-    use super::render_error_page;
+    use super::{mailto_url, ErrorPageContent, ErrorPages};
+    use crate::form_schema::FormField;
+    use crate::template_source::{bundled_templates, TemplateFile};
     use googletest::prelude::*;
 
     const MALICIOUS_CONTENT: &str = "<script>doEvil();</script>";
 
     #[test]
     fn escapes_user_input_in_subject() -> Result<()> {
-        let output = render_error_page(MALICIOUS_CONTENT, "A body", "en");
+        let output = bundled().render(&content(MALICIOUS_CONTENT, "A body"), "en", None);
 
         verify_that!(output, not(contains_substring(MALICIOUS_CONTENT)))
     }
 
     #[test]
     fn escapes_user_input_in_body() -> Result<()> {
-        let output = render_error_page("A subject", MALICIOUS_CONTENT, "en");
+        let output = bundled().render(&content("A subject", MALICIOUS_CONTENT), "en", None);
 
         verify_that!(output, not(contains_substring(MALICIOUS_CONTENT)))
     }
 
     #[test]
     fn renders_paragraphs_in_body() -> Result<()> {
-        let output = render_error_page("A subject", "A paragraph\n\nAnother paragraph", "en");
+        let output = bundled().render(
+            &content("A subject", "A paragraph\n\nAnother paragraph"),
+            "en",
+            None,
+        );
 
         verify_that!(
             output,
@@ -79,20 +196,233 @@ mod tests {
         )
     }
 
+    #[test]
+    fn renders_explanation() -> Result<()> {
+        let output = bundled().render(&content("A subject", "A body"), "en", None);
+
+        verify_that!(output, contains_substring("<p>An explanation</p>"))
+    }
+
+    #[test]
+    fn renders_fields() -> Result<()> {
+        let fields = [FormField {
+            label: "Phone".into(),
+            value: "+49 30 1234".into(),
+        }];
+        let output = bundled().render(
+            &ErrorPageContent {
+                fields: &fields,
+                ..content("A subject", "A body")
+            },
+            "en",
+            None,
+        );
+
+        verify_that!(
+            output,
+            contains_substring("<strong>Phone:</strong> +49 30 1234")
+        )
+    }
+
+    #[test]
+    fn renders_retry_form_with_token() -> Result<()> {
+        let output = bundled().render(
+            &ErrorPageContent {
+                retry_token: Some("A token"),
+                ..content("A subject", "A body")
+            },
+            "en",
+            None,
+        );
+
+        verify_that!(
+            output,
+            contains_substring(r#"name="retry-token" value="A token""#)
+        )
+    }
+
+    #[test]
+    fn omits_retry_form_without_token() -> Result<()> {
+        let output = bundled().render(&content("A subject", "A body"), "en", None);
+
+        verify_that!(output, not(contains_substring("retry-token")))
+    }
+
+    #[test]
+    fn escapes_retry_token() -> Result<()> {
+        let output = bundled().render(
+            &ErrorPageContent {
+                retry_token: Some(r#""><script>doEvil();</script>"#),
+                ..content("A subject", "A body")
+            },
+            "en",
+            None,
+        );
+
+        verify_that!(output, not(contains_substring(MALICIOUS_CONTENT)))
+    }
+
+    #[test]
+    fn renders_mailto_link() -> Result<()> {
+        let output = bundled().render(&content("A subject", "A body"), "en", None);
+
+        verify_that!(
+            output,
+            contains_substring("href=\"mailto:someone@example.com\"")
+        )
+    }
+
+    #[test]
+    fn mailto_url_encodes_subject_and_body() -> Result<()> {
+        verify_that!(
+            mailto_url("someone@example.com", "Hello & welcome", "Line 1\nLine 2?"),
+            eq("mailto:someone@example.com?subject=Hello%20%26%20welcome&body=Line%201%0ALine%202%3F")
+        )
+    }
+
     #[test]
     fn renders_english_when_requested() -> Result<()> {
-        let output = render_error_page("A subject", "A paragraph\n\nAnother paragraph", "en");
+        let output = bundled().render(
+            &content("A subject", "A paragraph\n\nAnother paragraph"),
+            "en",
+            None,
+        );
 
         verify_that!(output, contains_substring("Something went wrong"))
     }
 
     #[test]
     fn renders_german_when_requested() -> Result<()> {
-        let output = render_error_page("A subject", "A paragraph\n\nAnother paragraph", "de");
+        let output = bundled().render(
+            &content("A subject", "A paragraph\n\nAnother paragraph"),
+            "de",
+            None,
+        );
 
         verify_that!(
             output,
             contains_substring("Leider ist etwas schiefgelaufen")
         )
     }
+
+    #[googletest::test]
+    fn discovers_languages_from_file_names() -> Result<()> {
+        let subject = ErrorPages::from_files(&[
+            file("send-error.html", "English: {subject}"),
+            file("send-error.fr.html", "Français : {subject}"),
+            file("README.md", "Not a template"),
+        ])
+        .unwrap();
+
+        expect_that!(
+            subject.languages(),
+            unordered_elements_are![eq("en"), eq("fr")]
+        );
+        verify_that!(
+            subject.render(&content("A subject", "A body"), "fr", None),
+            eq("Français : A subject")
+        )
+    }
+
+    #[test]
+    fn falls_back_to_default_language() -> Result<()> {
+        let subject = ErrorPages::from_files(&[file("send-error.html", "English")]).unwrap();
+
+        verify_that!(
+            subject.render(&content("A subject", "A body"), "fr", None),
+            eq("English")
+        )
+    }
+
+    #[test]
+    fn falls_back_to_more_general_language() -> Result<()> {
+        let subject = ErrorPages::from_files(&[
+            file("send-error.html", "English"),
+            file("send-error.de.html", "Deutsch"),
+        ])
+        .unwrap();
+
+        verify_that!(
+            subject.render(&content("A subject", "A body"), "de-AT", None),
+            eq("Deutsch")
+        )
+    }
+
+    #[googletest::test]
+    fn renders_site_override_with_site_root() -> Result<()> {
+        let subject = ErrorPages::from_files(&[
+            file("send-error.html", "Default"),
+            file("send-error.de.html", "Standard"),
+            file("sites/example.com/send-error.html", "Example {site_root}"),
+        ])
+        .unwrap();
+
+        expect_that!(
+            subject.render(&content("A subject", "A body"), "en", Some("example.com")),
+            eq("Example https://example.com")
+        );
+        expect_that!(
+            subject.render(&content("A subject", "A body"), "de", Some("example.com")),
+            eq("Example https://example.com")
+        );
+        verify_that!(
+            subject.render(
+                &content("A subject", "A body"),
+                "de",
+                Some("other.example.com")
+            ),
+            eq("Standard")
+        )
+    }
+
+    #[test]
+    fn rejects_template_which_does_not_compile() -> Result<()> {
+        let result = ErrorPages::from_files(&[
+            file("send-error.html", "Default"),
+            file("send-error.de.html", "{subject"),
+        ]);
+
+        verify_that!(
+            result.map(|_| ()),
+            err(displays_as(contains_substring("send-error.de.html")))
+        )
+    }
+
+    #[test]
+    fn rejects_template_with_unknown_variable() -> Result<()> {
+        let result = ErrorPages::from_files(&[file("send-error.html", "{unknown}")]);
+
+        verify_that!(result.map(|_| ()), err(anything()))
+    }
+
+    #[test]
+    fn rejects_missing_default_template() -> Result<()> {
+        let result = ErrorPages::from_files(&[file("send-error.de.html", "Standard")]);
+
+        verify_that!(result.map(|_| ()), err(anything()))
+    }
+
+    fn bundled() -> ErrorPages {
+        ErrorPages::from_files(&bundled_templates()).unwrap()
+    }
+
+    fn content<'a>(subject: &'a str, body: &'a str) -> ErrorPageContent<'a> {
+        ErrorPageContent {
+            explanation: "An explanation",
+            subject,
+            body,
+            fields: &[],
+            retry_token: None,
+            retry_label: "Try sending again",
+            mailto_url: "mailto:someone@example.com",
+            mailto_label: "Send the message from your email program instead",
+        }
+    }
+
+    fn file(path: &str, content: &str) -> TemplateFile {
+        TemplateFile {
+            path: path.into(),
+            content: content.into(),
+        }
+    }
 }
@@ -0,0 +1,555 @@
+use crate::success_page::SuccessBehaviour;
+use lambda_http::Error;
+use lettre::Address;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    path::Path,
+};
+use tracing::info;
+
+/// The names of the fields which every form has. They are parsed by the handler itself, so a
+/// schema may not declare them.
+const RESERVED_FIELD_NAMES: [&str; 12] = [
+    "name",
+    "email",
+    "subject",
+    "body",
+    "language",
+    "frc-captcha-solution",
+    "form-id",
+    "retry-token",
+    "consent",
+    "newsletter",
+    "return-to",
+    "idempotency-key",
+];
+
+/// The values of a checkbox which mean that it is checked. Browsers send `on` by default.
+const CHECKED_VALUES: [&str; 4] = ["on", "true", "yes", "1"];
+
+/// The field schemas of the forms, keyed by form ID, as configured in the JSON or TOML file given
+/// by the environment variable `FORM_SCHEMAS_FILE`. The format is determined by the file
+/// extension, defaulting to JSON:
+///
+/// ```toml
+/// [consulting]
+/// unknown_fields = "reject"
+/// consent_required = true
+/// privacy_policy_version = "2024-05-01"
+///
+/// [[consulting.fields]]
+/// name = "budget"
+/// label = "Budget"
+/// required = true
+/// allowed_values = ["< 10k", "10k-50k", "> 50k"]
+///
+/// [consulting.success]
+/// return_to = ["https://hovinen.tech/consulting/"]
+/// inline_page = true
+/// ```
+///
+/// See [`SuccessBehaviour`] for the settings of the `success` section. Forms without a schema have
+/// no further fields and ignore unknown fields.
+#[derive(Default)]
+pub struct FormSchemas {
+    schemas: HashMap<String, FormSchema>,
+    default: FormSchema,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FormSchema {
+    #[serde(default)]
+    fields: Vec<FieldSchema>,
+    #[serde(default)]
+    unknown_fields: UnknownFields,
+    /// Whether the visitor must accept the privacy notice through the `consent` field.
+    #[serde(default)]
+    consent_required: bool,
+    /// The version of the privacy notice which the form shows, recorded with the consent.
+    privacy_policy_version: Option<String>,
+    #[serde(default)]
+    success: SuccessBehaviour,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldSchema {
+    name: String,
+    label: Option<String>,
+    #[serde(rename = "type", default)]
+    field_type: FieldType,
+    #[serde(default)]
+    required: bool,
+    max_length: Option<usize>,
+    allowed_values: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum FieldType {
+    #[default]
+    Text,
+    Textarea,
+    Email,
+    Tel,
+    Number,
+    Checkbox,
+}
+
+/// What happens to submitted fields which the schema does not declare.
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum UnknownFields {
+    #[default]
+    Ignore,
+    Reject,
+    /// Included in the notification email after the declared fields, labelled with their names.
+    PassThrough,
+}
+
+/// A validated field, as shown in the notification email and on the error page.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct FormField {
+    pub label: String,
+    pub value: String,
+}
+
+impl FormSchemas {
+    pub fn from_environment() -> Result<Self, Error> {
+        let Ok(path) = std::env::var("FORM_SCHEMAS_FILE") else {
+            return Ok(Self::default());
+        };
+        let schemas = Self::load(Path::new(&path))
+            .map_err(|error| format!("Unable to read form schemas file {path}: {error}"))?;
+        info!(
+            "Loaded field schemas for forms {:?}",
+            schemas.schemas.keys()
+        );
+        Ok(schemas)
+    }
+
+    fn load(path: &Path) -> Result<Self, Error> {
+        let content = std::fs::read_to_string(path)?;
+        let schemas: HashMap<String, FormSchema> = if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&content)?
+        } else {
+            serde_json::from_str(&content)?
+        };
+        for (form_id, schema) in &schemas {
+            schema
+                .check()
+                .map_err(|error| format!("Form {form_id}: {error}"))?;
+        }
+        Ok(Self {
+            schemas,
+            default: FormSchema::default(),
+        })
+    }
+
+    /// Returns the IDs of the forms which have a schema.
+    pub fn form_ids(&self) -> BTreeSet<&str> {
+        self.schemas.keys().map(String::as_str).collect()
+    }
+
+    /// Returns whether any form shows the success page inline.
+    pub fn uses_inline_success_page(&self) -> bool {
+        self.schemas
+            .values()
+            .any(|schema| schema.success.uses_inline_page())
+    }
+
+    /// Returns the schema of the form with the given ID, or the default schema if there is none.
+    pub fn get(&self, form_id: Option<&str>) -> &FormSchema {
+        form_id
+            .and_then(|form_id| self.schemas.get(form_id))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl FormSchema {
+    pub fn consent_required(&self) -> bool {
+        self.consent_required
+    }
+
+    pub fn privacy_policy_version(&self) -> Option<&str> {
+        self.privacy_policy_version.as_deref()
+    }
+
+    pub fn success(&self) -> &SuccessBehaviour {
+        &self.success
+    }
+
+    fn check(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        for field in &self.fields {
+            if RESERVED_FIELD_NAMES.contains(&field.name.as_str()) {
+                return Err(format!("Field {} is reserved", field.name));
+            }
+            if !names.insert(field.name.as_str()) {
+                return Err(format!("Field {} is declared twice", field.name));
+            }
+        }
+        self.success.check()
+    }
+
+    /// Validates the submitted values of the fields beyond those which every form has, returning
+    /// the fields to show in the notification email in the order of the schema.
+    pub fn validate(&self, values: &BTreeMap<String, Value>) -> Result<Vec<FormField>, FieldError> {
+        let mut fields = vec![];
+        for field in &self.fields {
+            let value = values
+                .get(&field.name)
+                .map(|value| field_value(&field.name, value))
+                .transpose()?
+                .filter(|value| !value.is_empty());
+            if let Some(value) = field.validate(value)? {
+                fields.push(FormField {
+                    label: field.label.clone().unwrap_or(field.name.clone()),
+                    value,
+                });
+            }
+        }
+        for (name, value) in values {
+            if self.fields.iter().any(|field| &field.name == name) {
+                continue;
+            }
+            match self.unknown_fields {
+                UnknownFields::Ignore => {}
+                UnknownFields::Reject => return Err(FieldError::Unknown(name.clone())),
+                UnknownFields::PassThrough => fields.push(FormField {
+                    label: name.clone(),
+                    value: field_value(name, value)?,
+                }),
+            }
+        }
+        Ok(fields)
+    }
+}
+
+impl FieldSchema {
+    fn validate(&self, value: Option<String>) -> Result<Option<String>, FieldError> {
+        if let FieldType::Checkbox = self.field_type {
+            let checked = is_checked(value.as_deref());
+            if self.required && !checked {
+                return Err(FieldError::Missing(self.name.clone()));
+            }
+            return Ok(Some(if checked { "yes" } else { "no" }.into()));
+        }
+        let Some(value) = value else {
+            return if self.required {
+                Err(FieldError::Missing(self.name.clone()))
+            } else {
+                Ok(None)
+            };
+        };
+        let invalid = || Err(FieldError::Invalid(self.name.clone()));
+        if self
+            .max_length
+            .is_some_and(|max_length| value.chars().count() > max_length)
+        {
+            return invalid();
+        }
+        if self
+            .allowed_values
+            .as_ref()
+            .is_some_and(|allowed_values| !allowed_values.contains(&value))
+        {
+            return invalid();
+        }
+        let valid = match self.field_type {
+            FieldType::Textarea => true,
+            FieldType::Text | FieldType::Checkbox => !value.chars().any(char::is_control),
+            FieldType::Email => value.parse::<Address>().is_ok(),
+            FieldType::Tel => {
+                value.chars().any(|c| c.is_ascii_digit())
+                    && value
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || " +-()./".contains(c))
+            }
+            FieldType::Number => value.parse::<f64>().is_ok_and(f64::is_finite),
+        };
+        if !valid {
+            return invalid();
+        }
+        Ok(Some(value))
+    }
+}
+
+/// Returns whether the submitted value of a checkbox means that it is checked.
+pub fn is_checked(value: Option<&str>) -> bool {
+    value.is_some_and(|value| CHECKED_VALUES.contains(&value))
+}
+
+/// Returns the submitted value as text. HTML forms submit text, whereas JSON submissions may also
+/// use booleans and numbers.
+fn field_value(name: &str, value: &Value) -> Result<String, FieldError> {
+    match value {
+        Value::Null => Ok(String::new()),
+        Value::String(value) => Ok(value.clone()),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        Value::Array(_) | Value::Object(_) => Err(FieldError::Invalid(name.into())),
+    }
+}
+
+/// Writes the fields as `Label: value` lines, with the lines of multi-line values indented below
+/// the label.
+pub fn format_fields(fields: &[FormField]) -> String {
+    let mut formatted = String::new();
+    for FormField { label, value } in fields {
+        if value.contains('\n') {
+            formatted.push_str(&format!("{label}:\n"));
+            for line in value.lines() {
+                formatted.push_str(&format!("  {line}\n"));
+            }
+        } else {
+            formatted.push_str(&format!("{label}: {value}\n"));
+        }
+    }
+    formatted
+}
+
+#[derive(Debug)]
+pub enum FieldError {
+    Missing(String),
+    Invalid(String),
+    Unknown(String),
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FieldError::Missing(name) => write!(f, "Missing field {name}"),
+            FieldError::Invalid(name) => write!(f, "Invalid value of field {name}"),
+            FieldError::Unknown(name) => write!(f, "Unknown field {name}"),
+        }
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_fields, FieldError, FormField, FormSchema, FormSchemas};
+    use googletest::prelude::*;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn returns_fields_in_schema_order_with_labels() -> Result<()> {
+        let subject = schema(json!({
+            "fields": [
+                {"name": "phone", "label": "Phone", "type": "tel"},
+                {"name": "company"},
+            ]
+        }));
+
+        verify_that!(
+            subject.validate(&values(json!({"company": "ACME", "phone": "+49 30 1234"}))),
+            ok(elements_are![
+                eq(field("Phone", "+49 30 1234")),
+                eq(field("company", "ACME"))
+            ])
+        )
+    }
+
+    #[test]
+    fn omits_empty_optional_field() -> Result<()> {
+        let subject = schema(json!({"fields": [{"name": "company"}]}));
+
+        verify_that!(
+            subject.validate(&values(json!({"company": ""}))),
+            ok(empty())
+        )
+    }
+
+    #[test]
+    fn rejects_missing_required_field() -> Result<()> {
+        let subject = schema(json!({"fields": [{"name": "company", "required": true}]}));
+
+        verify_that!(
+            subject.validate(&values(json!({}))),
+            err(matches_pattern!(FieldError::Missing(eq("company"))))
+        )
+    }
+
+    #[test]
+    fn rejects_value_which_is_too_long() -> Result<()> {
+        let subject = schema(json!({"fields": [{"name": "company", "max_length": 4}]}));
+
+        verify_that!(
+            subject.validate(&values(json!({"company": "Too long"}))),
+            err(matches_pattern!(FieldError::Invalid(eq("company"))))
+        )
+    }
+
+    #[test]
+    fn rejects_value_which_is_not_allowed() -> Result<()> {
+        let subject = schema(json!({
+            "fields": [{"name": "budget", "allowed_values": ["small", "large"]}]
+        }));
+
+        verify_that!(
+            subject.validate(&values(json!({"budget": "huge"}))),
+            err(matches_pattern!(FieldError::Invalid(eq("budget"))))
+        )
+    }
+
+    #[test]
+    fn rejects_line_break_in_text_field() -> Result<()> {
+        let subject = schema(json!({"fields": [{"name": "company"}]}));
+
+        verify_that!(
+            subject.validate(&values(json!({"company": "ACME\r\nBcc: x@example.com"}))),
+            err(matches_pattern!(FieldError::Invalid(eq("company"))))
+        )
+    }
+
+    #[googletest::test]
+    fn validates_typed_fields() {
+        let subject = schema(json!({
+            "fields": [
+                {"name": "email", "type": "email"},
+                {"name": "phone", "type": "tel"},
+                {"name": "employees", "type": "number"},
+            ]
+        }));
+
+        expect_that!(
+            subject.validate(&values(json!({"email": "not an address"}))),
+            err(anything())
+        );
+        expect_that!(
+            subject.validate(&values(json!({"phone": "call me"}))),
+            err(anything())
+        );
+        expect_that!(
+            subject.validate(&values(json!({"employees": "many"}))),
+            err(anything())
+        );
+        expect_that!(
+            subject.validate(&values(json!({"employees": 12}))),
+            ok(elements_are![eq(field("employees", "12"))])
+        );
+    }
+
+    #[googletest::test]
+    fn requires_required_checkbox_to_be_checked() {
+        let subject = schema(json!({
+            "fields": [{"name": "consent", "type": "checkbox", "required": true}]
+        }));
+
+        expect_that!(
+            subject.validate(&values(json!({"consent": "on"}))),
+            ok(elements_are![eq(field("consent", "yes"))])
+        );
+        expect_that!(
+            subject.validate(&values(json!({"consent": false}))),
+            err(matches_pattern!(FieldError::Missing(eq("consent"))))
+        );
+        expect_that!(
+            subject.validate(&values(json!({}))),
+            err(matches_pattern!(FieldError::Missing(eq("consent"))))
+        );
+    }
+
+    #[googletest::test]
+    fn handles_unknown_fields_as_configured() {
+        let submitted = values(json!({"company": "ACME", "extra": "Something"}));
+
+        expect_that!(
+            schema(json!({"fields": [{"name": "company"}]})).validate(&submitted),
+            ok(elements_are![eq(field("company", "ACME"))])
+        );
+        expect_that!(
+            schema(json!({"fields": [{"name": "company"}], "unknown_fields": "reject"}))
+                .validate(&submitted),
+            err(matches_pattern!(FieldError::Unknown(eq("extra"))))
+        );
+        expect_that!(
+            schema(json!({"fields": [{"name": "company"}], "unknown_fields": "pass-through"}))
+                .validate(&submitted),
+            ok(elements_are![
+                eq(field("company", "ACME")),
+                eq(field("extra", "Something"))
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_schema_declaring_reserved_field() -> Result<()> {
+        let subject = schema(json!({"fields": [{"name": "email"}]}));
+
+        verify_that!(subject.check(), err(anything()))
+    }
+
+    #[test]
+    fn loads_schemas_from_toml_file() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("forms-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [consulting]
+            unknown_fields = "reject"
+
+            [[consulting.fields]]
+            name = "company"
+            required = true
+            "#,
+        )
+        .unwrap();
+
+        let schemas = FormSchemas::load(&path).unwrap();
+
+        std::fs::remove_file(path).unwrap();
+        verify_that!(
+            schemas.get(Some("consulting")).validate(&values(json!({}))),
+            err(matches_pattern!(FieldError::Missing(eq("company"))))
+        )
+    }
+
+    #[test]
+    fn uses_default_schema_for_form_without_schema() -> Result<()> {
+        let schemas = FormSchemas::default();
+
+        verify_that!(
+            schemas
+                .get(Some("unknown"))
+                .validate(&values(json!({"extra": "Something"}))),
+            ok(empty())
+        )
+    }
+
+    #[test]
+    fn formats_multi_line_values_indented() -> Result<()> {
+        verify_that!(
+            format_fields(&[
+                field("Phone", "+49 30 1234"),
+                field("Address", "Street 1\nBerlin")
+            ]),
+            eq("Phone: +49 30 1234\nAddress:\n  Street 1\n  Berlin\n")
+        )
+    }
+
+    fn schema(schema: Value) -> FormSchema {
+        serde_json::from_value(schema).unwrap()
+    }
+
+    fn values(values: Value) -> BTreeMap<String, Value> {
+        serde_json::from_value(values).unwrap()
+    }
+
+    fn field(label: &str, value: &str) -> FormField {
+        FormField {
+            label: label.into(),
+            value: value.into(),
+        }
+    }
+}
@@ -1,5 +1,9 @@
-use crate::{secrets::SecretRepository, ContactFormError};
-use async_once_cell::OnceCell;
+use crate::{
+    alerting::AlertKind,
+    messages,
+    secrets::{SecretRepository, VersionStage},
+    ContactFormError,
+};
 use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
@@ -10,57 +14,106 @@ const FRIENDLYCAPTCHA_VERIFY_URL: &str = "https://api.friendlycaptcha.com/api/v1
 
 pub struct FriendlyCaptchaVerifier<SecretRepositoryT: SecretRepository> {
     secrets_repository: SecretRepositoryT,
-    friendlycaptcha_data: OnceCell<FriendlyCaptchaData>,
 }
 
 impl<SecretRepositoryT: SecretRepository> FriendlyCaptchaVerifier<SecretRepositoryT> {
     pub fn new(secrets_repository: SecretRepositoryT) -> Self {
-        Self {
-            secrets_repository,
-            friendlycaptcha_data: Default::default(),
-        }
+        Self { secrets_repository }
     }
 
     pub async fn verify_token(&self, solution: &str) -> Result<(), FriendlyCaptchaError> {
-        let payload = match self.build_payload(solution).await {
-            Ok(response) => response,
-            Err(FriendlyCaptchaError::BackendError) => return Ok(()),
-            Err(e) => return Err(e),
-        };
-        let response = match Self::send_solution(payload).await {
-            Ok(response) => response,
-            Err(FriendlyCaptchaError::BackendError) => return Ok(()),
-            Err(e) => return Err(e),
-        };
-        self.process_response(response).await?;
-        Ok(())
-    }
-
-    async fn build_payload<'a>(
-        &'a self,
-        solution: &'a str,
-    ) -> Result<FriendlyCaptchaVerifyPayload<'a>, FriendlyCaptchaError> {
-        let data = match self
-            .friendlycaptcha_data
-            .get_or_try_init(
-                self.secrets_repository
-                    .get_secret(FRIENDLYCAPTCHA_DATA_NAME),
-            )
+        let result = match self
+            .verify_token_at_stage(solution, VersionStage::Current)
             .await
         {
-            Ok(data) => data,
-            Err(error) => {
-                warn!("Could not retrieve FriendlyCaptcha credentials {FRIENDLYCAPTCHA_DATA_NAME} from AWS secrets manager: {error}");
-                warn!("Letting request pass without verification.");
-                return Err(FriendlyCaptchaError::BackendError);
+            Err(FriendlyCaptchaError::IncorrectSecret) => {
+                self.verify_token_with_refreshed_secret(solution).await
             }
+            result => result,
         };
+        match result {
+            Err(FriendlyCaptchaError::BackendError) => {
+                warn!("Letting request pass without verification.");
+                Ok(())
+            }
+            result => result,
+        }
+    }
 
-        Ok(FriendlyCaptchaVerifyPayload {
+    /// Retries verification after FriendlyCaptcha rejected the secret, first with a freshly
+    /// fetched current secret and then with the pending secret of an ongoing rotation.
+    async fn verify_token_with_refreshed_secret(
+        &self,
+        solution: &str,
+    ) -> Result<(), FriendlyCaptchaError> {
+        warn!("FriendlyCaptcha rejected the secret, retrying with a freshly fetched secret");
+        self.secrets_repository
+            .invalidate(FRIENDLYCAPTCHA_DATA_NAME)
+            .await;
+        match self
+            .verify_token_at_stage(solution, VersionStage::Current)
+            .await
+        {
+            Err(FriendlyCaptchaError::IncorrectSecret) => {}
+            result => return result,
+        }
+        match self
+            .verify_token_at_stage(solution, VersionStage::Pending)
+            .await
+        {
+            // There is no usable pending secret, so the current one really is incorrect.
+            Err(FriendlyCaptchaError::BackendError) => Err(FriendlyCaptchaError::IncorrectSecret),
+            result => result,
+        }
+    }
+
+    async fn verify_token_at_stage(
+        &self,
+        solution: &str,
+        stage: VersionStage,
+    ) -> Result<(), FriendlyCaptchaError> {
+        let data = self.fetch_data(stage).await?;
+        let payload = FriendlyCaptchaVerifyPayload {
             solution,
             sitekey: &data.sitekey,
             secret: &data.secret,
-        })
+        };
+        let response = Self::send_solution(payload).await?;
+        self.process_response(response).await
+    }
+
+    /// Checks that the FriendlyCaptcha credentials can be retrieved and parsed, bypassing any
+    /// cached value.
+    pub async fn check_secret(&self) -> Result<(), lambda_http::Error> {
+        self.secrets_repository
+            .invalidate(FRIENDLYCAPTCHA_DATA_NAME)
+            .await;
+        self.secrets_repository
+            .get_secret::<FriendlyCaptchaData>(FRIENDLYCAPTCHA_DATA_NAME)
+            .await
+            .map(|_| ())
+    }
+
+    /// Checks that the verification endpoint answers HTTP requests, regardless of the response.
+    pub async fn check_endpoint_reachable() -> Result<(), reqwest::Error> {
+        Client::new()
+            .post(Self::verification_url().as_ref())
+            .send()
+            .await
+            .map(|_| ())
+    }
+
+    async fn fetch_data(
+        &self,
+        stage: VersionStage,
+    ) -> Result<FriendlyCaptchaData, FriendlyCaptchaError> {
+        self.secrets_repository
+            .get_secret_at_stage(FRIENDLYCAPTCHA_DATA_NAME, stage)
+            .await
+            .map_err(|error| {
+                warn!("Could not retrieve FriendlyCaptcha credentials {FRIENDLYCAPTCHA_DATA_NAME} ({stage}): {error}");
+                FriendlyCaptchaError::BackendError
+            })
     }
 
     async fn send_solution<'a>(
@@ -81,14 +134,13 @@ impl<SecretRepositoryT: SecretRepository> FriendlyCaptchaVerifier<SecretReposito
                         }
                     }
                     warn!("Error verifying FriendlyCaptcha solution: {error}");
-                    warn!("Letting request pass without verification.");
                     return Err(FriendlyCaptchaError::BackendError);
                 }
             },
         )
     }
 
-    fn verification_url() -> Cow<'static, str> {
+    pub fn verification_url() -> Cow<'static, str> {
         std::env::var("FRIENDLYCAPTCHA_VERIFY_URL")
             .map(Cow::Owned)
             .unwrap_or(FRIENDLYCAPTCHA_VERIFY_URL.into())
@@ -125,7 +177,7 @@ impl<SecretRepositoryT: SecretRepository> FriendlyCaptchaVerifier<SecretReposito
 }
 
 #[derive(Deserialize, Clone)]
-struct FriendlyCaptchaData {
+pub(crate) struct FriendlyCaptchaData {
     #[serde(rename = "FRIENDLYCAPTCHA_SITEKEY")]
     sitekey: String,
     #[serde(rename = "FRIENDLYCAPTCHA_SECRET")]
@@ -169,30 +221,42 @@ impl FriendlyCaptchaError {
                 subject,
                 body,
                 language,
+                retryable: false,
+                alert: AlertKind::CaptchaVerification,
             },
             FriendlyCaptchaError::IncorrectSecret => ContactFormError::InternalError {
                 description: "Incorrect FriendlyCaptcha secret".into(),
                 subject,
                 body,
                 language,
+                retryable: false,
+                alert: AlertKind::CaptchaVerification,
+            },
+            FriendlyCaptchaError::SolutionInvalid => ContactFormError::ClientError {
+                description: "Invalid FriendlyCaptcha solution".into(),
+                message_id: messages::CAPTCHA_INVALID,
+                language,
+            },
+            FriendlyCaptchaError::SolutionTimeoutOrDuplicate => ContactFormError::ClientError {
+                description: "FriendlyCaptcha solution timeout or duplicate".into(),
+                message_id: messages::CAPTCHA_EXPIRED,
+                language,
             },
-            FriendlyCaptchaError::SolutionInvalid => {
-                ContactFormError::ClientError("Invalid FriendlyCaptcha solution".into())
-            }
-            FriendlyCaptchaError::SolutionTimeoutOrDuplicate => ContactFormError::ClientError(
-                "FriendlyCaptcha solution timeout or duplicate".into(),
-            ),
             FriendlyCaptchaError::UnrecognizedError(errors) => ContactFormError::InternalError {
                 description: format!("FriendlyCaptcha error: {errors:?}"),
                 subject,
                 body,
                 language,
+                retryable: false,
+                alert: AlertKind::CaptchaVerification,
             },
             FriendlyCaptchaError::BackendError => ContactFormError::InternalError {
                 description: "FriendlyCaptcha backend error".into(),
                 subject,
                 body,
                 language,
+                retryable: false,
+                alert: AlertKind::CaptchaVerification,
             },
         }
     }
@@ -216,3 +280,103 @@ impl std::fmt::Display for FriendlyCaptchaError {
 }
 
 impl std::error::Error for FriendlyCaptchaError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{FriendlyCaptchaVerifier, FRIENDLYCAPTCHA_DATA_NAME};
+    use crate::secrets::{
+        test_support::{
+            FakeSecretRepsitory, FAKE_FRIENDLYCAPTCHA_SECRET, FAKE_FRIENDLYCAPTCHA_SITEKEY,
+        },
+        CachingSecretRepository, SecretRepository,
+    };
+    use googletest::prelude::*;
+    use serial_test::serial;
+    use std::time::Duration;
+    use test_support::fake_friendlycaptcha::FakeFriendlyCaptcha;
+
+    const CORRECT_CAPTCHA_SOLUTION: &str = "correct captcha solution";
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn verifies_with_refreshed_secret_after_rotation() {
+        serve_fake_friendlycaptcha().await;
+        let mut secrets_repository = CachingSecretRepository::new(
+            FakeSecretRepsitory::open().await,
+            Duration::from_secs(60),
+        );
+        secrets_repository.inner_mut().add_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            friendlycaptcha_data("outdated secret"),
+        );
+        secrets_repository
+            .get_secret_string(FRIENDLYCAPTCHA_DATA_NAME)
+            .await
+            .unwrap();
+        secrets_repository.inner_mut().add_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            friendlycaptcha_data(FAKE_FRIENDLYCAPTCHA_SECRET),
+        );
+        let subject = FriendlyCaptchaVerifier::new(secrets_repository);
+
+        let result = subject.verify_token(CORRECT_CAPTCHA_SOLUTION).await;
+
+        expect_that!(result, ok(anything()));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn verifies_with_pending_secret_during_rotation() {
+        serve_fake_friendlycaptcha().await;
+        let mut secrets_repository = FakeSecretRepsitory::open().await;
+        secrets_repository.add_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            friendlycaptcha_data("outdated secret"),
+        );
+        secrets_repository.add_pending_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            friendlycaptcha_data(FAKE_FRIENDLYCAPTCHA_SECRET),
+        );
+        let subject = FriendlyCaptchaVerifier::new(secrets_repository);
+
+        let result = subject.verify_token(CORRECT_CAPTCHA_SOLUTION).await;
+
+        expect_that!(result, ok(anything()));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reports_incorrect_secret_when_no_version_is_accepted() {
+        serve_fake_friendlycaptcha().await;
+        let mut secrets_repository = FakeSecretRepsitory::open().await;
+        secrets_repository.add_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            friendlycaptcha_data("outdated secret"),
+        );
+        let subject = FriendlyCaptchaVerifier::new(secrets_repository);
+
+        let result = subject.verify_token(CORRECT_CAPTCHA_SOLUTION).await;
+
+        expect_that!(result, err(displays_as(eq("Incorrect secret"))));
+    }
+
+    async fn serve_fake_friendlycaptcha() {
+        FakeFriendlyCaptcha::setup_environment();
+        FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+            .require_solution(CORRECT_CAPTCHA_SOLUTION)
+            .start()
+            .await;
+    }
+
+    fn friendlycaptcha_data(secret: &str) -> String {
+        format!(
+            r#"{{
+                "FRIENDLYCAPTCHA_SITEKEY": "{FAKE_FRIENDLYCAPTCHA_SITEKEY}",
+                "FRIENDLYCAPTCHA_SECRET": "{secret}"
+            }}"#
+        )
+    }
+}
@@ -0,0 +1,94 @@
+use lambda_http::{
+    http::{header, StatusCode},
+    Body, Request, Response,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+pub const HEALTH_CHECK_PATH: &str = "/health";
+pub const HEALTH_CHECK_TOKEN_NAME: &str = "health-check-token";
+
+#[derive(Deserialize)]
+pub struct HealthCheckToken {
+    #[serde(rename = "HEALTH_CHECK_TOKEN")]
+    token: String,
+}
+
+impl HealthCheckToken {
+    /// Returns whether the request carries this token as a bearer token in its `Authorization`
+    /// header.
+    pub fn authorizes(&self, request: &Request) -> bool {
+        let Some(provided_token) = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+        else {
+            return false;
+        };
+        constant_time_eq(provided_token.as_bytes(), self.token.as_bytes())
+    }
+}
+
+/// The result of a self-test, serialised as the JSON body of the health check response.
+#[derive(Serialize)]
+pub struct HealthReport {
+    healthy: bool,
+    checks: Vec<CheckResult>,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    name: &'static str,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl HealthReport {
+    pub fn new() -> Self {
+        Self {
+            healthy: true,
+            checks: vec![],
+        }
+    }
+
+    pub fn record<E: Display>(&mut self, name: &'static str, result: Result<(), E>) {
+        let error = result.err().map(|error| error.to_string());
+        self.healthy &= error.is_none();
+        self.checks.push(CheckResult {
+            name,
+            ok: error.is_none(),
+            error,
+        });
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let status = if self.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_string(&self).unwrap().into())
+            .unwrap()
+    }
+}
+
+pub fn unauthorized_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .body("".into())
+        .unwrap()
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right.iter())
+            .fold(0, |acc, (l, r)| acc | (l ^ r))
+            == 0
+}
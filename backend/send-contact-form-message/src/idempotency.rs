@@ -0,0 +1,614 @@
+use crate::secrets::load_aws_config;
+use aws_sdk_dynamodb::{
+    operation::put_item::PutItemError,
+    types::{AttributeValue, ReturnValuesOnConditionCheckFailure},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use lambda_http::{
+    http::{header, StatusCode},
+    Body, Response,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// The name of the request header with which clients may supply their own idempotency key.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// How long a submission which is being processed blocks identical ones. This only matters if the
+/// instance processing it dies, since the claim is otherwise completed or released.
+const PENDING_CLAIM_LIFETIME: Duration = Duration::from_secs(60);
+
+/// How long to wait for an identical submission which is being processed, and how often to check
+/// on it. Browsers show the response to the last of several clicks, so a repeat should get the
+/// same response as the original rather than an error if at all possible.
+const PENDING_WAIT: Duration = Duration::from_secs(10);
+const PENDING_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Returns the key under which a submission is deduplicated: the sender's address, subject and
+/// body, together with the key which the client supplied, if any. A client key thus only tells
+/// apart submissions of the same message and cannot make one visitor's message pass for a repeat
+/// of another's. The key is hashed so that the store holds no message content.
+pub fn idempotency_key(
+    client_key: Option<&str>,
+    email: Option<&str>,
+    subject: Option<&str>,
+    body: Option<&str>,
+) -> String {
+    let mut hasher = Sha256::new();
+    if client_key.is_some() {
+        hasher.update(b"client-key\0");
+    } else {
+        hasher.update(b"content\0");
+    }
+    for field in [client_key, email, subject, body] {
+        let field = field.unwrap_or_default();
+        // The length prefix keeps the boundaries between the fields unambiguous.
+        hasher.update((field.len() as u64).to_be_bytes());
+        hasher.update(field.as_bytes());
+    }
+    URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// The parts of a success response which are replayed to repeated submissions.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredResponse {
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    body: String,
+}
+
+impl StoredResponse {
+    pub fn from_response(response: &Response<Body>) -> Self {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        Self {
+            status: response.status().as_u16(),
+            location: header(header::LOCATION),
+            content_type: header(header::CONTENT_TYPE),
+            body: match response.body() {
+                Body::Empty => String::new(),
+                Body::Text(text) => text.clone(),
+                Body::Binary(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            },
+        }
+    }
+
+    pub fn to_response(&self) -> Response<Body> {
+        let mut response =
+            Response::builder().status(StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK));
+        if let Some(location) = &self.location {
+            response = response.header(header::LOCATION, location);
+        }
+        if let Some(content_type) = &self.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        response.body(self.body.clone().into()).unwrap()
+    }
+}
+
+/// The outcome of trying to claim an idempotency key.
+#[derive(Debug, PartialEq)]
+pub enum Claim {
+    /// No identical submission was seen within the window, so this one should be processed.
+    Claimed,
+    /// An identical submission is being processed.
+    Pending,
+    /// An identical submission was sent with the given response.
+    Completed(StoredResponse),
+}
+
+// The trait is only implemented within this package, where the concrete futures are known.
+#[allow(async_fn_in_trait)]
+pub trait IdempotencyStore {
+    /// Records that a submission with the given key is being processed, unless there is an
+    /// unexpired record of one already, which is returned instead.
+    async fn claim(&self, key: &str, expires_at: SystemTime) -> Result<Claim, lambda_http::Error>;
+
+    /// Records the response to the submission with the given key.
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        expires_at: SystemTime,
+    ) -> Result<(), lambda_http::Error>;
+
+    /// Removes the claim on the given key, so that the submission can be sent again.
+    async fn release(&self, key: &str) -> Result<(), lambda_http::Error>;
+}
+
+/// The idempotency store selected by the environment variable `IDEMPOTENCY_TABLE`: the
+/// [`DynamoDbIdempotencyStore`] on the given table if it is set, otherwise an
+/// [`InMemoryIdempotencyStore`].
+pub enum ConfiguredIdempotencyStore {
+    DynamoDb(DynamoDbIdempotencyStore),
+    InMemory(InMemoryIdempotencyStore),
+}
+
+impl ConfiguredIdempotencyStore {
+    pub async fn from_environment() -> Self {
+        match std::env::var("IDEMPOTENCY_TABLE") {
+            Ok(table) => {
+                let config = load_aws_config(None).await;
+                Self::DynamoDb(DynamoDbIdempotencyStore {
+                    client: aws_sdk_dynamodb::Client::new(&config),
+                    table,
+                })
+            }
+            Err(_) => Self::InMemory(Default::default()),
+        }
+    }
+}
+
+impl IdempotencyStore for ConfiguredIdempotencyStore {
+    async fn claim(&self, key: &str, expires_at: SystemTime) -> Result<Claim, lambda_http::Error> {
+        match self {
+            Self::DynamoDb(store) => store.claim(key, expires_at).await,
+            Self::InMemory(store) => store.claim(key, expires_at).await,
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        expires_at: SystemTime,
+    ) -> Result<(), lambda_http::Error> {
+        match self {
+            Self::DynamoDb(store) => store.complete(key, response, expires_at).await,
+            Self::InMemory(store) => store.complete(key, response, expires_at).await,
+        }
+    }
+
+    async fn release(&self, key: &str) -> Result<(), lambda_http::Error> {
+        match self {
+            Self::DynamoDb(store) => store.release(key).await,
+            Self::InMemory(store) => store.release(key).await,
+        }
+    }
+}
+
+/// Keeps the records in a DynamoDB table, so that they are shared between instances.
+///
+/// The table has the partition key `idempotency_key` (a string). Each item has the attribute
+/// `expires_at` with the time in seconds since the epoch after which it no longer counts, which
+/// should be configured as the time to live of the table so that old items are removed, and, once
+/// the submission was sent, the attribute `response` with the response as JSON.
+pub struct DynamoDbIdempotencyStore {
+    client: aws_sdk_dynamodb::Client,
+    table: String,
+}
+
+impl DynamoDbIdempotencyStore {
+    fn claim_from_item(
+        item: &HashMap<String, AttributeValue>,
+    ) -> Result<Claim, lambda_http::Error> {
+        match item.get("response") {
+            Some(AttributeValue::S(response)) => {
+                Ok(Claim::Completed(serde_json::from_str(response)?))
+            }
+            _ => Ok(Claim::Pending),
+        }
+    }
+}
+
+impl IdempotencyStore for DynamoDbIdempotencyStore {
+    async fn claim(&self, key: &str, expires_at: SystemTime) -> Result<Claim, lambda_http::Error> {
+        let result = self
+            .client
+            .put_item()
+            .table_name(&self.table)
+            .item("idempotency_key", AttributeValue::S(key.into()))
+            .item("expires_at", epoch_seconds(expires_at))
+            .condition_expression("attribute_not_exists(idempotency_key) OR expires_at < :now")
+            .expression_attribute_values(":now", epoch_seconds(SystemTime::now()))
+            .return_values_on_condition_check_failure(ReturnValuesOnConditionCheckFailure::AllOld)
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(Claim::Claimed),
+            Err(error) => match error.into_service_error() {
+                PutItemError::ConditionalCheckFailedException(exception) => {
+                    Self::claim_from_item(exception.item().unwrap_or(&HashMap::new()))
+                }
+                error => Err(error.into()),
+            },
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        expires_at: SystemTime,
+    ) -> Result<(), lambda_http::Error> {
+        self.client
+            .put_item()
+            .table_name(&self.table)
+            .item("idempotency_key", AttributeValue::S(key.into()))
+            .item("expires_at", epoch_seconds(expires_at))
+            .item(
+                "response",
+                AttributeValue::S(serde_json::to_string(response)?),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), lambda_http::Error> {
+        self.client
+            .delete_item()
+            .table_name(&self.table)
+            .key("idempotency_key", AttributeValue::S(key.into()))
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+fn epoch_seconds(time: SystemTime) -> AttributeValue {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    AttributeValue::N(seconds.to_string())
+}
+
+/// Keeps the records in memory. Since each instance has its own, this only catches repeats which
+/// reach the same warm instance.
+#[derive(Default)]
+pub struct InMemoryIdempotencyStore {
+    records: Mutex<HashMap<String, Record>>,
+}
+
+struct Record {
+    expires_at: SystemTime,
+    response: Option<StoredResponse>,
+}
+
+impl IdempotencyStore for InMemoryIdempotencyStore {
+    async fn claim(&self, key: &str, expires_at: SystemTime) -> Result<Claim, lambda_http::Error> {
+        let now = SystemTime::now();
+        let mut records = self.records.lock().await;
+        records.retain(|_, record| record.expires_at > now);
+        if let Some(record) = records.get(key) {
+            return Ok(match &record.response {
+                Some(response) => Claim::Completed(response.clone()),
+                None => Claim::Pending,
+            });
+        }
+        records.insert(
+            key.into(),
+            Record {
+                expires_at,
+                response: None,
+            },
+        );
+        Ok(Claim::Claimed)
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: &StoredResponse,
+        expires_at: SystemTime,
+    ) -> Result<(), lambda_http::Error> {
+        self.records.lock().await.insert(
+            key.into(),
+            Record {
+                expires_at,
+                response: Some(response.clone()),
+            },
+        );
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), lambda_http::Error> {
+        self.records.lock().await.remove(key);
+        Ok(())
+    }
+}
+
+/// What to do with a submission, according to the identical ones before it.
+pub enum Admission {
+    /// Process the submission and then [`Deduplicator::complete`] or [`Deduplicator::release`]
+    /// its key.
+    Proceed,
+    /// Answer with the response to an identical submission which was already sent.
+    Repeat(Response<Body>),
+    /// An identical submission is still being processed after waiting for it.
+    InProgress,
+}
+
+/// Answers repeated identical submissions within a time window with the response to the first one
+/// instead of sending the message again, so that a double click does not produce two emails.
+///
+/// The window is configured through `IDEMPOTENCY_WINDOW_SECS` and defaults to ten minutes. Only
+/// successful submissions are remembered for the window; failed ones release their key so that the
+/// visitor can try again. If the store is unavailable, submissions are processed as if it did not
+/// exist.
+pub struct Deduplicator<StoreT = ConfiguredIdempotencyStore> {
+    store: StoreT,
+    window: Duration,
+    pending_wait: Duration,
+    /// The keys claimed by this instance, which it still has to complete or release.
+    claimed: Mutex<HashSet<String>>,
+}
+
+impl Deduplicator {
+    pub async fn from_environment() -> Self {
+        let window = std::env::var("IDEMPOTENCY_WINDOW_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW);
+        Self::new(
+            ConfiguredIdempotencyStore::from_environment().await,
+            window,
+            PENDING_WAIT,
+        )
+    }
+}
+
+impl<StoreT: IdempotencyStore> Deduplicator<StoreT> {
+    fn new(store: StoreT, window: Duration, pending_wait: Duration) -> Self {
+        Self {
+            store,
+            window,
+            pending_wait,
+            claimed: Default::default(),
+        }
+    }
+
+    /// Claims the key for a submission, waiting for an identical submission which is being
+    /// processed to finish.
+    pub async fn admit(&self, key: &str) -> Admission {
+        let started_at = SystemTime::now();
+        loop {
+            let expires_at = SystemTime::now() + PENDING_CLAIM_LIFETIME.min(self.window);
+            match self.store.claim(key, expires_at).await {
+                Ok(Claim::Claimed) => {
+                    self.claimed.lock().await.insert(key.into());
+                    return Admission::Proceed;
+                }
+                Ok(Claim::Completed(response)) => {
+                    info!("Answering repeated submission {key} with the original response");
+                    return Admission::Repeat(response.to_response());
+                }
+                Ok(Claim::Pending) => {
+                    if started_at.elapsed().unwrap_or_default() >= self.pending_wait {
+                        return Admission::InProgress;
+                    }
+                    tokio::time::sleep(PENDING_POLL_INTERVAL).await;
+                }
+                Err(error) => {
+                    warn!("Unable to check for repeated submission, processing it: {error}");
+                    return Admission::Proceed;
+                }
+            }
+        }
+    }
+
+    /// Records the response to the submission with the given key for the rest of the window.
+    pub async fn complete(&self, key: &str, response: &Response<Body>) {
+        if !self.claimed.lock().await.remove(key) {
+            return;
+        }
+        if let Err(error) = self
+            .store
+            .complete(
+                key,
+                &StoredResponse::from_response(response),
+                SystemTime::now() + self.window,
+            )
+            .await
+        {
+            warn!("Unable to record response to submission {key}: {error}");
+        }
+    }
+
+    /// Records that the retry token of the given failed submission was redeemed, for as long as
+    /// the token could still be valid, and returns whether it had not been redeemed before.
+    ///
+    /// Since retry tokens bypass the captcha, a token is refused if the store is unavailable. With
+    /// the in-memory store, a token can only be redeemed once per instance.
+    pub async fn redeem_retry_token(&self, submission_id: &str, lifetime: Duration) -> bool {
+        let key = format!("retry-token:{submission_id}");
+        match self.store.claim(&key, SystemTime::now() + lifetime).await {
+            Ok(Claim::Claimed) => true,
+            Ok(Claim::Pending | Claim::Completed(_)) => false,
+            Err(error) => {
+                warn!("Unable to record redemption of retry token, refusing it: {error}");
+                false
+            }
+        }
+    }
+
+    /// Releases the key of a submission which failed.
+    pub async fn release(&self, key: &str) {
+        if !self.claimed.lock().await.remove(key) {
+            return;
+        }
+        if let Err(error) = self.store.release(key).await {
+            warn!("Unable to release submission {key}: {error}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        idempotency_key, Admission, Claim, Deduplicator, IdempotencyStore,
+        InMemoryIdempotencyStore, StoredResponse,
+    };
+    use googletest::prelude::*;
+    use lambda_http::{Body, Response};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn content_key_depends_on_each_field() -> Result<()> {
+        let key = idempotency_key(None, Some("a@example.com"), Some("Hi"), Some("Body"));
+
+        verify_that!(
+            [
+                idempotency_key(None, Some("b@example.com"), Some("Hi"), Some("Body")),
+                idempotency_key(None, Some("a@example.com"), Some("Hi!"), Some("Body")),
+                idempotency_key(None, Some("a@example.com"), Some("Hi"), Some("Body!")),
+                idempotency_key(None, Some("a@example.com"), Some("HiBody"), Some("")),
+            ],
+            each(not(eq(key.clone())))
+        )
+    }
+
+    #[test]
+    fn client_key_distinguishes_submissions_of_same_message() -> Result<()> {
+        let key = idempotency_key(Some("abc"), Some("a@example.com"), Some("Hi"), Some("Body"));
+
+        verify_that!(
+            [
+                idempotency_key(Some("def"), Some("a@example.com"), Some("Hi"), Some("Body")),
+                idempotency_key(None, Some("a@example.com"), Some("Hi"), Some("Body")),
+            ],
+            each(not(eq(key.clone())))
+        )
+    }
+
+    #[test]
+    fn same_client_key_with_other_message_is_not_duplicate() -> Result<()> {
+        let key = idempotency_key(Some("abc"), Some("a@example.com"), Some("Hi"), Some("Body"));
+
+        verify_that!(
+            [
+                idempotency_key(Some("abc"), Some("b@example.com"), Some("Hi"), Some("Body")),
+                idempotency_key(
+                    Some("abc"),
+                    Some("a@example.com"),
+                    Some("Other"),
+                    Some("Body")
+                ),
+                idempotency_key(
+                    Some("abc"),
+                    Some("a@example.com"),
+                    Some("Hi"),
+                    Some("Other")
+                ),
+            ],
+            each(not(eq(key.clone())))
+        )
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_returns_completed_response() -> Result<()> {
+        let subject = InMemoryIdempotencyStore::default();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        subject.claim("key", expires_at).await.unwrap();
+        subject
+            .complete("key", &response(), expires_at)
+            .await
+            .unwrap();
+
+        verify_that!(
+            subject.claim("key", expires_at).await.unwrap(),
+            eq(Claim::Completed(response()))
+        )
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_forgets_expired_records() -> Result<()> {
+        let subject = InMemoryIdempotencyStore::default();
+        subject.claim("key", SystemTime::now()).await.unwrap();
+
+        verify_that!(
+            subject
+                .claim("key", SystemTime::now() + Duration::from_secs(60))
+                .await
+                .unwrap(),
+            eq(Claim::Claimed)
+        )
+    }
+
+    #[tokio::test]
+    async fn repeats_response_to_completed_submission() -> Result<()> {
+        let subject = deduplicator();
+        let Admission::Proceed = subject.admit("key").await else {
+            return fail!("First submission was not admitted");
+        };
+        subject.complete("key", &response().to_response()).await;
+
+        let Admission::Repeat(repeated) = subject.admit("key").await else {
+            return fail!("Repeated submission was admitted");
+        };
+        verify_that!(StoredResponse::from_response(&repeated), eq(response()))
+    }
+
+    #[tokio::test]
+    async fn admits_submission_again_after_release() -> Result<()> {
+        let subject = deduplicator();
+        subject.admit("key").await;
+        subject.release("key").await;
+
+        verify_that!(
+            matches!(subject.admit("key").await, Admission::Proceed),
+            eq(true)
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_submission_in_progress_after_waiting() -> Result<()> {
+        let subject = deduplicator();
+        subject.admit("key").await;
+
+        verify_that!(
+            matches!(subject.admit("key").await, Admission::InProgress),
+            eq(true)
+        )
+    }
+
+    #[tokio::test]
+    async fn redeems_retry_token_only_once() -> Result<()> {
+        let subject = deduplicator();
+        let lifetime = Duration::from_secs(60);
+
+        verify_that!(
+            (
+                subject.redeem_retry_token("submission", lifetime).await,
+                subject.redeem_retry_token("submission", lifetime).await
+            ),
+            eq((true, false))
+        )
+    }
+
+    fn deduplicator() -> Deduplicator<InMemoryIdempotencyStore> {
+        Deduplicator::new(
+            InMemoryIdempotencyStore::default(),
+            Duration::from_secs(60),
+            Duration::from_millis(10),
+        )
+    }
+
+    fn response() -> StoredResponse {
+        StoredResponse::from_response(
+            &Response::builder()
+                .status(303)
+                .header("Location", "https://hovinen.tech/email-sent.html")
+                .body(Body::Empty)
+                .unwrap(),
+        )
+    }
+}
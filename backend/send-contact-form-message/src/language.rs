@@ -0,0 +1,260 @@
+use crate::error_page::DEFAULT_LANGUAGE;
+use lambda_http::Error;
+use tracing::warn;
+use unic_langid::LanguageIdentifier;
+
+/// Chooses the language of the responses to a submission from an allow-list, so that arbitrary
+/// strings never end up in templates or redirect URLs.
+///
+/// The allow-list is configured through `SUPPORTED_LANGUAGES` as a comma-separated list, and
+/// otherwise consists of the languages for which there are error page templates. The language used
+/// when no requested language is supported is configured through `DEFAULT_LANGUAGE` and is English
+/// by default. All of these must be well-formed BCP 47 language tags.
+pub struct LanguageNegotiator {
+    supported: Vec<String>,
+    default: String,
+}
+
+impl LanguageNegotiator {
+    pub fn from_environment<'a>(
+        available: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, Error> {
+        let supported = match std::env::var("SUPPORTED_LANGUAGES") {
+            Ok(languages) => languages
+                .split(',')
+                .map(str::trim)
+                .filter(|language| !language.is_empty())
+                .map(|language| {
+                    normalize_language_tag(language).ok_or_else(|| {
+                        format!("Invalid language {language} in SUPPORTED_LANGUAGES")
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            Err(_) => available
+                .into_iter()
+                .filter_map(|language| {
+                    let normalized = normalize_language_tag(language);
+                    if normalized.is_none() {
+                        warn!("Ignoring templates for invalid language {language}");
+                    }
+                    normalized
+                })
+                .collect(),
+        };
+        let default = match std::env::var("DEFAULT_LANGUAGE") {
+            Ok(language) => normalize_language_tag(&language)
+                .ok_or_else(|| format!("Invalid DEFAULT_LANGUAGE {language}"))?,
+            Err(_) => DEFAULT_LANGUAGE.into(),
+        };
+        Ok(Self::new(supported, default))
+    }
+
+    fn new(mut supported: Vec<String>, default: String) -> Self {
+        if !supported
+            .iter()
+            .any(|language| language.eq_ignore_ascii_case(&default))
+        {
+            warn!("Default language {default} is not in the supported languages, adding it");
+            supported.push(default.clone());
+        }
+        Self { supported, default }
+    }
+
+    pub fn supported_languages(&self) -> &[String] {
+        &self.supported
+    }
+
+    pub fn default_language(&self) -> &str {
+        &self.default
+    }
+
+    /// Returns the first supported language from the language requested in the form, then the
+    /// languages in the `Accept-Language` header in order of preference, and finally the default
+    /// language. Each requested language falls back to its more general forms, e.g. `de-AT` to
+    /// `de`.
+    pub fn negotiate(&self, requested: Option<&str>, accept_language: Option<&str>) -> &str {
+        requested
+            .into_iter()
+            .chain(
+                accept_language
+                    .map(parse_accept_language)
+                    .unwrap_or_default(),
+            )
+            .flat_map(fallback_chain)
+            .find_map(|candidate| self.supported(candidate))
+            .unwrap_or(&self.default)
+    }
+
+    fn supported(&self, language: &str) -> Option<&str> {
+        self.supported
+            .iter()
+            .find(|supported| supported.eq_ignore_ascii_case(language))
+            .map(String::as_str)
+    }
+}
+
+/// Returns the canonical form of a well-formed BCP 47 language tag, e.g. `de-CH` for `DE-ch`, or
+/// `None` if the tag is malformed.
+///
+/// Only language, script, region and variant subtags are accepted, which covers every language the
+/// site could be offered in. The tag must use `-` as the separator; the `_` of POSIX locales is
+/// rejected.
+pub fn normalize_language_tag(tag: &str) -> Option<String> {
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return None;
+    }
+    tag.parse::<LanguageIdentifier>()
+        .ok()
+        .map(|language| language.to_string())
+}
+
+/// Returns the language followed by its successively more general forms, e.g. `de-CH-1996`,
+/// `de-CH`, `de`.
+pub fn fallback_chain(language: &str) -> impl Iterator<Item = &str> {
+    let mut next = Some(language);
+    std::iter::from_fn(move || {
+        let current = next?;
+        next = current.rsplit_once('-').map(|(general, _)| general);
+        Some(current)
+    })
+}
+
+/// Returns the language ranges of an `Accept-Language` header, most preferred first. Ranges with a
+/// quality of zero and the wildcard `*` are omitted.
+fn parse_accept_language(header: &str) -> Vec<&str> {
+    let mut ranges: Vec<(&str, f32)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let range = parts.next().filter(|range| !range.is_empty())?;
+            let quality = parts
+                .find_map(|parameter| parameter.strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.parse().ok())?;
+            Some((range, quality))
+        })
+        .filter(|(range, quality)| {
+            *range != "*" && *quality > 0.0 && normalize_language_tag(range).is_some()
+        })
+        .collect();
+    // The sort is stable, so ranges with equal quality keep their order.
+    ranges.sort_by(|(_, left), (_, right)| right.total_cmp(left));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fallback_chain, normalize_language_tag, LanguageNegotiator};
+    use googletest::prelude::*;
+
+    #[test]
+    fn uses_requested_language_when_supported() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(Some("de"), Some("fr")), eq("de"))
+    }
+
+    #[test]
+    fn falls_back_to_more_general_requested_language() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(Some("de-AT"), None), eq("de"))
+    }
+
+    #[test]
+    fn matches_languages_case_insensitively() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(Some("DE"), None), eq("de"))
+    }
+
+    #[test]
+    fn uses_accept_language_when_requested_language_is_unsupported() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(
+            subject.negotiate(Some("xx"), Some("es;q=0.5, de-CH;q=0.8, *;q=0.9")),
+            eq("de")
+        )
+    }
+
+    #[test]
+    fn uses_accept_language_when_language_is_not_requested() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(None, Some("fr-FR,de;q=0.7")), eq("de"))
+    }
+
+    #[test]
+    fn ignores_accept_language_with_zero_quality() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(None, Some("de;q=0")), eq("en"))
+    }
+
+    #[test]
+    fn uses_default_language_when_nothing_is_supported() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(
+            subject.negotiate(Some("../../evil"), Some("xx, yy")),
+            eq("en")
+        )
+    }
+
+    #[test]
+    fn adds_default_language_to_supported_languages() -> Result<()> {
+        let subject = LanguageNegotiator::new(vec!["de".into()], "fr".into());
+
+        verify_that!(subject.negotiate(Some("fr"), None), eq("fr"))
+    }
+
+    #[test]
+    fn ignores_malformed_accept_language_ranges() -> Result<()> {
+        let subject = negotiator();
+
+        verify_that!(subject.negotiate(None, Some("de_DE, de/../en")), eq("en"))
+    }
+
+    #[googletest::test]
+    fn normalizes_well_formed_language_tags() {
+        expect_that!(normalize_language_tag("de"), some(eq("de")));
+        expect_that!(normalize_language_tag("DE-ch"), some(eq("de-CH")));
+        expect_that!(normalize_language_tag("de-CH-1996"), some(eq("de-CH-1996")));
+        expect_that!(normalize_language_tag("zh-Hant-TW"), some(eq("zh-Hant-TW")));
+        expect_that!(normalize_language_tag("es-419"), some(eq("es-419")));
+    }
+
+    #[googletest::test]
+    fn rejects_malformed_language_tags() {
+        for tag in [
+            "",
+            "../../etc/passwd",
+            "de/../../evil",
+            "..%2F..%2Fevil",
+            "%2e%2e%2f",
+            "de\r\nSet-Cookie: evil=1",
+            "de%0d%0aLocation:%20https://evil.example",
+            "de_DE",
+            "de--CH",
+            "-de",
+            "de-",
+            "toolongprimarylanguage",
+            "de CH",
+            "https://evil.example",
+        ] {
+            expect_that!(normalize_language_tag(tag), none(), "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn fallback_chain_ends_with_primary_language() -> Result<()> {
+        verify_that!(
+            fallback_chain("de-CH-1996").collect::<Vec<_>>(),
+            elements_are![eq("de-CH-1996"), eq("de-CH"), eq("de")]
+        )
+    }
+
+    fn negotiator() -> LanguageNegotiator {
+        LanguageNegotiator::new(vec!["en".into(), "de".into()], "en".into())
+    }
+}
@@ -0,0 +1,3282 @@
+pub mod admin;
+mod alerting;
+mod consent;
+mod dkim;
+mod error_page;
+mod form_schema;
+mod friendlycaptcha;
+mod health_check;
+mod idempotency;
+mod language;
+mod limits;
+mod mailer;
+mod messages;
+mod newsletter;
+mod oauth2;
+mod secret_checks;
+mod secret_schema;
+pub mod secrets;
+mod sender_rules;
+mod submission;
+mod success_page;
+mod template_source;
+mod token;
+
+use alerting::{AlertKind, Alerter};
+pub use consent::PeerAddress;
+use consent::{
+    client_ip, default_privacy_policy_version, ConsentRecord, IpHashKey, IP_HASH_KEY_NAME,
+};
+use dkim::{dkim_signing_enabled, DkimSigningKeySecret, DKIM_SIGNING_KEY_NAME};
+use error_page::{mailto_url, ErrorPageContent, ErrorPages, DEFAULT_LANGUAGE};
+use form_schema::{format_fields, is_checked, FieldError, FormField, FormSchemas};
+use friendlycaptcha::FriendlyCaptchaVerifier;
+use health_check::{
+    unauthorized_response, HealthCheckToken, HealthReport, HEALTH_CHECK_PATH,
+    HEALTH_CHECK_TOKEN_NAME,
+};
+use idempotency::{idempotency_key, Admission, Deduplicator, IDEMPOTENCY_KEY_HEADER};
+use lambda_http::{
+    http::{header, Method, StatusCode},
+    Body, Error, Request, RequestPayloadExt, Response,
+};
+use language::{normalize_language_tag, LanguageNegotiator};
+use lettre::{
+    message::{
+        dkim::DkimConfig,
+        header::{ContentType, HeaderName, HeaderValue},
+        Mailbox,
+    },
+    Message,
+};
+use limits::RequestLimits;
+use mailer::{Mailer, RelayConfig, SmtpCredentials};
+use messages::Messages;
+use newsletter::{
+    confirmation_token, confirmation_url, subscription_token_lifetime, ConfiguredSubscriberList,
+    Subscriber, SubscriberList, CONFIRM_SUBSCRIPTION_PATH,
+};
+use secret_checks::prefetch_secrets;
+use secrets::{secrets_cache_ttl, SecretRepository};
+use sender_rules::{Sender, SenderRules, Verdict};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, collections::BTreeMap, sync::OnceLock, time::Instant};
+use submission::{is_valid_form_id, list_id, SubmissionId};
+use success_page::{Success, SuccessPages};
+use template_source::TemplateSource;
+use token::{retry_token_lifetime, TokenKey, TokenPurpose, TOKEN_KEY_NAME};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+const FROM_ADDRESS: &str = "Web contact form <noreply@hovinen.tech>";
+const TO_ADDRESS: &str = "Bradford Hovinen <bradford@hovinen.tech>";
+
+const BASE_HOST: &str = "hovinen.tech";
+
+static FROM_MAILBOX: OnceLock<Mailbox> = OnceLock::new();
+static TO_MAILBOX: OnceLock<Mailbox> = OnceLock::new();
+
+pub struct ContactFormMessageHandler<SecretRepositoryT: SecretRepository> {
+    secrets_repository: SecretRepositoryT,
+    mailer: Mutex<Option<CachedMailer>>,
+    friendlycaptcha_verifier: FriendlyCaptchaVerifier<SecretRepositoryT>,
+    alerter: Alerter,
+    error_pages: ErrorPages,
+    success_pages: SuccessPages,
+    languages: LanguageNegotiator,
+    messages: Messages,
+    form_schemas: FormSchemas,
+    limits: RequestLimits,
+    deduplicator: Deduplicator,
+    sender_rules: SenderRules,
+    subscriber_list: Option<ConfiguredSubscriberList>,
+    print_emails: bool,
+}
+
+impl<SecretRepositoryT: SecretRepository> ContactFormMessageHandler<SecretRepositoryT> {
+    pub async fn new() -> Self
+    where
+        SecretRepositoryT: Clone,
+    {
+        let secrets_repository = SecretRepositoryT::open().await;
+        // Unlike broken templates, unusable secrets do not stop the instance from starting, since
+        // they can be fixed without a deployment and the instance still reports each failed
+        // submission.
+        let unusable_secrets = prefetch_secrets(&secrets_repository).await;
+        if unusable_secrets > 0 {
+            error!("{unusable_secrets} required secrets are unusable; submissions will fail");
+        }
+        // Failing here keeps an instance with broken templates from accepting traffic.
+        let templates = TemplateSource::load_from_environment()
+            .await
+            .unwrap_or_else(|error| panic!("Unable to load templates: {error}"));
+        let error_pages = ErrorPages::from_files(&templates)
+            .unwrap_or_else(|error| panic!("Unable to load error page templates: {error}"));
+        let success_pages = SuccessPages::from_files(&templates)
+            .unwrap_or_else(|error| panic!("Unable to load success page templates: {error}"));
+        let languages = LanguageNegotiator::from_environment(error_pages.languages())
+            .unwrap_or_else(|error| panic!("Invalid language configuration: {error}"));
+        let form_schemas = FormSchemas::from_environment()
+            .unwrap_or_else(|error| panic!("Unable to load form schemas: {error}"));
+        let sender_rules = SenderRules::from_environment()
+            .await
+            .unwrap_or_else(|error| panic!("Invalid sender rules configuration: {error}"));
+        let limits = RequestLimits::from_environment()
+            .unwrap_or_else(|error| panic!("Invalid request limits: {error}"));
+        if form_schemas.uses_inline_success_page() && !success_pages.is_available() {
+            panic!(
+                "A form shows the success page inline, but there is no template email-sent.html"
+            );
+        }
+        Self {
+            secrets_repository: secrets_repository.clone(),
+            mailer: Default::default(),
+            friendlycaptcha_verifier: FriendlyCaptchaVerifier::new(secrets_repository),
+            alerter: Alerter::from_environment().await,
+            error_pages,
+            success_pages,
+            languages,
+            messages: Messages::bundled()
+                .unwrap_or_else(|error| panic!("Unable to load message catalogue: {error}")),
+            form_schemas,
+            limits,
+            deduplicator: Deduplicator::from_environment().await,
+            sender_rules,
+            subscriber_list: ConfiguredSubscriberList::from_environment(),
+            print_emails: false,
+        }
+    }
+
+    /// Prints outgoing emails to standard output instead of sending them, for local development.
+    pub fn printing_emails(self) -> Self {
+        Self {
+            print_emails: true,
+            ..self
+        }
+    }
+
+    /// The largest request body in bytes which the handler accepts, from `MAX_REQUEST_BYTES`.
+    pub fn max_request_bytes(&self) -> usize {
+        self.limits.max_request_bytes()
+    }
+
+    /// Handles a request from any of the integrations which lambda_http supports: API Gateway REST
+    /// and HTTP APIs, Lambda Function URLs and Application Load Balancers.
+    ///
+    /// Successful submissions are answered with `303 See Other` and an absolute `Location`, which
+    /// every integration passes to the browser unchanged, so that reloading the success page does
+    /// not submit the form again. The handler sets no cookies, so the differences in how the
+    /// integrations return them do not arise.
+    ///
+    /// The size of the request body is checked against the [`RequestLimits`] before it is parsed.
+    /// Repeated identical submissions are answered by the [`Deduplicator`].
+    pub async fn handle(&self, event: Request) -> Result<Response<Body>, Error> {
+        if event.method() == Method::GET && event.uri().path().ends_with(HEALTH_CHECK_PATH) {
+            return Ok(self.health_check(&event).await);
+        }
+        let accept_language = event
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok());
+        if event.method() == Method::GET && event.uri().path().ends_with(CONFIRM_SUBSCRIPTION_PATH)
+        {
+            return Ok(self.confirm_subscription(&event, accept_language).await);
+        }
+        if let Err(description) = self.limits.check_request_size(event.body()) {
+            let error = ContactFormError::TooLarge {
+                description,
+                language: self.languages.negotiate(None, accept_language).into(),
+            };
+            self.report(&error).await;
+            return Ok(self.error_response(error, None, &[], &event));
+        }
+        let Some(message) = event.payload()? else {
+            let error = ContactFormError::InternalError {
+                description: "Missing event payload".into(),
+                subject: "(Unable to retrieve)".into(),
+                body: "(Unable to retrieve)".into(),
+                language: self.languages.negotiate(None, accept_language).into(),
+                retryable: false,
+                alert: AlertKind::MissingPayload,
+            };
+            self.report(&error).await;
+            return Ok(self.error_response(error, None, &[], &event));
+        };
+        let message = match self.resolve_retry_token(message, accept_language).await {
+            Ok(message) => message,
+            Err(error) => {
+                self.report(&error).await;
+                return Ok(self.error_response(error, None, &[], &event));
+            }
+        };
+        let idempotency_key = idempotency_key(
+            event
+                .headers()
+                .get(IDEMPOTENCY_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .or(message.idempotency_key.as_deref()),
+            message.email.as_deref(),
+            message.subject.as_deref(),
+            message.body.as_deref(),
+        );
+        match self.deduplicator.admit(&idempotency_key).await {
+            Admission::Proceed => {}
+            Admission::Repeat(response) => return Ok(response),
+            Admission::InProgress => {
+                let language = self
+                    .languages
+                    .negotiate(message.language.as_deref(), accept_language);
+                return Ok(self.message_response(
+                    StatusCode::CONFLICT,
+                    messages::SUBMISSION_IN_PROGRESS,
+                    language,
+                    &event,
+                ));
+            }
+        }
+        let submission_id = SubmissionId::generate();
+        let client_ip = client_ip(&event);
+        match self
+            .process_message(
+                &message,
+                accept_language,
+                client_ip.as_deref(),
+                &submission_id,
+            )
+            .await
+        {
+            Ok(language) => {
+                if let (true, Some(email)) = (
+                    is_checked(message.newsletter.as_deref()),
+                    message.email.as_deref(),
+                ) {
+                    self.request_subscription(email, &language).await;
+                }
+                let response = self.success_response(&message, &language, &submission_id, &event);
+                self.deduplicator
+                    .complete(&idempotency_key, &response)
+                    .await;
+                Ok(response)
+            }
+            Err(error) => {
+                self.deduplicator.release(&idempotency_key).await;
+                self.report(&error).await;
+                let retry_token = self
+                    .issue_retry_token(&message, &error, &submission_id)
+                    .await;
+                let fields = self
+                    .form_schemas
+                    .get(message.form_id.as_deref())
+                    .validate(&message.fields)
+                    .unwrap_or_default();
+                Ok(self.error_response(error, retry_token.as_deref(), &fields, &event))
+            }
+        }
+    }
+
+    /// Replaces a message which carries a retry token from the error page with the message in the
+    /// token. The captcha was already solved for the original submission, so the returned message
+    /// is marked as verified. Each token can only be redeemed once.
+    async fn resolve_retry_token(
+        &self,
+        message: ContactFormMessage,
+        accept_language: Option<&str>,
+    ) -> Result<ContactFormMessage, ContactFormError> {
+        let Some(token) = message.retry_token.as_deref() else {
+            return Ok(message);
+        };
+        let language = self
+            .languages
+            .negotiate(message.language.as_deref(), accept_language);
+        let key: TokenKey = self
+            .secrets_repository
+            .get_secret(TOKEN_KEY_NAME)
+            .await
+            .map_err(|error| ContactFormError::InternalError {
+                description: format!("Unable to retrieve {TOKEN_KEY_NAME}: {error}"),
+                subject: "(Unable to retrieve)".into(),
+                body: "(Unable to retrieve)".into(),
+                language: language.into(),
+                retryable: false,
+                alert: AlertKind::TokenKeyUnavailable,
+            })?;
+        let payload: RetryPayload = key.verify(TokenPurpose::Retry, token).map_err(|error| {
+            ContactFormError::ClientError {
+                description: error.to_string(),
+                message_id: messages::RETRY_TOKEN_INVALID,
+                language: language.into(),
+            }
+        })?;
+        if !self
+            .deduplicator
+            .redeem_retry_token(&payload.submission_id, retry_token_lifetime())
+            .await
+        {
+            return Err(ContactFormError::ClientError {
+                description: format!(
+                    "Retry token of submission {} was already used",
+                    payload.submission_id
+                ),
+                message_id: messages::RETRY_TOKEN_INVALID,
+                language: language.into(),
+            });
+        }
+        let mut retried_message = payload.message;
+        retried_message.captcha_verified = true;
+        Ok(retried_message)
+    }
+
+    /// Returns a token with which the visitor can send the message again from the error page, if
+    /// sending failed because of an internal error after the captcha was verified.
+    async fn issue_retry_token(
+        &self,
+        message: &ContactFormMessage,
+        error: &ContactFormError,
+        submission_id: &SubmissionId,
+    ) -> Option<String> {
+        let ContactFormError::InternalError {
+            language,
+            retryable: true,
+            ..
+        } = error
+        else {
+            return None;
+        };
+        let key: TokenKey = match self.secrets_repository.get_secret(TOKEN_KEY_NAME).await {
+            Ok(key) => key,
+            Err(error) => {
+                warn!("Unable to retrieve {TOKEN_KEY_NAME}, omitting retry button: {error}");
+                return None;
+            }
+        };
+        let payload = RetryPayload {
+            submission_id: submission_id.to_string(),
+            message: ContactFormMessage {
+                language: Some(language.clone()),
+                ..message.clone()
+            },
+        };
+        Some(key.issue(TokenPurpose::Retry, payload, retry_token_lifetime()))
+    }
+
+    /// Returns the response to a failed submission with the text from the message catalogue in the
+    /// visitor's language: the error page, which shows the visitor their message, for internal
+    /// errors and the bare text for client errors. Clients which accept JSON get the message ID
+    /// and text as JSON instead.
+    fn error_response(
+        &self,
+        error: ContactFormError,
+        retry_token: Option<&str>,
+        fields: &[FormField],
+        event: &Request,
+    ) -> Response<Body> {
+        let (subject, body, language) = match error {
+            ContactFormError::InternalError {
+                subject,
+                body,
+                language,
+                ..
+            } if !accepts_json(event) => (subject, body, language),
+            ContactFormError::InternalError { language, .. } => {
+                return self.message_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    messages::INTERNAL_ERROR,
+                    &language,
+                    event,
+                );
+            }
+            ContactFormError::ClientError {
+                message_id,
+                language,
+                ..
+            } => {
+                return self.message_response(
+                    StatusCode::BAD_REQUEST,
+                    message_id,
+                    &language,
+                    event,
+                );
+            }
+            ContactFormError::TooLarge { language, .. } => {
+                return self.message_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    messages::REQUEST_TOO_LARGE,
+                    &language,
+                    event,
+                );
+            }
+            ContactFormError::Blocked { language, .. } => {
+                return self.message_response(
+                    StatusCode::FORBIDDEN,
+                    messages::SENDER_BLOCKED,
+                    &language,
+                    event,
+                );
+            }
+        };
+        let message = self.messages.get(&language, messages::INTERNAL_ERROR);
+        let retry_label = self
+            .messages
+            .get(&language, messages::ERROR_PAGE_RETRY_BUTTON);
+        let mailto_label = self
+            .messages
+            .get(&language, messages::ERROR_PAGE_MAILTO_LINK);
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(
+                self.error_pages
+                    .render(
+                        &ErrorPageContent {
+                            explanation: &message,
+                            subject: &subject,
+                            body: &body,
+                            fields,
+                            retry_token,
+                            retry_label: &retry_label,
+                            mailto_url: &mailto_url(
+                                TO_MAILBOX
+                                    .get_or_init(|| TO_ADDRESS.parse().unwrap())
+                                    .email
+                                    .as_ref(),
+                                &subject,
+                                &body,
+                            ),
+                            mailto_label: &mailto_label,
+                        },
+                        &language,
+                        site_of(event).as_deref(),
+                    )
+                    .into(),
+            )
+            .unwrap()
+    }
+
+    /// Returns a response with the message from the catalogue as plain text, or with its ID and
+    /// text as JSON if the client accepts JSON.
+    fn message_response(
+        &self,
+        status: StatusCode,
+        message_id: &str,
+        language: &str,
+        event: &Request,
+    ) -> Response<Body> {
+        let message = self.messages.get(language, message_id);
+        let response = Response::builder().status(status);
+        if accepts_json(event) {
+            return response
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(
+                    serde_json::json!({ "error": message_id, "message": message })
+                        .to_string()
+                        .into(),
+                )
+                .unwrap();
+        }
+        response
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(message.into())
+            .unwrap()
+    }
+
+    /// Sends the visitor an email with a link which confirms that they want to receive the
+    /// newsletter. Failures are only logged, since the visitor's message itself was delivered.
+    async fn request_subscription(&self, email: &str, language: &str) {
+        if self.subscriber_list.is_none() {
+            return;
+        }
+        let Some(confirmation_url) = confirmation_url() else {
+            warn!("SUBSCRIPTION_CONFIRMATION_URL is not set, not requesting subscription");
+            return;
+        };
+        let key: TokenKey = match self.secrets_repository.get_secret(TOKEN_KEY_NAME).await {
+            Ok(key) => key,
+            Err(error) => {
+                warn!("Unable to retrieve {TOKEN_KEY_NAME}, not requesting subscription: {error}");
+                return;
+            }
+        };
+        let Ok(recipient) = email.parse::<Mailbox>() else {
+            warn!("Invalid email address {email}, not requesting subscription");
+            return;
+        };
+        let token = key.issue(
+            TokenPurpose::Subscription,
+            Subscriber {
+                email: email.into(),
+                language: language.into(),
+            },
+            subscription_token_lifetime(),
+        );
+        let link = format!("{confirmation_url}?token={token}");
+        let confirmation = Message::builder()
+            .from(
+                FROM_MAILBOX
+                    .get_or_init(|| FROM_ADDRESS.parse().unwrap())
+                    .clone(),
+            )
+            .to(recipient)
+            .subject(
+                self.messages
+                    .get(language, messages::SUBSCRIPTION_CONFIRMATION_SUBJECT),
+            )
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.messages.get_with_args(
+                language,
+                messages::SUBSCRIPTION_CONFIRMATION_BODY,
+                &[("link", &link)],
+            ));
+        let confirmation = match confirmation {
+            Ok(confirmation) => confirmation,
+            Err(error) => {
+                warn!("Error building subscription confirmation: {error}");
+                return;
+            }
+        };
+        let confirmation = self.sign_email(confirmation).await;
+        if let Err(error) = self.deliver(&confirmation).await {
+            warn!("Unable to send subscription confirmation: {error}");
+        }
+    }
+
+    /// Handles the link in the confirmation email by adding the visitor to the subscriber list and
+    /// redirecting them to a page which confirms the subscription.
+    async fn confirm_subscription(
+        &self,
+        event: &Request,
+        accept_language: Option<&str>,
+    ) -> Response<Body> {
+        let language = self.languages.negotiate(None, accept_language);
+        let Some(subscriber_list) = self.subscriber_list.as_ref() else {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("".into())
+                .unwrap();
+        };
+        let key: TokenKey = match self.secrets_repository.get_secret(TOKEN_KEY_NAME).await {
+            Ok(key) => key,
+            Err(error) => {
+                return self
+                    .subscription_error(
+                        format!("Unable to retrieve {TOKEN_KEY_NAME}: {error}"),
+                        language,
+                        event,
+                    )
+                    .await;
+            }
+        };
+        let Some(subscriber) = confirmation_token(event).and_then(|token| {
+            key.verify::<Subscriber>(TokenPurpose::Subscription, &token)
+                .inspect_err(|error| warn!("Rejecting subscription confirmation: {error}"))
+                .ok()
+        }) else {
+            return self.message_response(
+                StatusCode::BAD_REQUEST,
+                messages::SUBSCRIPTION_TOKEN_INVALID,
+                language,
+                event,
+            );
+        };
+        if let Err(error) = subscriber_list.add(&subscriber).await {
+            return self
+                .subscription_error(
+                    format!("Unable to add subscriber: {error}"),
+                    &subscriber.language,
+                    event,
+                )
+                .await;
+        }
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(
+                header::LOCATION,
+                Self::create_subscription_confirmed_url(&subscriber.language),
+            )
+            .body("".into())
+            .unwrap()
+    }
+
+    async fn subscription_error(
+        &self,
+        description: String,
+        language: &str,
+        event: &Request,
+    ) -> Response<Body> {
+        error!("{description}");
+        self.alerter
+            .alert(AlertKind::Subscription, &description)
+            .await;
+        self.message_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            messages::INTERNAL_ERROR,
+            language,
+            event,
+        )
+    }
+
+    async fn health_check(&self, event: &Request) -> Response<Body> {
+        match self
+            .secrets_repository
+            .get_secret::<HealthCheckToken>(HEALTH_CHECK_TOKEN_NAME)
+            .await
+        {
+            Ok(token) if token.authorizes(event) => {}
+            Ok(_) => return unauthorized_response(),
+            Err(error) => {
+                error!("Unable to retrieve health check token {HEALTH_CHECK_TOKEN_NAME}: {error}");
+                return unauthorized_response();
+            }
+        }
+
+        let mut report = HealthReport::new();
+        report.record("configuration", Self::check_configuration());
+        report.record("smtp-credentials", self.check_smtp_credentials().await);
+        report.record(
+            "friendlycaptcha-data",
+            self.friendlycaptcha_verifier.check_secret().await,
+        );
+        if dkim_signing_enabled() {
+            report.record("dkim-signing-key", self.dkim_config().await.map(|_| ()));
+        }
+        report.record("smtp-connection", self.check_smtp_connection().await);
+        report.record(
+            "friendlycaptcha-endpoint",
+            FriendlyCaptchaVerifier::<SecretRepositoryT>::check_endpoint_reachable().await,
+        );
+        report.into_response()
+    }
+
+    fn check_configuration() -> Result<(), Error> {
+        RelayConfig::from_environment()?;
+        reqwest::Url::parse(&FriendlyCaptchaVerifier::<SecretRepositoryT>::verification_url())?;
+        Ok(())
+    }
+
+    async fn check_smtp_credentials(&self) -> Result<(), Error> {
+        let relays = RelayConfig::from_environment()?;
+        for name in RelayConfig::credentials_names(&relays) {
+            self.secrets_repository
+                .get_secret::<SmtpCredentials>(name)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn check_smtp_connection(&self) -> Result<(), Error> {
+        // Use fresh transports rather than the pooled ones so that the connection and
+        // authentication are actually exercised.
+        Mailer::build(&self.secrets_repository)
+            .await?
+            .test_connections()
+            .await
+    }
+
+    async fn report(&self, error: &ContactFormError) {
+        error.log();
+        if let ContactFormError::InternalError {
+            description, alert, ..
+        } = error
+        {
+            self.alerter.alert(*alert, description).await;
+        }
+    }
+
+    async fn process_message(
+        &self,
+        message: &ContactFormMessage,
+        accept_language: Option<&str>,
+        client_ip: Option<&str>,
+        submission_id: &SubmissionId,
+    ) -> Result<String, ContactFormError> {
+        let language = self
+            .languages
+            .negotiate(message.language.as_deref(), accept_language);
+        let validated_message = message.validate(language, &self.form_schemas, &self.limits)?;
+        match self.sender_verdict(&validated_message, client_ip).await {
+            Verdict::Blocked(rule) => {
+                return Err(ContactFormError::Blocked {
+                    description: format!("Sender {} blocked by {rule}", validated_message.email),
+                    language: language.into(),
+                });
+            }
+            Verdict::Trusted => {
+                info!(
+                    "Skipping captcha for allowed client {}",
+                    client_ip.unwrap_or_default()
+                );
+            }
+            Verdict::Allowed | Verdict::Unmatched => {
+                self.verify_captcha(&validated_message).await?
+            }
+        }
+        let consent = self.record_consent(&validated_message, client_ip).await;
+        let email =
+            self.construct_email_message(&validated_message, consent.as_ref(), submission_id)?;
+        let email = self.sign_email(email).await;
+        self.send_email(email, &validated_message).await
+    }
+
+    /// Applies the sender rules to the message. If the rules could not be refreshed, the site
+    /// owner is alerted and the previous rules apply.
+    async fn sender_verdict(
+        &self,
+        message: &ValidatedContactFormMessage<'_>,
+        client_ip: Option<&str>,
+    ) -> Verdict {
+        let (rules, error) = self.sender_rules.current(&self.secrets_repository).await;
+        if let Some(error) = error {
+            let description =
+                format!("Unable to load sender rules, keeping previous ones: {error}");
+            error!("{description}");
+            self.alerter
+                .alert(AlertKind::SenderRules, &description)
+                .await;
+        }
+        rules.verdict(&Sender {
+            email: message.email,
+            client_ip,
+            body: message.body,
+        })
+    }
+
+    async fn verify_captcha<'a>(
+        &self,
+        message: &ValidatedContactFormMessage<'a>,
+    ) -> Result<(), ContactFormError> {
+        if message.captcha_verified {
+            // The message is a retry whose captcha was verified with the original submission.
+            return Ok(());
+        }
+        let Some(friendlycaptcha_token) = message.friendlycaptcha_token else {
+            return Err(ContactFormError::ClientError {
+                description: "Missing captcha solution in request".into(),
+                message_id: messages::MISSING_FIELDS,
+                language: message.language.into(),
+            });
+        };
+        self.friendlycaptcha_verifier
+            .verify_token(friendlycaptcha_token)
+            .await
+            .map_err(|e| {
+                e.into_contact_form_error(
+                    message.subject.into(),
+                    message.body.into(),
+                    message.language.into(),
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Returns the record of the visitor's consent to the privacy notice, if they gave it. The IP
+    /// address is omitted from the record if the key to hash it is unavailable.
+    async fn record_consent(
+        &self,
+        message: &ValidatedContactFormMessage<'_>,
+        client_ip: Option<&str>,
+    ) -> Option<ConsentRecord> {
+        let privacy_policy_version = message.consent.as_ref()?;
+        let ip_hash = match client_ip {
+            Some(ip) => match self
+                .secrets_repository
+                .get_secret::<IpHashKey>(IP_HASH_KEY_NAME)
+                .await
+            {
+                Ok(key) => Some(key.hash(ip)),
+                Err(error) => {
+                    warn!("Unable to retrieve {IP_HASH_KEY_NAME}, omitting IP hash: {error}");
+                    None
+                }
+            },
+            None => None,
+        };
+        Some(ConsentRecord::now(
+            privacy_policy_version.clone(),
+            ip_hash,
+            message.language.into(),
+        ))
+    }
+
+    fn construct_email_message(
+        &self,
+        message: &ValidatedContactFormMessage,
+        consent: Option<&ConsentRecord>,
+        submission_id: &SubmissionId,
+    ) -> Result<Message, ContactFormError> {
+        let reply_to_string = if let Some(name) = message.name {
+            format!("{} <{}>", name, message.email)
+        } else {
+            message.email.into()
+        };
+        let Ok(reply_to_email) = reply_to_string.parse() else {
+            return Err(ContactFormError::ClientError {
+                description: format!("Invalid email address {}", message.email),
+                message_id: messages::INVALID_EMAIL,
+                language: message.language.into(),
+            });
+        };
+        let mut builder = Message::builder()
+            .from(
+                FROM_MAILBOX
+                    .get_or_init(|| FROM_ADDRESS.parse().unwrap())
+                    .clone(),
+            )
+            .reply_to(reply_to_email)
+            .to(TO_MAILBOX
+                .get_or_init(|| TO_ADDRESS.parse().unwrap())
+                .clone())
+            .subject(format!("{} [{submission_id}]", message.subject))
+            .message_id(Some(submission_id.message_id()))
+            .raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str("X-Contact-Form-Submission"),
+                submission_id.to_string(),
+            ))
+            .header(ContentType::TEXT_PLAIN);
+        if let Some(form_id) = message.form_id {
+            builder = builder
+                .raw_header(HeaderValue::new(
+                    HeaderName::new_from_ascii_str("List-Id"),
+                    list_id(form_id),
+                ))
+                .raw_header(HeaderValue::new(
+                    HeaderName::new_from_ascii_str("X-Form-Id"),
+                    form_id.into(),
+                ));
+        }
+        for (name, value) in consent.map(ConsentRecord::headers).unwrap_or_default() {
+            builder = builder.raw_header(HeaderValue::new(
+                HeaderName::new_from_ascii_str(name),
+                value,
+            ));
+        }
+        let body = self.limits.truncate_message(message.body);
+        if body.len() < message.body.len() {
+            warn!("Truncated message body of {} bytes", message.body.len());
+        }
+        builder
+            .body(if message.fields.is_empty() {
+                body.into_owned()
+            } else {
+                format!("{}\n{body}", format_fields(&message.fields))
+            })
+            .map_err(|error| ContactFormError::InternalError {
+                description: format!("Error building message: {error}"),
+                subject: message.subject.into(),
+                body: message.body.into(),
+                language: message.language.into(),
+                retryable: true,
+                alert: AlertKind::MessageConstruction,
+            })
+    }
+
+    /// DKIM-signs the message if signing is enabled.
+    ///
+    /// If the message cannot be signed, it is sent unsigned, since a message in the spam folder is
+    /// better than none, and the site owner is alerted.
+    async fn sign_email(&self, mut email: Message) -> Message {
+        if !dkim_signing_enabled() {
+            return email;
+        }
+        match self.dkim_config().await {
+            Ok(dkim_config) => email.sign(&dkim_config),
+            Err(error) => {
+                let description =
+                    format!("Unable to DKIM-sign message, sending it unsigned: {error}");
+                error!("{description}");
+                self.alerter
+                    .alert(AlertKind::DkimSigning, &description)
+                    .await;
+            }
+        }
+        email
+    }
+
+    async fn dkim_config(&self) -> Result<DkimConfig, Error> {
+        let secret: DkimSigningKeySecret = self
+            .secrets_repository
+            .get_secret(DKIM_SIGNING_KEY_NAME)
+            .await?;
+        secret.into_config(
+            FROM_MAILBOX
+                .get_or_init(|| FROM_ADDRESS.parse().unwrap())
+                .email
+                .domain(),
+        )
+    }
+
+    async fn send_email<'a>(
+        &self,
+        email: Message,
+        validated_message: &ValidatedContactFormMessage<'a>,
+    ) -> Result<String, ContactFormError> {
+        self.deliver(&email)
+            .await
+            .map_err(|description| ContactFormError::InternalError {
+                description,
+                subject: validated_message.subject.into(),
+                body: validated_message.body.into(),
+                language: validated_message.language.into(),
+                retryable: true,
+                alert: AlertKind::Delivery,
+            })?;
+        Ok(validated_message.language.into())
+    }
+
+    /// Sends the email through the SMTP relay, or prints it if the handler was configured to print
+    /// emails.
+    async fn deliver(&self, email: &Message) -> Result<(), String> {
+        if self.print_emails {
+            println!("{}", String::from_utf8_lossy(&email.formatted()));
+            return Ok(());
+        }
+        let mailer = self
+            .mailer()
+            .await
+            .map_err(|e| format!("Unable to connect to SMTP server: {e}"))?;
+        let result = match mailer.send(email).await {
+            Err(error) if error.requires_new_transport() => {
+                // The credentials may have been rotated or the pooled connections may have gone
+                // bad, so retry once with a freshly built transport.
+                warn!("Sending failed ({error}), retrying with a new SMTP transport");
+                self.discard_mailer().await;
+                let mailer = self
+                    .mailer()
+                    .await
+                    .map_err(|e| format!("Unable to connect to SMTP server: {e}"))?;
+                mailer.send(email).await
+            }
+            result => result,
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => {
+                if error.requires_new_transport() {
+                    self.discard_mailer().await;
+                }
+                Err(format!("Error sending message: {error}"))
+            }
+        }
+    }
+
+    /// Returns the SMTP transports, building them if there are none, if they are older than the
+    /// secrets cache TTL, so that rotated credentials are picked up, or if an OAuth2 access token
+    /// has expired.
+    async fn mailer(&self) -> Result<Mailer, Error> {
+        let mut mailer = self.mailer.lock().await;
+        if let Some(cached) = mailer.as_ref() {
+            if cached.created_at.elapsed() < secrets_cache_ttl() && !cached.mailer.is_expired() {
+                return Ok(cached.mailer.clone());
+            }
+        }
+        let new_mailer = Mailer::build(&self.secrets_repository).await?;
+        *mailer = Some(CachedMailer {
+            mailer: new_mailer.clone(),
+            created_at: Instant::now(),
+        });
+        Ok(new_mailer)
+    }
+
+    /// Discards the SMTP transports and cached credentials, so that the next message is sent with
+    /// freshly fetched credentials.
+    async fn discard_mailer(&self) {
+        *self.mailer.lock().await = None;
+        if let Ok(relays) = RelayConfig::from_environment() {
+            for name in RelayConfig::credentials_names(&relays) {
+                self.secrets_repository.invalidate(name).await;
+            }
+        }
+    }
+
+    /// Returns the response to a submission which was sent: a redirect or the inline success page,
+    /// according to the schema of the form. The language must be one of the supported languages,
+    /// since it may become part of the URL.
+    fn success_response(
+        &self,
+        message: &ContactFormMessage,
+        language: &str,
+        submission_id: &SubmissionId,
+        event: &Request,
+    ) -> Response<Body> {
+        let schema = self.form_schemas.get(message.form_id.as_deref());
+        let location =
+            match schema
+                .success()
+                .outcome(message.return_to.as_deref(), language, submission_id)
+            {
+                Success::Redirect(location) => location,
+                Success::InlinePage => {
+                    let page = self
+                        .success_pages
+                        .render(submission_id, language, site_of(event).as_deref())
+                        .expect("Success page template is checked at startup");
+                    return Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+                        .body(page.into())
+                        .unwrap();
+                }
+            };
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, location)
+            .body("".into())
+            .unwrap()
+    }
+
+    /// Returns the URL of the page confirming that the visitor subscribed to the newsletter.
+    fn create_subscription_confirmed_url(language: &str) -> String {
+        if language == DEFAULT_LANGUAGE {
+            format!("https://{BASE_HOST}/subscription-confirmed.html")
+        } else {
+            format!("https://{BASE_HOST}/subscription-confirmed.{language}.html")
+        }
+    }
+}
+
+/// Returns whether the client asked for a JSON response in the `Accept` header.
+fn accepts_json(event: &Request) -> bool {
+    event
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+/// Returns the host of the site from which the form was submitted, according to the `Origin` or
+/// `Referer` header.
+fn site_of(event: &Request) -> Option<String> {
+    [header::ORIGIN, header::REFERER]
+        .iter()
+        .filter_map(|name| event.headers().get(name)?.to_str().ok())
+        .find_map(|value| {
+            reqwest::Url::parse(value)
+                .ok()?
+                .host_str()
+                .map(String::from)
+        })
+}
+
+/// A submission of the contact form. It also serves as the payload of retry tokens, which carry
+/// neither the captcha solution nor another retry token.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+struct ContactFormMessage {
+    name: Option<String>,
+    email: Option<String>,
+    subject: Option<String>,
+    body: Option<String>,
+    language: Option<String>,
+    #[serde(rename = "frc-captcha-solution", skip_serializing)]
+    friendlycaptcha_token: Option<String>,
+    #[serde(rename = "form-id")]
+    form_id: Option<String>,
+    #[serde(rename = "retry-token", skip_serializing)]
+    retry_token: Option<String>,
+    /// A key with which the client marks repeats of the same submission, as an alternative to the
+    /// `Idempotency-Key` header.
+    #[serde(rename = "idempotency-key", skip_serializing)]
+    idempotency_key: Option<String>,
+    /// The checkbox with which the visitor accepts the privacy notice.
+    consent: Option<String>,
+    /// The checkbox with which the visitor asks to receive the newsletter.
+    newsletter: Option<String>,
+    /// The URL to which to return the visitor, if the schema of the form allows it.
+    #[serde(rename = "return-to")]
+    return_to: Option<String>,
+    /// The fields beyond those above, which are validated against the schema of the form.
+    #[serde(flatten)]
+    fields: BTreeMap<String, serde_json::Value>,
+    /// Whether the captcha was verified with an earlier submission of the same message.
+    #[serde(skip)]
+    captcha_verified: bool,
+}
+
+/// The content of a retry token: the message and the failed submission which it retries, by which
+/// the token is recorded as redeemed.
+#[derive(Serialize, Deserialize)]
+struct RetryPayload {
+    submission_id: String,
+    message: ContactFormMessage,
+}
+
+impl ContactFormMessage {
+    /// Checks that all required fields are present, that no field is longer than the limit, that
+    /// the language in the form is a well-formed language tag and that the further fields match
+    /// the schema of the form. The language is the one negotiated for the request rather than the
+    /// one in the form.
+    fn validate<'a>(
+        &'a self,
+        language: &'a str,
+        form_schemas: &FormSchemas,
+        limits: &RequestLimits,
+    ) -> Result<ValidatedContactFormMessage<'a>, ContactFormError> {
+        for (name, value) in self.limited_fields() {
+            limits
+                .check_field(name, &value)
+                .map_err(|description| ContactFormError::TooLarge {
+                    description,
+                    language: language.into(),
+                })?;
+        }
+        if let Some(requested) = self.language.as_deref().filter(|l| !l.is_empty()) {
+            if normalize_language_tag(requested).is_none() {
+                return Err(ContactFormError::ClientError {
+                    // Debug formatting keeps control characters out of the log.
+                    description: format!("Invalid language {requested:?}"),
+                    message_id: messages::INVALID_LANGUAGE,
+                    language: language.into(),
+                });
+            }
+        }
+        let missing_fields = || ContactFormError::ClientError {
+            description: "Missing fields in request".into(),
+            message_id: messages::MISSING_FIELDS,
+            language: language.into(),
+        };
+        let ContactFormMessage {
+            name,
+            email: Some(email),
+            subject: Some(subject),
+            body: Some(body),
+            language: _,
+            friendlycaptcha_token,
+            form_id,
+            retry_token: _,
+            idempotency_key: _,
+            consent,
+            newsletter: _,
+            return_to: _,
+            fields,
+            captcha_verified,
+        } = self
+        else {
+            return Err(missing_fields());
+        };
+        if let Some(form_id) = form_id {
+            if !is_valid_form_id(form_id) {
+                return Err(ContactFormError::ClientError {
+                    description: format!("Invalid form ID {form_id}"),
+                    message_id: messages::INVALID_FORM_ID,
+                    language: language.into(),
+                });
+            }
+        }
+
+        let schema = form_schemas.get(form_id.as_deref());
+        let consent_given = is_checked(consent.as_deref());
+        if schema.consent_required() && !consent_given {
+            return Err(ContactFormError::ClientError {
+                description: "Consent to the privacy notice not given".into(),
+                message_id: messages::MISSING_CONSENT,
+                language: language.into(),
+            });
+        }
+
+        let fields = schema
+            .validate(fields)
+            .map_err(|error| ContactFormError::ClientError {
+                message_id: match error {
+                    FieldError::Missing(_) => messages::MISSING_FIELDS,
+                    FieldError::Invalid(_) | FieldError::Unknown(_) => messages::INVALID_FIELD,
+                },
+                description: error.to_string(),
+                language: language.into(),
+            })?;
+
+        Ok(ValidatedContactFormMessage {
+            name: name.as_ref().map(|s| s.as_str()),
+            email,
+            subject,
+            body,
+            language,
+            friendlycaptcha_token: friendlycaptcha_token.as_deref(),
+            captcha_verified: *captcha_verified,
+            form_id: form_id.as_deref(),
+            fields,
+            consent: consent_given.then(|| {
+                schema
+                    .privacy_policy_version()
+                    .map(String::from)
+                    .unwrap_or_else(default_privacy_policy_version)
+            }),
+        })
+    }
+
+    /// Returns the names and values of the fields which are subject to the field length limit:
+    /// all but the message body, which has a soft limit, and the captcha solution and retry token,
+    /// which are verified by other means.
+    fn limited_fields(&self) -> impl Iterator<Item = (&str, Cow<'_, str>)> {
+        [
+            ("name", &self.name),
+            ("email", &self.email),
+            ("subject", &self.subject),
+            ("language", &self.language),
+            ("form-id", &self.form_id),
+            ("consent", &self.consent),
+            ("newsletter", &self.newsletter),
+            ("return-to", &self.return_to),
+            ("idempotency-key", &self.idempotency_key),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, Cow::Borrowed(value.as_deref()?))))
+        .chain(self.fields.iter().map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => Cow::Borrowed(value.as_str()),
+                value => Cow::Owned(value.to_string()),
+            };
+            (name.as_str(), value)
+        }))
+    }
+}
+
+struct ValidatedContactFormMessage<'a> {
+    name: Option<&'a str>,
+    email: &'a str,
+    subject: &'a str,
+    body: &'a str,
+    language: &'a str,
+    /// The captcha solution, which allowed senders and retries may omit.
+    friendlycaptcha_token: Option<&'a str>,
+    /// Whether the captcha was verified with an earlier submission of the same message.
+    captcha_verified: bool,
+    form_id: Option<&'a str>,
+    fields: Vec<FormField>,
+    /// The version of the privacy notice which the visitor accepted, if they did.
+    consent: Option<String>,
+}
+
+struct CachedMailer {
+    mailer: Mailer,
+    created_at: Instant,
+}
+
+#[derive(Debug)]
+enum ContactFormError {
+    InternalError {
+        description: String,
+        subject: String,
+        body: String,
+        language: String,
+        /// Whether the error occurred after the captcha was verified, so that the visitor may be
+        /// given a retry token with which to send the message again without solving it again.
+        retryable: bool,
+        alert: AlertKind,
+    },
+    ClientError {
+        description: String,
+        /// The ID of the message in the catalogue which is shown to the visitor.
+        message_id: &'static str,
+        language: String,
+    },
+    /// The request or one of its fields exceeds the [`RequestLimits`].
+    TooLarge {
+        description: String,
+        language: String,
+    },
+    /// The submission matches a block rule of the [`SenderRules`].
+    Blocked {
+        description: String,
+        language: String,
+    },
+}
+
+impl ContactFormError {
+    fn log(&self) {
+        match self {
+            ContactFormError::InternalError { description, .. } => {
+                error!("Internal error sending contact form email: {description}");
+            }
+            ContactFormError::ClientError { description, .. }
+            | ContactFormError::TooLarge { description, .. }
+            | ContactFormError::Blocked { description, .. } => {
+                error!("Client error sending contact form email: {description}");
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ContactFormError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContactFormError::InternalError { description, .. } => {
+                write!(f, "Internal error: {description}")
+            }
+            ContactFormError::ClientError { description, .. }
+            | ContactFormError::TooLarge { description, .. }
+            | ContactFormError::Blocked { description, .. } => {
+                write!(f, "Client error: {description}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContactFormError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{ContactFormMessageHandler, PeerAddress};
+    use crate::dkim::DKIM_SIGNING_KEY_NAME;
+    use crate::friendlycaptcha::FriendlyCaptchaVerifier;
+    use crate::{
+        friendlycaptcha::FRIENDLYCAPTCHA_DATA_NAME,
+        mailer::SMTP_CREDENTIALS_NAME,
+        secrets::test_support::{
+            FakeSecretRepsitory, FAKE_DKIM_PUBLIC_KEY, FAKE_DKIM_SELECTOR,
+            FAKE_FRIENDLYCAPTCHA_SECRET, FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_HEALTH_CHECK_TOKEN,
+        },
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use googletest::prelude::*;
+    use lambda_http::{
+        http::{HeaderValue, Method},
+        Body, Request,
+    };
+    use serde::Serialize;
+    use serial_test::serial;
+    use std::{collections::BTreeMap, sync::OnceLock, time::Duration};
+    use test_support::{
+        dkim::verify_dkim_signature,
+        fake_friendlycaptcha::FakeFriendlyCaptcha,
+        fake_smtp::{
+            start_poisoned_smtp_server, FakeSmtpServer, POISONED_SMTP_PORT, SMTP_PORT,
+            UNUSED_SMTP_PORT,
+        },
+        fake_webhook::FakeWebhook,
+        setup_logging, TemporaryEnv,
+    };
+    use tokio::time::timeout;
+
+    type ContactFormMessageHandlerForTesting = ContactFormMessageHandler<FakeSecretRepsitory>;
+
+    const CORRECT_CAPTCHA_SOLUTION: &str = "correct captcha solution";
+
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_captcha_solution_does_not_validate() -> Result<()> {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_captcha_solution("incorrect captcha solution")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_captcha_solution_is_missing() -> Result<()> {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_no_captcha_solution()
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_when_friendlycaptcha_fails() {
+        init().await;
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(""))))
+        );
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_captcha_solution_is_wrong_on_second_attempt() -> Result<()> {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        let event = EventPayload::arbitrary()
+            .with_captcha_solution("incorrect captcha solution")
+            .into_event();
+        subject
+            .secrets_repository
+            .remove_secret(FRIENDLYCAPTCHA_DATA_NAME);
+        subject.handle(event).await.unwrap();
+        let event = EventPayload::arbitrary()
+            .with_captcha_solution("incorrect captcha solution")
+            .into_event();
+        subject.secrets_repository.add_secret(
+            FRIENDLYCAPTCHA_DATA_NAME,
+            format!(
+                r#"{{
+                    "FRIENDLYCAPTCHA_SITEKEY": "{FAKE_FRIENDLYCAPTCHA_SITEKEY}",
+                    "FRIENDLYCAPTCHA_SECRET": "{FAKE_FRIENDLYCAPTCHA_SECRET}"
+                }}"#
+            ),
+        );
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_when_friendlycaptcha_sends_invalid_response() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .return_invalid_response();
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(""))))
+        );
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_send_mail_when_friendlycaptcha_sends_solution_timeout() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .return_solution_timeout();
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            err(anything())
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_contact_page_when_friendly_captcha_reports_bad_sitekey() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new("A different sitekey", FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                "Something went wrong"
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_contact_page_when_friendly_captcha_reports_bad_secret() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, "A different secret");
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                "Something went wrong"
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_contact_page_when_connection_to_mail_server_fails() {
+        init().await;
+        let _env = TemporaryEnv::new("SMTP_URL", "smtp://nonexistent.host.internal");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                "Something went wrong"
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_contact_page_when_smtp_fails() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                "Something went wrong"
+            ))))
+        );
+        expect_that!(
+            response.headers().get("Content-Type"),
+            some(eq("text/html; charset=utf-8"))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_again_with_retry_token_from_error_page() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        start_poisoned_smtp_server();
+        let smtp_env =
+            TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let event = EventPayload::arbitrary()
+            .with_subject("Retried subject")
+            .into_event();
+        let error_response = ContactFormMessageHandlerForTesting::new()
+            .await
+            .handle(event)
+            .await
+            .unwrap();
+        drop(smtp_env);
+        let token = retry_token_in(&error_response);
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(retry_event(&token)).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(contains_substring("Subject: Retried subject [")))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_retry_token_is_used_again() -> Result<()> {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        start_poisoned_smtp_server();
+        let smtp_env =
+            TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let error_response = subject
+            .handle(EventPayload::arbitrary().into_event())
+            .await
+            .unwrap();
+        drop(smtp_env);
+        let token = retry_token_in(&error_response);
+        subject.handle(retry_event(&token)).await.unwrap();
+
+        let response = subject.handle(retry_event(&token)).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_offer_retry_when_captcha_verification_fails() -> Result<()> {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, "A different secret");
+        fake_friendlycaptcha.start().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject
+            .handle(EventPayload::arbitrary().into_event())
+            .await
+            .unwrap();
+
+        verify_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(all!(
+                contains_substring("Something went wrong"),
+                not(contains_substring("retry-token"))
+            ))))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_retry_token_is_invalid() -> Result<()> {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject
+            .handle(retry_event("not.a-valid-token"))
+            .await
+            .unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn adds_subscriber_after_confirmation_link_is_followed() {
+        init().await;
+        let subscribers = std::env::temp_dir().join(format!("{}-subscribers", std::process::id()));
+        let _ = std::fs::remove_file(&subscribers);
+        let _env = TemporaryEnv::new("NEWSLETTER_SUBSCRIBERS_FILE", subscribers.to_string_lossy());
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let _url_env = TemporaryEnv::new(
+            "SUBSCRIPTION_CONFIRMATION_URL",
+            "https://forms.example.com/confirm-subscription",
+        );
+        let event = EventPayload::arbitrary()
+            .with_field("newsletter", "on")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        subject.handle(event).await.unwrap();
+        let confirmation = timeout(Duration::from_secs(1), fake_smtp().last_mail_content())
+            .await
+            .unwrap()
+            .unwrap();
+        // Undo the soft line breaks and escaping of the quoted-printable body.
+        let confirmation = confirmation.replace("=\r\n", "").replace("=3D", "=");
+        let (_, link) = confirmation
+            .split_once("https://forms.example.com")
+            .expect("Confirmation email should contain a link");
+        let (link, _) = link.split_once(char::is_whitespace).unwrap();
+        let mut confirmation_event = Request::new(Body::Empty);
+        *confirmation_event.method_mut() = Method::GET;
+        *confirmation_event.uri_mut() = link.parse().unwrap();
+
+        let response = subject.handle(confirmation_event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            std::fs::read_to_string(&subscribers),
+            ok(eq("email@example.com\ten\n"))
+        );
+        let _ = std::fs::remove_file(&subscribers);
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_send_confirmation_link_to_host_from_request() {
+        init().await;
+        let _env = TemporaryEnv::new("NEWSLETTER_SUBSCRIBERS_FILE", "/nonexistent/subscribers");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut event = EventPayload::arbitrary()
+            .with_field("newsletter", "on")
+            .into_event();
+        event
+            .headers_mut()
+            .append("Host", HeaderValue::from_static("attacker.example"));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(all!(
+                contains_substring("Reply-To:"),
+                not(contains_substring("attacker.example"))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_subscription_token_is_invalid() -> Result<()> {
+        init().await;
+        let _env = TemporaryEnv::new("NEWSLETTER_SUBSCRIBERS_FILE", "/nonexistent/subscribers");
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let mut event = Request::new(Body::Empty);
+        *event.method_mut() = Method::GET;
+        *event.uri_mut() = "/confirm-subscription?token=not.a-valid-token"
+            .parse()
+            .unwrap();
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_through_next_relay_when_first_relay_fails() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new(
+            "SMTP_URL",
+            format!("smtp://localhost:{POISONED_SMTP_PORT},smtp://localhost:{SMTP_PORT}"),
+        );
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_with_new_transport_when_existing_transport_fails_to_connect() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        {
+            let _env =
+                TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{UNUSED_SMTP_PORT}"));
+            subject.mailer().await.unwrap();
+        }
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn includes_submission_id_in_mail_and_success_url() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        let location = response.headers()["Location"].to_str().unwrap();
+        let (_, submission_id) = location.split_once("?submission=").unwrap();
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(all!(
+                contains_substring(format!("Message-ID: <{submission_id}@hovinen.tech>")),
+                contains_substring(format!("X-Contact-Form-Submission: {submission_id}")),
+                contains_substring(format!("Subject: Test [{submission_id}]")),
+                not(contains_substring("List-Id"))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn redirects_to_success_page_in_more_general_requested_language() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_language("de-AT")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.headers()["Location"].to_str(),
+            ok(starts_with("https://hovinen.tech/email-sent.de.html?"))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn redirects_to_success_page_in_language_from_accept_language() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut event = EventPayload::arbitrary().with_no_language().into_event();
+        event.headers_mut().append(
+            "Accept-Language",
+            HeaderValue::from_static("fr-CH, fr;q=0.9, de;q=0.8, en;q=0.7"),
+        );
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.headers()["Location"].to_str(),
+            ok(starts_with("https://hovinen.tech/email-sent.de.html?"))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_put_unsupported_language_in_success_url() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_language("fr-CA")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.headers()["Location"].to_str(),
+            ok(starts_with("https://hovinen.tech/email-sent.html?"))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_localized_400_when_language_is_malformed() {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        for language in [
+            "../evil.example.com/",
+            "de/../../../etc/passwd",
+            "de\r\nLocation: https://evil.example",
+            "de%0d%0aSet-Cookie:%20evil=1",
+            "%2e%2e%2fevil",
+        ] {
+            let mut event = EventPayload::arbitrary()
+                .with_language(language)
+                .into_event();
+            event
+                .headers_mut()
+                .append("Accept-Language", HeaderValue::from_static("de"));
+
+            let response = subject.handle(event).await.unwrap();
+
+            expect_that!(response.status().as_u16(), eq(400), "{language:?}");
+            expect_that!(response.headers().get("Location"), none(), "{language:?}");
+            expect_that!(
+                response.body(),
+                points_to(matches_pattern!(Body::Text(eq(
+                    "Die Sprache des Formulars ist ungültig."
+                )))),
+                "{language:?}"
+            );
+        }
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_url_encoded_language_decodes_to_path_traversal() -> Result<()> {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let mut event = Request::new(Body::Text(
+            "name=Arbitrary+sender&email=email%40example.com&subject=Test&body=Test+message\
+            &language=..%2F..%2Fevil.example.com%2F&frc-captcha-solution=correct+captcha+solution"
+                .into(),
+        ));
+        event.headers_mut().append(
+            "Content-Type",
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn generates_distinct_submission_ids() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let first_response = subject
+            .handle(EventPayload::arbitrary().with_body("First").into_event())
+            .await
+            .unwrap();
+        let second_response = subject
+            .handle(EventPayload::arbitrary().with_body("Second").into_event())
+            .await
+            .unwrap();
+
+        expect_that!(
+            first_response.headers()["Location"],
+            not(eq(&second_response.headers()["Location"]))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn answers_repeated_submission_with_original_response_without_sending_again() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let first_response = subject
+            .handle(EventPayload::arbitrary().into_event())
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(1), fake_smtp().last_mail_content())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let second_response = subject
+            .handle(EventPayload::arbitrary().into_event())
+            .await
+            .unwrap();
+
+        expect_that!(second_response.status().as_u16(), eq(303));
+        expect_that!(
+            second_response.headers()["Location"],
+            eq(&first_response.headers()["Location"])
+        );
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            err(anything())
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn deduplicates_submissions_by_idempotency_key_header() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let event = |body: &str| {
+            let mut event = EventPayload::arbitrary().with_body(body).into_event();
+            event
+                .headers_mut()
+                .append("Idempotency-Key", HeaderValue::from_static("abc123"));
+            event
+        };
+        let first_response = subject.handle(event("First")).await.unwrap();
+
+        let second_response = subject.handle(event("First")).await.unwrap();
+
+        expect_that!(
+            second_response.headers()["Location"],
+            eq(&first_response.headers()["Location"])
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_other_message_with_same_idempotency_key() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        fake_friendlycaptcha.start().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let event = |body: &str| {
+            let mut event = EventPayload::arbitrary().with_body(body).into_event();
+            event
+                .headers_mut()
+                .append("Idempotency-Key", HeaderValue::from_static("abc123"));
+            event
+        };
+        let first_response = subject.handle(event("First")).await.unwrap();
+
+        let second_response = subject
+            .handle(event("Another visitor's message"))
+            .await
+            .unwrap();
+
+        expect_that!(second_response.status().as_u16(), eq(303));
+        expect_that!(
+            second_response.headers()["Location"],
+            not(eq(&first_response.headers()["Location"]))
+        );
+        expect_that!(
+            fake_smtp().last_mail_content().await,
+            ok(contains_substring("Another visitor's message"))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_repeated_submission_after_first_one_failed() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET)
+                .require_solution(CORRECT_CAPTCHA_SOLUTION);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        let first_response = subject
+            .handle(
+                EventPayload::arbitrary()
+                    .with_captcha_solution("incorrect captcha solution")
+                    .into_event(),
+            )
+            .await
+            .unwrap();
+
+        let second_response = subject
+            .handle(EventPayload::arbitrary().into_event())
+            .await
+            .unwrap();
+
+        expect_that!(first_response.status().as_u16(), eq(400));
+        expect_that!(second_response.status().as_u16(), eq(303));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn includes_form_id_headers_when_form_id_is_given() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_form_id("consulting")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(all!(
+                contains_substring(
+                    "List-Id: Contact form consulting <consulting.contact-form.hovinen.tech>"
+                ),
+                contains_substring("X-Form-Id: consulting")
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_form_id_is_invalid() -> Result<()> {
+        init().await;
+        let event = EventPayload::arbitrary()
+            .with_form_id("consulting\r\nBcc: someone@example.com")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_localized_client_error() {
+        init().await;
+        let event = EventPayload::arbitrary()
+            .with_language("de")
+            .with_form_id("not a form ID")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(
+                "Das abgeschickte Formular konnte nicht zugeordnet werden."
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_json_error_when_client_accepts_json() {
+        init().await;
+        let mut event = EventPayload::arbitrary()
+            .with_form_id("not a form ID")
+            .into_event();
+        event
+            .headers_mut()
+            .append("Accept", HeaderValue::from_static("application/json"));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.headers()["Content-Type"].to_str(),
+            ok(eq("application/json"))
+        );
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(serde_json::json!({
+                "error": "client-error-invalid-form-id",
+                "message": "The form you submitted could not be identified.",
+            })
+            .to_string()))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn includes_schema_fields_in_mail() {
+        init().await;
+        let _env = form_schemas_file(CONSULTING_FORM_SCHEMA);
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_body("Message body")
+            .with_form_id("consulting")
+            .with_field("budget", "small")
+            .with_field("phone", "+49 30 1234")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(contains_substring(
+                "Phone: +49 30 1234\r\nBudget: small\r\n\r\nMessage body"
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_field_does_not_match_schema() -> Result<()> {
+        init().await;
+        let _env = form_schemas_file(CONSULTING_FORM_SCHEMA);
+        let event = EventPayload::arbitrary()
+            .with_form_id("consulting")
+            .with_field("budget", "unlimited")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_localized_413_when_request_is_too_large() {
+        init().await;
+        let _env = TemporaryEnv::new("MAX_REQUEST_BYTES", "1024");
+        let mut event = EventPayload::arbitrary()
+            .with_body("x".repeat(1024))
+            .into_event();
+        event
+            .headers_mut()
+            .append("Accept-Language", HeaderValue::from_static("de"));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(413));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(
+                "Ihre Nachricht ist zu lang. Bitte kürzen Sie sie und versuchen Sie es erneut."
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_413_when_field_is_too_long() -> Result<()> {
+        init().await;
+        let _env = TemporaryEnv::new("MAX_FIELD_LENGTH", "20");
+        let event = EventPayload::arbitrary()
+            .with_subject("A subject which is far too long")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(413))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn truncates_message_over_soft_limit_in_mail() {
+        init().await;
+        let _env = TemporaryEnv::new("MAX_MESSAGE_LENGTH", "10");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_body("0123456789abcdef")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(all!(
+                contains_substring("0123456789\r\n\r\n[Truncated: 6 more characters omitted]"),
+                not(contains_substring("abcdef"))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn redirects_to_allowed_return_url() -> Result<()> {
+        init().await;
+        let _env = form_schemas_file(SHOP_FORM_SCHEMA);
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_form_id("shop")
+            .with_field("return-to", "https://shop.example.com/orders/42")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(
+            response.headers()["Location"].to_str(),
+            ok(starts_with(
+                "https://shop.example.com/orders/42?submission="
+            ))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn shows_inline_success_page_with_submission_reference() {
+        init().await;
+        let _env = form_schemas_file(SHOP_FORM_SCHEMA);
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_form_id("shop")
+            .with_field("return-to", "https://evil.example/")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(200));
+        let mail = timeout(Duration::from_secs(1), fake_smtp().last_mail_content())
+            .await
+            .unwrap()
+            .unwrap();
+        let (_, submission_id) = mail.split_once("X-Contact-Form-Submission: ").unwrap();
+        let (submission_id, _) = submission_id.split_once("\r\n").unwrap();
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(format!(
+                "<strong>{submission_id}</strong>"
+            )))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn records_consent_in_mail_headers() {
+        init().await;
+        let _env = form_schemas_file(NEWSLETTER_FORM_SCHEMA);
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut event = EventPayload::arbitrary()
+            .with_form_id("newsletter")
+            .with_language("de")
+            .with_field("consent", "on")
+            .into_event();
+        event
+            .extensions_mut()
+            .insert(PeerAddress("192.0.2.1".parse().unwrap()));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(all!(
+                contains_substring("X-Privacy-Consent-Policy-Version: 2024-05-01"),
+                contains_substring("X-Privacy-Consent-Language: de"),
+                contains_substring("X-Privacy-Consent-Timestamp: "),
+                contains_substring("X-Privacy-Consent-IP-Hash: "),
+                not(contains_substring("192.0.2.1"))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_400_when_required_consent_is_missing() -> Result<()> {
+        init().await;
+        let _env = form_schemas_file(NEWSLETTER_FORM_SCHEMA);
+        let event = EventPayload::arbitrary()
+            .with_form_id("newsletter")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        verify_that!(response.status().as_u16(), eq(400))
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_403_and_does_not_send_mail_when_sender_is_blocked() {
+        init().await;
+        let _env = sender_rules_file(SENDER_RULES);
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut payload = EventPayload::arbitrary();
+        payload.email = "someone@mail.spam.example".into();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(payload.into_event()).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(403));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            err(anything())
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_without_captcha_when_client_ip_is_allowed() {
+        init().await;
+        let _env = sender_rules_file(r#"{"allow": {"ips": ["198.51.100.0/24"]}}"#);
+        let mut event = EventPayload::arbitrary()
+            .with_no_captcha_solution()
+            .into_event();
+        event
+            .extensions_mut()
+            .insert(PeerAddress("198.51.100.7".parse().unwrap()));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn requires_captcha_when_only_sender_address_is_allowed() {
+        init().await;
+        let _env = sender_rules_file(SENDER_RULES);
+        let mut payload = EventPayload::arbitrary().with_no_captcha_solution();
+        payload.email = "client@example.com".into();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(payload.into_event()).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(400));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            err(anything())
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn requires_captcha_when_allowed_ip_is_only_claimed_in_forwarded_header() {
+        init().await;
+        let _env = sender_rules_file(r#"{"allow": {"ips": ["198.51.100.7"]}}"#);
+        let mut event = EventPayload::arbitrary()
+            .with_no_captcha_solution()
+            .into_event();
+        event
+            .headers_mut()
+            .append("X-Forwarded-For", HeaderValue::from_static("198.51.100.7"));
+        event
+            .extensions_mut()
+            .insert(PeerAddress("192.0.2.1".parse().unwrap()));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(400));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            err(anything())
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn signs_mail_with_dkim_when_enabled() {
+        init().await;
+        let _env = TemporaryEnv::new("DKIM_SIGNING_ENABLED", "true");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        let mail = timeout(Duration::from_secs(1), fake_smtp().last_mail_content())
+            .await
+            .unwrap()
+            .unwrap();
+        expect_that!(
+            mail,
+            contains_substring(format!("s={FAKE_DKIM_SELECTOR}"))
+                .and(contains_substring("d=hovinen.tech"))
+        );
+        expect_that!(verify_dkim_signature(&mail, FAKE_DKIM_PUBLIC_KEY), ok(()));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_unsigned_mail_when_dkim_key_is_unavailable() {
+        init().await;
+        let _env = TemporaryEnv::new("DKIM_SIGNING_ENABLED", "true");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        subject
+            .secrets_repository
+            .remove_secret(DKIM_SIGNING_KEY_NAME);
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(not(contains_substring("DKIM-Signature"))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn renders_error_page_for_site_of_origin() {
+        init().await;
+        let templates = std::env::temp_dir().join(format!("site-templates-{}", std::process::id()));
+        std::fs::create_dir_all(templates.join("sites/example.com")).unwrap();
+        std::fs::write(templates.join("send-error.html"), "Default error page").unwrap();
+        std::fs::write(
+            templates.join("sites/example.com/send-error.html"),
+            "Error page for {site_root}",
+        )
+        .unwrap();
+        let _env = TemporaryEnv::new("TEMPLATES_DIR", templates.to_string_lossy());
+        start_poisoned_smtp_server();
+        let _smtp_env =
+            TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut event = EventPayload::arbitrary().into_event();
+        event
+            .headers_mut()
+            .append("Origin", HeaderValue::from_static("https://example.com"));
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+        std::fs::remove_dir_all(templates).unwrap();
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(
+                "Error page for https://example.com"
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_alert_when_smtp_fails() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let _alert_env = TemporaryEnv::new("ALERT_WEBHOOK_URL", FakeWebhook::url());
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_webhook.last_request_body()).await,
+            ok(ok(contains_substring("Error sending message")))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn does_not_send_alert_on_client_error() {
+        init().await;
+        let _alert_env = TemporaryEnv::new("ALERT_WEBHOOK_URL", FakeWebhook::url());
+        let fake_webhook = FakeWebhook::new();
+        fake_webhook.start().await;
+        let event = EventPayload::arbitrary()
+            .with_no_captcha_solution()
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(fake_webhook.request_count(), eq(0));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn send_mail_when_secrets_service_fails_for_friendlycaptcha() {
+        setup_logging();
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        subject
+            .secrets_repository
+            .remove_secret(FRIENDLYCAPTCHA_DATA_NAME);
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(303));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(eq(""))))
+        );
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_contact_page_when_secrets_service_fails_for_smtp() {
+        init().await;
+        // Credentials are only retrieved if using smtps
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtps://localhost:{SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        subject
+            .secrets_repository
+            .remove_secret(SMTP_CREDENTIALS_NAME);
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                "Something went wrong"
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn sends_mail_when_second_attempt_to_obtain_smtp_secrets_succeeds() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary().into_event();
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        {
+            // Credentials are only retrieved if using smtps
+            let _env = TemporaryEnv::new("SMTP_URL", format!("smtps://localhost:{SMTP_PORT}"));
+            let event = EventPayload::arbitrary().into_event();
+            subject
+                .secrets_repository
+                .remove_secret(SMTP_CREDENTIALS_NAME);
+            subject.handle(event).await.unwrap();
+        }
+
+        subject.handle(event).await.unwrap();
+
+        expect_that!(
+            timeout(Duration::from_secs(1), fake_smtp().last_mail_content()).await,
+            ok(ok(anything()))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn renders_message_content_and_subject_in_error_page() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let event = EventPayload::arbitrary()
+            .with_subject("Message subject")
+            .with_body("Message body")
+            .into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(
+                contains_substring("Message subject").and(contains_substring("Message body"))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn renders_error_page_labels_in_language_of_message() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        fake_friendlycaptcha.start().await;
+        let event = EventPayload::arbitrary().with_language("de").into_event();
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(event).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(500));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(
+                contains_substring(">Erneut senden<").and(contains_substring(
+                    ">Nachricht stattdessen mit Ihrem E-Mail-Programm senden<"
+                ))
+            )))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn health_check_returns_401_without_token() {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject.handle(health_check_event(None)).await.unwrap();
+
+        expect_that!(response.status().as_u16(), eq(401));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn health_check_returns_401_with_incorrect_token() {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject
+            .handle(health_check_event(Some("incorrect token")))
+            .await
+            .unwrap();
+
+        expect_that!(response.status().as_u16(), eq(401));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn health_check_reports_healthy_when_all_checks_pass() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject
+            .handle(health_check_event(Some(FAKE_HEALTH_CHECK_TOKEN)))
+            .await
+            .unwrap();
+
+        expect_that!(response.status().as_u16(), eq(200));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                r#""healthy":true"#
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn health_check_reports_missing_friendlycaptcha_secret() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let mut subject = ContactFormMessageHandlerForTesting::new().await;
+        subject.friendlycaptcha_verifier = FriendlyCaptchaVerifier::new({
+            let mut secrets_repository = subject.secrets_repository.clone();
+            secrets_repository.remove_secret(FRIENDLYCAPTCHA_DATA_NAME);
+            secrets_repository
+        });
+
+        let response = subject
+            .handle(health_check_event(Some(FAKE_HEALTH_CHECK_TOKEN)))
+            .await
+            .unwrap();
+
+        expect_that!(response.status().as_u16(), eq(503));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                r#"{"name":"friendlycaptcha-data","ok":false"#
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn health_check_reports_unreachable_smtp_server() {
+        init().await;
+        let _env = TemporaryEnv::new("SMTP_URL", "smtp://nonexistent.host.internal");
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        let response = subject
+            .handle(health_check_event(Some(FAKE_HEALTH_CHECK_TOKEN)))
+            .await
+            .unwrap();
+
+        expect_that!(response.status().as_u16(), eq(503));
+        expect_that!(
+            response.body(),
+            points_to(matches_pattern!(Body::Text(contains_substring(
+                r#"{"name":"smtp-connection","ok":false"#
+            ))))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn redirects_to_success_page_for_each_event_shape() {
+        init().await;
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        for shape in EventShape::ALL {
+            for (content_type, body) in [
+                (
+                    "application/x-www-form-urlencoded",
+                    FORM_ENCODED_MESSAGE.into(),
+                ),
+                ("application/json", EventPayload::arbitrary().into_json()),
+            ] {
+                let response = subject
+                    .handle(lambda_event(shape, content_type, &body))
+                    .await
+                    .unwrap();
+
+                expect_that!(
+                    response.status().as_u16(),
+                    eq(303),
+                    "{shape:?} event with {content_type} body"
+                );
+                expect_that!(
+                    response.headers()["Location"].to_str(),
+                    ok(starts_with(
+                        "https://hovinen.tech/email-sent.html?submission="
+                    )),
+                    "{shape:?} event with {content_type} body"
+                );
+                expect_that!(
+                    response.headers().get("Set-Cookie"),
+                    none(),
+                    "{shape:?} event with {content_type} body"
+                );
+                expect_that!(
+                    response.body(),
+                    points_to(matches_pattern!(Body::Text(eq("")))),
+                    "{shape:?} event with {content_type} body"
+                );
+            }
+        }
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_plain_text_client_error_for_each_event_shape() {
+        init().await;
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        for shape in EventShape::ALL {
+            let response = subject
+                .handle(lambda_event(
+                    shape,
+                    "application/x-www-form-urlencoded",
+                    "name=Arbitrary+sender",
+                ))
+                .await
+                .unwrap();
+
+            expect_that!(response.status().as_u16(), eq(400), "{shape:?} event");
+            expect_that!(
+                response.headers().get("Content-Type"),
+                some(eq("text/plain; charset=utf-8")),
+                "{shape:?} event"
+            );
+        }
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_error_page_for_each_event_shape() {
+        init().await;
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let fake_friendlycaptcha =
+            FakeFriendlyCaptcha::new(FAKE_FRIENDLYCAPTCHA_SITEKEY, FAKE_FRIENDLYCAPTCHA_SECRET);
+        tokio::spawn(fake_friendlycaptcha.serve());
+        let subject = ContactFormMessageHandlerForTesting::new().await;
+
+        for shape in EventShape::ALL {
+            let response = subject
+                .handle(lambda_event(
+                    shape,
+                    "application/x-www-form-urlencoded",
+                    FORM_ENCODED_MESSAGE,
+                ))
+                .await
+                .unwrap();
+
+            expect_that!(response.status().as_u16(), eq(500), "{shape:?} event");
+            expect_that!(
+                response.headers().get("Content-Type"),
+                some(eq("text/html; charset=utf-8")),
+                "{shape:?} event"
+            );
+        }
+    }
+
+    fn health_check_event(token: Option<&str>) -> Request {
+        let mut event = Request::new(Body::Empty);
+        *event.method_mut() = Method::GET;
+        *event.uri_mut() = "/health".parse().unwrap();
+        if let Some(token) = token {
+            event.headers_mut().append(
+                "Authorization",
+                HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+            );
+        }
+        event
+    }
+
+    async fn init() {
+        setup_environment();
+        fake_smtp().start();
+        fake_smtp().flush().await;
+    }
+
+    fn setup_environment() {
+        FakeSmtpServer::setup_environment();
+        FakeFriendlyCaptcha::setup_environment();
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    }
+
+    #[derive(Serialize)]
+    struct EventPayload {
+        name: String,
+        email: String,
+        subject: String,
+        body: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+        #[serde(rename = "frc-captcha-solution")]
+        solution: Option<String>,
+        #[serde(rename = "form-id", skip_serializing_if = "Option::is_none")]
+        form_id: Option<String>,
+        #[serde(flatten)]
+        fields: BTreeMap<String, String>,
+    }
+
+    impl EventPayload {
+        fn arbitrary() -> Self {
+            Self {
+                name: "Arbitrary sender".into(),
+                email: "email@example.com".into(),
+                subject: "Test".into(),
+                body: "Test message".into(),
+                language: Some("en".into()),
+                solution: Some(CORRECT_CAPTCHA_SOLUTION.into()),
+                form_id: None,
+                fields: BTreeMap::new(),
+            }
+        }
+
+        fn with_subject(self, subject: impl AsRef<str>) -> Self {
+            Self {
+                subject: subject.as_ref().into(),
+                ..self
+            }
+        }
+
+        fn with_body(self, body: impl AsRef<str>) -> Self {
+            Self {
+                body: body.as_ref().into(),
+                ..self
+            }
+        }
+
+        fn with_language(self, language: impl AsRef<str>) -> Self {
+            Self {
+                language: Some(language.as_ref().into()),
+                ..self
+            }
+        }
+
+        fn with_no_language(self) -> Self {
+            Self {
+                language: None,
+                ..self
+            }
+        }
+
+        fn with_no_captcha_solution(self) -> Self {
+            Self {
+                solution: None,
+                ..self
+            }
+        }
+
+        fn with_captcha_solution(self, solution: impl AsRef<str>) -> Self {
+            Self {
+                solution: Some(solution.as_ref().into()),
+                ..self
+            }
+        }
+
+        fn with_form_id(self, form_id: impl AsRef<str>) -> Self {
+            Self {
+                form_id: Some(form_id.as_ref().into()),
+                ..self
+            }
+        }
+
+        fn with_field(mut self, name: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+            self.fields
+                .insert(name.as_ref().into(), value.as_ref().into());
+            self
+        }
+
+        fn into_event(self) -> Request {
+            let mut event = Request::new(Body::Text(self.into_json()));
+            event
+                .headers_mut()
+                .append("Content-Type", HeaderValue::from_static("application/json"));
+            event
+        }
+
+        fn into_json(self) -> String {
+            serde_json::to_string(&self).unwrap()
+        }
+    }
+
+    const CONSULTING_FORM_SCHEMA: &str = r#"{
+        "consulting": {
+            "unknown_fields": "reject",
+            "fields": [
+                {"name": "phone", "label": "Phone", "type": "tel"},
+                {"name": "budget", "label": "Budget", "required": true, "allowed_values": ["small", "large"]}
+            ]
+        }
+    }"#;
+
+    const SHOP_FORM_SCHEMA: &str = r#"{
+        "shop": {
+            "success": {
+                "return_to": ["https://shop.example.com/orders/"],
+                "inline_page": true
+            }
+        }
+    }"#;
+
+    const NEWSLETTER_FORM_SCHEMA: &str = r#"{
+        "newsletter": {
+            "consent_required": true,
+            "privacy_policy_version": "2024-05-01"
+        }
+    }"#;
+
+    /// Writes the form schemas to a file and points `FORM_SCHEMAS_FILE` at it for as long as the
+    /// returned value lives.
+    fn form_schemas_file(content: &str) -> TemporaryEnv {
+        let path = std::env::temp_dir().join(format!("{}-form-schemas.json", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        TemporaryEnv::new("FORM_SCHEMAS_FILE", path.to_string_lossy())
+    }
+
+    const SENDER_RULES: &str = r#"{
+        "block": {"domains": ["spam.example"]},
+        "allow": {"emails": ["client@example.com"]}
+    }"#;
+
+    fn sender_rules_file(content: &str) -> TemporaryEnv {
+        let path = std::env::temp_dir().join(format!("{}-sender-rules.json", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        TemporaryEnv::new("SENDER_RULES_FILE", path.to_string_lossy())
+    }
+
+    const FORM_ENCODED_MESSAGE: &str = "name=Arbitrary+sender&email=email%40example.com\
+        &subject=Test&body=Test+message&language=en&frc-captcha-solution=correct+captcha+solution";
+
+    /// The kinds of events through which the handler can be invoked.
+    #[derive(Clone, Copy, Debug)]
+    enum EventShape {
+        ApiGatewayV1,
+        ApiGatewayV2,
+        FunctionUrl,
+        Alb,
+    }
+
+    impl EventShape {
+        const ALL: [EventShape; 4] = [
+            EventShape::ApiGatewayV1,
+            EventShape::ApiGatewayV2,
+            EventShape::FunctionUrl,
+            EventShape::Alb,
+        ];
+    }
+
+    /// Returns a POST request as the Lambda runtime would deliver it for the given kind of event,
+    /// with the body base64-encoded as Function URLs and load balancers do for form submissions.
+    fn lambda_event(shape: EventShape, content_type: &str, body: &str) -> Request {
+        let body = STANDARD.encode(body);
+        let event = match shape {
+            EventShape::ApiGatewayV1 => serde_json::json!({
+                "resource": "/contact",
+                "path": "/contact",
+                "httpMethod": "POST",
+                "multiValueHeaders": {
+                    "Content-Type": [content_type],
+                    "Host": ["api.example.com"]
+                },
+                "requestContext": {
+                    "httpMethod": "POST",
+                    "stage": "prod",
+                    "identity": {"sourceIp": "192.0.2.1"}
+                },
+                "body": body,
+                "isBase64Encoded": true
+            }),
+            EventShape::ApiGatewayV2 => serde_json::json!({
+                "version": "2.0",
+                "routeKey": "POST /contact",
+                "rawPath": "/contact",
+                "rawQueryString": "",
+                "cookies": ["visitor=returning"],
+                "headers": {
+                    "content-type": content_type,
+                    "host": "api.example.com"
+                },
+                "requestContext": {
+                    "routeKey": "POST /contact",
+                    "stage": "$default",
+                    "http": {"method": "POST", "path": "/contact", "sourceIp": "192.0.2.1"}
+                },
+                "body": body,
+                "isBase64Encoded": true
+            }),
+            EventShape::FunctionUrl => serde_json::json!({
+                "version": "2.0",
+                "routeKey": "$default",
+                "rawPath": "/",
+                "rawQueryString": "",
+                "headers": {
+                    "content-type": content_type,
+                    "host": "abcdefg.lambda-url.eu-north-1.on.aws"
+                },
+                "requestContext": {
+                    "routeKey": "$default",
+                    "stage": "$default",
+                    "domainName": "abcdefg.lambda-url.eu-north-1.on.aws",
+                    "http": {"method": "POST", "path": "/", "sourceIp": "192.0.2.1"}
+                },
+                "body": body,
+                "isBase64Encoded": true
+            }),
+            EventShape::Alb => serde_json::json!({
+                "requestContext": {
+                    "elb": {
+                        "targetGroupArn":
+                            "arn:aws:elasticloadbalancing:eu-north-1:123456789012:targetgroup/contact/0123456789abcdef"
+                    }
+                },
+                "httpMethod": "POST",
+                "path": "/",
+                "queryStringParameters": {},
+                "headers": {
+                    "content-type": content_type,
+                    "host": "contact.example.com",
+                    "x-forwarded-for": "192.0.2.1"
+                },
+                "body": body,
+                "isBase64Encoded": true
+            }),
+        };
+        lambda_http::request::from_str(&event.to_string()).unwrap()
+    }
+
+    fn retry_token_in(error_response: &lambda_http::Response<Body>) -> String {
+        let Body::Text(error_page) = error_response.body() else {
+            panic!("Error page should be text");
+        };
+        let (_, token) = error_page
+            .split_once(r#"name="retry-token" value=""#)
+            .expect("Error page should contain a retry token");
+        let (token, _) = token.split_once('"').unwrap();
+        token.into()
+    }
+
+    fn retry_event(token: &str) -> Request {
+        let mut event = Request::new(Body::Text(format!("retry-token={token}")));
+        event.headers_mut().append(
+            "Content-Type",
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        event
+    }
+
+    fn fake_smtp() -> &'static FakeSmtpServer {
+        static FAKE_SMTP: OnceLock<FakeSmtpServer> = OnceLock::new();
+        FAKE_SMTP.get_or_init(FakeSmtpServer::new)
+    }
+}
@@ -0,0 +1,158 @@
+use lambda_http::Body;
+use std::borrow::Cow;
+
+const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_FIELD_LENGTH: usize = 1000;
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 20_000;
+
+/// The limits on the size of submissions, configured by the environment variables:
+///
+///  * `MAX_REQUEST_BYTES`: the size in bytes of the request body, which is checked before the body
+///    is parsed;
+///  * `MAX_FIELD_LENGTH`: the length in characters of each field except the message body, the
+///    captcha solution and the retry token;
+///  * `MAX_MESSAGE_LENGTH`: the length in characters of the message body beyond which it is
+///    truncated in the email, with a marker saying how much was cut off.
+///
+/// Requests exceeding either of the first two limits are rejected with `413 Payload Too Large`.
+/// The last one is a soft limit, so that the visitor does not lose a long message.
+pub struct RequestLimits {
+    max_request_bytes: usize,
+    max_field_length: usize,
+    max_message_length: usize,
+}
+
+impl RequestLimits {
+    pub fn from_environment() -> Result<Self, String> {
+        Ok(Self {
+            max_request_bytes: limit_from_environment(
+                "MAX_REQUEST_BYTES",
+                DEFAULT_MAX_REQUEST_BYTES,
+            )?,
+            max_field_length: limit_from_environment("MAX_FIELD_LENGTH", DEFAULT_MAX_FIELD_LENGTH)?,
+            max_message_length: limit_from_environment(
+                "MAX_MESSAGE_LENGTH",
+                DEFAULT_MAX_MESSAGE_LENGTH,
+            )?,
+        })
+    }
+
+    pub fn max_request_bytes(&self) -> usize {
+        self.max_request_bytes
+    }
+
+    /// Checks the size of the raw request body.
+    pub fn check_request_size(&self, body: &Body) -> Result<(), String> {
+        let size = match body {
+            Body::Empty => 0,
+            Body::Text(text) => text.len(),
+            Body::Binary(bytes) => bytes.len(),
+        };
+        if size > self.max_request_bytes {
+            return Err(format!(
+                "Request body of {size} bytes exceeds the limit of {} bytes",
+                self.max_request_bytes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the length of the field with the given name.
+    pub fn check_field(&self, name: &str, value: &str) -> Result<(), String> {
+        let length = value.chars().count();
+        if length > self.max_field_length {
+            return Err(format!(
+                "Field {name} of {length} characters exceeds the limit of {} characters",
+                self.max_field_length
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the message body, cut off at the soft limit with a marker if it is longer.
+    pub fn truncate_message<'a>(&self, message: &'a str) -> Cow<'a, str> {
+        let Some((end, _)) = message.char_indices().nth(self.max_message_length) else {
+            return Cow::Borrowed(message);
+        };
+        let omitted = message[end..].chars().count();
+        Cow::Owned(format!(
+            "{}\n\n[Truncated: {omitted} more characters omitted]",
+            &message[..end]
+        ))
+    }
+}
+
+fn limit_from_environment(name: &str, default: usize) -> Result<usize, String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .ok()
+            .filter(|&limit| limit > 0)
+            .ok_or_else(|| format!("Invalid {name} {value:?}")),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestLimits;
+    use googletest::prelude::*;
+    use lambda_http::Body;
+
+    #[test]
+    fn accepts_request_within_limit() -> Result<()> {
+        verify_that!(
+            limits(10, 10, 10).check_request_size(&Body::Text("0123456789".into())),
+            ok(anything())
+        )
+    }
+
+    #[test]
+    fn rejects_request_over_limit() -> Result<()> {
+        verify_that!(
+            limits(10, 10, 10).check_request_size(&Body::Binary(vec![0; 11])),
+            err(contains_substring("11 bytes"))
+        )
+    }
+
+    #[test]
+    fn counts_field_length_in_characters() -> Result<()> {
+        verify_that!(
+            limits(100, 3, 10).check_field("name", "Jöö"),
+            ok(anything())
+        )
+    }
+
+    #[test]
+    fn rejects_field_over_limit() -> Result<()> {
+        verify_that!(
+            limits(100, 3, 10).check_field("name", "Jane"),
+            err(contains_substring("name"))
+        )
+    }
+
+    #[test]
+    fn leaves_message_within_soft_limit_intact() -> Result<()> {
+        verify_that!(limits(100, 10, 5).truncate_message("Hällo"), eq("Hällo"))
+    }
+
+    #[test]
+    fn truncates_message_over_soft_limit_with_marker() -> Result<()> {
+        verify_that!(
+            limits(100, 10, 5).truncate_message("Hällo, Welt"),
+            eq("Hällo\n\n[Truncated: 6 more characters omitted]")
+        )
+    }
+
+    fn limits(
+        max_request_bytes: usize,
+        max_field_length: usize,
+        max_message_length: usize,
+    ) -> RequestLimits {
+        RequestLimits {
+            max_request_bytes,
+            max_field_length,
+            max_message_length,
+        }
+    }
+}
@@ -0,0 +1,630 @@
+use crate::{oauth2::OAuth2Credentials, secrets::SecretRepository};
+use lambda_http::Error;
+use lettre::{
+    transport::smtp::{
+        authentication::{Credentials, Mechanism},
+        PoolConfig,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use reqwest::Url;
+use serde::{de::value::MapDeserializer, Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+use tracing::{error, info, warn};
+
+pub const SMTP_CREDENTIALS_NAME: &str = "smtp-ses-credentials";
+
+const SMTP_URL: &str = "smtps://email-smtp.eu-north-1.amazonaws.com";
+const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Credentials for a relay, stored in a secret in one of two formats:
+///
+///  * `SMTP_USERNAME` and `SMTP_PASSWORD`, presented with the `PLAIN` or `LOGIN` mechanism;
+///  * `SMTP_USERNAME` and the `OAUTH2_*` fields of [`OAuth2Credentials`], with which an access
+///    token is obtained and presented with the `XOAUTH2` mechanism, as required by Google Workspace
+///    and Microsoft 365.
+///
+/// The format is chosen by whether any `OAUTH2_*` field is present, so that a missing field is
+/// reported by name rather than as a secret which matches neither format.
+pub enum SmtpCredentials {
+    OAuth2 {
+        username: String,
+        oauth2: OAuth2Credentials,
+    },
+    Password {
+        username: String,
+        password: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct OAuth2Fields {
+    #[serde(rename = "SMTP_USERNAME")]
+    username: String,
+    #[serde(flatten)]
+    oauth2: OAuth2Credentials,
+}
+
+#[derive(Deserialize)]
+struct PasswordFields {
+    #[serde(rename = "SMTP_USERNAME")]
+    username: String,
+    #[serde(rename = "SMTP_PASSWORD")]
+    password: String,
+}
+
+impl<'de> Deserialize<'de> for SmtpCredentials {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Every field of both formats is a string.
+        let fields = BTreeMap::<String, String>::deserialize(deserializer)?;
+        let uses_oauth2 = fields.keys().any(|name| name.starts_with("OAUTH2_"));
+        let fields = MapDeserializer::new(fields.into_iter());
+        if uses_oauth2 {
+            let OAuth2Fields { username, oauth2 } = OAuth2Fields::deserialize(fields)?;
+            Ok(SmtpCredentials::OAuth2 { username, oauth2 })
+        } else {
+            let PasswordFields { username, password } = PasswordFields::deserialize(fields)?;
+            Ok(SmtpCredentials::Password { username, password })
+        }
+    }
+}
+
+struct Authentication {
+    credentials: Credentials,
+    mechanisms: Vec<Mechanism>,
+    expires_at: Option<Instant>,
+}
+
+impl SmtpCredentials {
+    /// Returns the credentials to present to the server, fetching an access token if necessary.
+    async fn authenticate(self) -> Result<Authentication, Error> {
+        match self {
+            SmtpCredentials::OAuth2 { username, oauth2 } => {
+                let access_token = oauth2.fetch_access_token().await?;
+                Ok(Authentication {
+                    credentials: Credentials::new(username, access_token.token),
+                    mechanisms: vec![Mechanism::Xoauth2],
+                    expires_at: Some(access_token.expires_at),
+                })
+            }
+            SmtpCredentials::Password { username, password } => Ok(Authentication {
+                credentials: Credentials::new(username, password),
+                mechanisms: vec![Mechanism::Plain, Mechanism::Login],
+                expires_at: None,
+            }),
+        }
+    }
+}
+
+/// Settings shared by all relays, read from the environment:
+///
+///  * `SMTP_TIMEOUT_SECS`: timeout for connecting and for each SMTP command. The transport does not
+///    distinguish between the two.
+///  * `SMTP_SEND_TIMEOUT_SECS`: overall timeout for delivering a message through one relay, after
+///    which the next relay is tried. Defaults to 30 seconds.
+///  * `SMTP_POOL_MAX_SIZE`, `SMTP_POOL_IDLE_TIMEOUT_SECS`: connection pool tuning.
+struct TransportSettings {
+    timeout: Option<Duration>,
+    send_timeout: Duration,
+    pool_config: PoolConfig,
+}
+
+impl TransportSettings {
+    fn from_environment() -> Result<Self, Error> {
+        let mut pool_config = PoolConfig::new();
+        if let Some(max_size) = parse_env("SMTP_POOL_MAX_SIZE")? {
+            pool_config = pool_config.max_size(max_size);
+        }
+        if let Some(idle_timeout) = parse_env("SMTP_POOL_IDLE_TIMEOUT_SECS")? {
+            pool_config = pool_config.idle_timeout(Duration::from_secs(idle_timeout));
+        }
+        Ok(Self {
+            timeout: parse_env("SMTP_TIMEOUT_SECS")?.map(Duration::from_secs),
+            send_timeout: parse_env("SMTP_SEND_TIMEOUT_SECS")?
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_SEND_TIMEOUT),
+            pool_config,
+        })
+    }
+}
+
+fn parse_env<T: FromStr>(key: &str) -> Result<Option<T>, Error>
+where
+    T::Err: Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|error| format!("Invalid value {value} for {key}: {error}").into()),
+        Err(_) => Ok(None),
+    }
+}
+
+/// A relay configured through the environment variable `SMTP_URL`, which holds a comma-separated
+/// list of connection URLs tried in the given order.
+///
+/// Credentials are only sent over connections which are encrypted from the start (`smtps://`) or
+/// which require STARTTLS (`smtp://...?tls=required`). By default they are read from the secret
+/// `smtp-ses-credentials`; a different secret can be named with the query parameter
+/// `credentials`, e.g. `smtps://email-smtp.eu-west-1.amazonaws.com?credentials=ses-eu-west-1`.
+#[derive(Debug)]
+pub struct RelayConfig {
+    url: String,
+    display_name: String,
+    credentials_name: Option<String>,
+}
+
+impl RelayConfig {
+    pub fn from_environment() -> Result<Vec<Self>, Error> {
+        let urls = std::env::var("SMTP_URL").unwrap_or(SMTP_URL.into());
+        let relays = urls
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|url| !url.is_empty())
+            .map(Self::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if relays.is_empty() {
+            return Err("SMTP_URL does not contain any relay".into());
+        }
+        Ok(relays)
+    }
+
+    fn parse(url: &str) -> Result<Self, Error> {
+        // Validate the URL the same way the transport will when it is built.
+        AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?;
+        let parsed = Url::parse(url)?;
+        let requires_tls = parsed.scheme() == "smtps"
+            || parsed
+                .query_pairs()
+                .any(|(key, value)| key == "tls" && value == "required");
+        let credentials_name = requires_tls.then(|| {
+            parsed
+                .query_pairs()
+                .find(|(key, _)| key == "credentials")
+                .map(|(_, value)| value.into_owned())
+                .unwrap_or(SMTP_CREDENTIALS_NAME.into())
+        });
+        let display_name = format!(
+            "{}://{}:{}",
+            parsed.scheme(),
+            parsed.host_str().unwrap_or_default(),
+            parsed
+                .port()
+                .map(|port| port.to_string())
+                .unwrap_or("default".into())
+        );
+        Ok(Self {
+            url: url.into(),
+            display_name,
+            credentials_name,
+        })
+    }
+
+    async fn build(
+        &self,
+        settings: &TransportSettings,
+        secrets_repository: &impl SecretRepository,
+    ) -> Result<Relay, Error> {
+        info!("Building SMTP transport for {}", self.display_name);
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::from_url(&self.url)?
+            .timeout(settings.timeout)
+            .pool_config(settings.pool_config.clone());
+        let mut expires_at = None;
+
+        // Sending credentials over a non-TLS connection is risky, so we only set the credentials
+        // when the connection is guaranteed to be encrypted. If the environment is misconfigured
+        // so that the credentials are not sent, the connection will be rejected. This is better
+        // than a security breach.
+        if let Some(credentials_name) = self.credentials_name.as_ref() {
+            let parsed_credentials: SmtpCredentials =
+                secrets_repository.get_secret(credentials_name).await?;
+            let authentication = parsed_credentials.authenticate().await?;
+            builder = builder
+                .credentials(authentication.credentials)
+                .authentication(authentication.mechanisms);
+            expires_at = authentication.expires_at;
+        }
+
+        Ok(Relay {
+            display_name: self.display_name.clone(),
+            transport: builder.build(),
+            expires_at,
+        })
+    }
+
+    /// Returns the names of the secrets holding credentials for the configured relays.
+    pub fn credentials_names(relays: &[Self]) -> Vec<&str> {
+        let mut names: Vec<&str> = relays
+            .iter()
+            .filter_map(|relay| relay.credentials_name.as_deref())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+}
+
+/// An ordered list of SMTP relays. A message is offered to each relay in turn until one accepts
+/// it.
+#[derive(Clone)]
+pub struct Mailer {
+    relays: Vec<Relay>,
+    send_timeout: Duration,
+}
+
+#[derive(Clone)]
+struct Relay {
+    display_name: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    /// When the access token presented to the relay expires, if it authenticates with one.
+    expires_at: Option<Instant>,
+}
+
+impl Mailer {
+    /// Builds transports for all relays configured in the environment.
+    ///
+    /// A relay whose transport cannot be built, e.g. because its credentials cannot be retrieved,
+    /// is skipped so that the remaining relays can still be used. This fails only if no relay can
+    /// be built.
+    pub async fn build(secrets_repository: &impl SecretRepository) -> Result<Self, Error> {
+        let settings = TransportSettings::from_environment()?;
+        let mut relays = vec![];
+        let mut last_error = None;
+        for config in RelayConfig::from_environment()? {
+            match config.build(&settings, secrets_repository).await {
+                Ok(relay) => relays.push(relay),
+                Err(error) => {
+                    error!(
+                        "Unable to set up SMTP relay {}: {error}",
+                        config.display_name
+                    );
+                    last_error = Some(error);
+                }
+            }
+        }
+        match (relays.is_empty(), last_error) {
+            (true, Some(error)) => Err(error),
+            _ => Ok(Self {
+                relays,
+                send_timeout: settings.send_timeout,
+            }),
+        }
+    }
+
+    /// Returns whether an access token used by one of the relays has expired, in which case the
+    /// mailer must be rebuilt.
+    pub fn is_expired(&self) -> bool {
+        let now = Instant::now();
+        self.relays
+            .iter()
+            .any(|relay| relay.expires_at.is_some_and(|expires_at| expires_at <= now))
+    }
+
+    pub async fn send(&self, email: &Message) -> Result<(), SendError> {
+        let mut errors = vec![];
+        for relay in &self.relays {
+            match timeout(self.send_timeout, relay.transport.send(email.clone())).await {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(error)) => {
+                    errors.push((relay.display_name.clone(), RelayError::Smtp(error)))
+                }
+                Err(_) => errors.push((
+                    relay.display_name.clone(),
+                    RelayError::Timeout(self.send_timeout),
+                )),
+            }
+            if errors.len() < self.relays.len() {
+                warn!(
+                    "Sending through {} failed, trying next relay",
+                    relay.display_name
+                );
+            }
+        }
+        Err(SendError(errors))
+    }
+
+    /// Checks that every relay accepts a connection and the credentials.
+    pub async fn test_connections(&self) -> Result<(), Error> {
+        let mut failures = vec![];
+        for relay in &self.relays {
+            match relay.transport.test_connection().await {
+                Ok(true) => {}
+                Ok(false) => failures.push(format!(
+                    "{}: did not accept the connection",
+                    relay.display_name
+                )),
+                Err(error) => failures.push(format!("{}: {error}", relay.display_name)),
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failures.join("; ").into())
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RelayError {
+    Smtp(lettre::transport::smtp::Error),
+    Timeout(Duration),
+}
+
+impl RelayError {
+    /// Returns whether the SMTP server rejected the credentials (reply code 535).
+    fn is_authentication_failure(&self) -> bool {
+        match self {
+            RelayError::Smtp(error) => {
+                error.is_permanent() && error.status().map(u16::from) == Some(535)
+            }
+            RelayError::Timeout(_) => false,
+        }
+    }
+
+    /// Returns whether the connection itself failed rather than the server refusing the message,
+    /// e.g. because a pooled connection was closed by the server or the network is unreachable.
+    fn is_connection_failure(&self) -> bool {
+        match self {
+            RelayError::Smtp(error) => error.status().is_none() && !error.is_client(),
+            RelayError::Timeout(_) => false,
+        }
+    }
+}
+
+impl Display for RelayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RelayError::Smtp(error) => write!(f, "{error}"),
+            RelayError::Timeout(duration) => write!(f, "timed out after {duration:?}"),
+        }
+    }
+}
+
+/// The errors from each relay through which sending a message was attempted.
+#[derive(Debug)]
+pub struct SendError(Vec<(String, RelayError)>);
+
+impl SendError {
+    /// Returns whether sending may succeed with a freshly built transport, i.e. whether some relay
+    /// rejected the credentials or failed at the connection level.
+    ///
+    /// This is never the case if a relay timed out, since trying all relays again could keep the
+    /// visitor waiting for up to twice the send timeout per relay.
+    pub fn requires_new_transport(&self) -> bool {
+        let timed_out = self
+            .0
+            .iter()
+            .any(|(_, error)| matches!(error, RelayError::Timeout(_)));
+        !timed_out
+            && self.0.iter().any(|(_, error)| {
+                error.is_authentication_failure() || error.is_connection_failure()
+            })
+    }
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self
+            .0
+            .iter()
+            .map(|(relay, error)| format!("{relay}: {error}"))
+            .collect();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mailer, RelayConfig, RelayError, SendError, SmtpCredentials};
+    use crate::secrets::{test_support::FakeSecretRepsitory, SecretRepository};
+    use googletest::prelude::*;
+    use lettre::{
+        transport::smtp::authentication::{Credentials, Mechanism},
+        Message,
+    };
+    use serial_test::serial;
+    use std::time::Duration;
+    use test_support::{
+        fake_oauth2::{FakeOAuth2Server, FAKE_ACCESS_TOKEN},
+        fake_smtp::{start_poisoned_smtp_server, POISONED_SMTP_PORT, UNUSED_SMTP_PORT},
+        TemporaryEnv,
+    };
+
+    #[googletest::test]
+    #[serial]
+    fn parses_relays_in_order() {
+        let _env = TemporaryEnv::new(
+            "SMTP_URL",
+            "smtps://primary.example.com, smtps://secondary.example.com:2465",
+        );
+
+        let relays = RelayConfig::from_environment();
+
+        expect_that!(
+            relays,
+            ok(elements_are![
+                field!(
+                    RelayConfig.display_name,
+                    eq("smtps://primary.example.com:default")
+                ),
+                field!(
+                    RelayConfig.display_name,
+                    eq("smtps://secondary.example.com:2465")
+                ),
+            ])
+        );
+    }
+
+    #[googletest::test]
+    #[serial]
+    fn sends_credentials_with_required_starttls() {
+        let _env = TemporaryEnv::new("SMTP_URL", "smtp://relay.example.com:587?tls=required");
+
+        let relays = RelayConfig::from_environment();
+
+        expect_that!(
+            relays,
+            ok(elements_are![field!(
+                RelayConfig.credentials_name,
+                some(eq("smtp-ses-credentials"))
+            )])
+        );
+    }
+
+    #[googletest::test]
+    #[serial]
+    fn does_not_send_credentials_with_opportunistic_starttls() {
+        let _env = TemporaryEnv::new("SMTP_URL", "smtp://relay.example.com:587?tls=opportunistic");
+
+        let relays = RelayConfig::from_environment();
+
+        expect_that!(
+            relays,
+            ok(elements_are![field!(RelayConfig.credentials_name, none())])
+        );
+    }
+
+    #[googletest::test]
+    #[serial]
+    fn reads_credentials_from_named_secret() {
+        let _env = TemporaryEnv::new(
+            "SMTP_URL",
+            "smtps://email-smtp.eu-west-1.amazonaws.com?credentials=ses-eu-west-1",
+        );
+
+        let relays = RelayConfig::from_environment();
+
+        expect_that!(
+            relays,
+            ok(elements_are![field!(
+                RelayConfig.credentials_name,
+                some(eq("ses-eu-west-1"))
+            )])
+        );
+    }
+
+    #[googletest::test]
+    #[serial]
+    fn rejects_invalid_url() {
+        let _env = TemporaryEnv::new("SMTP_URL", "ftp://relay.example.com");
+
+        let relays = RelayConfig::from_environment();
+
+        expect_that!(relays, err(anything()));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn connection_failure_requires_new_transport() {
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{UNUSED_SMTP_PORT}"));
+        let subject = Mailer::build(&FakeSecretRepsitory::open().await)
+            .await
+            .unwrap();
+
+        let result = subject.send(&arbitrary_message()).await;
+
+        expect_that!(
+            result,
+            err(predicate(|e: &super::SendError| e.requires_new_transport()))
+        );
+    }
+
+    #[googletest::test]
+    fn timeout_does_not_require_new_transport() {
+        let error = SendError(vec![(
+            "relay.example.com".into(),
+            RelayError::Timeout(Duration::from_secs(10)),
+        )]);
+
+        expect_that!(error.requires_new_transport(), eq(false));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn rejected_message_does_not_require_new_transport() {
+        start_poisoned_smtp_server();
+        let _env = TemporaryEnv::new("SMTP_URL", format!("smtp://localhost:{POISONED_SMTP_PORT}"));
+        let subject = Mailer::build(&FakeSecretRepsitory::open().await)
+            .await
+            .unwrap();
+
+        let result = subject.send(&arbitrary_message()).await;
+
+        expect_that!(
+            result,
+            err(predicate(|e: &super::SendError| !e.requires_new_transport()))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn authenticates_with_password() {
+        let credentials: SmtpCredentials =
+            serde_json::from_str(r#"{"SMTP_USERNAME": "username", "SMTP_PASSWORD": "password"}"#)
+                .unwrap();
+
+        let authentication = credentials.authenticate().await.unwrap();
+
+        expect_that!(
+            authentication.credentials,
+            eq(Credentials::new("username".into(), "password".into()))
+        );
+        expect_that!(
+            authentication.mechanisms,
+            elements_are![eq(Mechanism::Plain), eq(Mechanism::Login)]
+        );
+        expect_that!(authentication.expires_at, none());
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn authenticates_with_oauth2_access_token() {
+        FakeOAuth2Server::new("refresh token").start().await;
+        let credentials: SmtpCredentials = serde_json::from_str(&format!(
+            r#"{{
+                "SMTP_USERNAME": "username",
+                "OAUTH2_TOKEN_URL": "{}",
+                "OAUTH2_CLIENT_ID": "client id",
+                "OAUTH2_CLIENT_SECRET": "client secret",
+                "OAUTH2_REFRESH_TOKEN": "refresh token"
+            }}"#,
+            FakeOAuth2Server::token_url()
+        ))
+        .unwrap();
+
+        let authentication = credentials.authenticate().await.unwrap();
+
+        expect_that!(
+            authentication.credentials,
+            eq(Credentials::new(
+                "username".into(),
+                FAKE_ACCESS_TOKEN.into()
+            ))
+        );
+        expect_that!(
+            authentication.mechanisms,
+            elements_are![eq(Mechanism::Xoauth2)]
+        );
+        expect_that!(authentication.expires_at, some(anything()));
+    }
+
+    fn arbitrary_message() -> Message {
+        Message::builder()
+            .from("sender@example.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .subject("Subject")
+            .body(String::from("Body"))
+            .unwrap()
+    }
+}
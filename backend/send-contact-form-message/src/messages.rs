@@ -0,0 +1,205 @@
+use crate::{error_page::DEFAULT_LANGUAGE, language::fallback_chain};
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource};
+use lambda_http::Error;
+use std::collections::HashMap;
+use tracing::error;
+use unic_langid::LanguageIdentifier;
+
+pub const INTERNAL_ERROR: &str = "internal-error";
+pub const MISSING_FIELDS: &str = "client-error-missing-fields";
+pub const INVALID_EMAIL: &str = "client-error-invalid-email";
+pub const INVALID_FIELD: &str = "client-error-invalid-field";
+pub const INVALID_LANGUAGE: &str = "client-error-invalid-language";
+pub const INVALID_FORM_ID: &str = "client-error-invalid-form-id";
+pub const REQUEST_TOO_LARGE: &str = "client-error-request-too-large";
+pub const CAPTCHA_INVALID: &str = "client-error-captcha-invalid";
+pub const CAPTCHA_EXPIRED: &str = "client-error-captcha-expired";
+pub const RETRY_TOKEN_INVALID: &str = "client-error-retry-token-invalid";
+pub const MISSING_CONSENT: &str = "client-error-missing-consent";
+pub const SENDER_BLOCKED: &str = "client-error-sender-blocked";
+pub const SUBMISSION_IN_PROGRESS: &str = "client-error-submission-in-progress";
+pub const SUBSCRIPTION_TOKEN_INVALID: &str = "client-error-subscription-token-invalid";
+pub const SUBSCRIPTION_CONFIRMATION_SUBJECT: &str = "subscription-confirmation-subject";
+/// The body of the email asking the visitor to confirm their subscription, with the variable
+/// `link`.
+pub const SUBSCRIPTION_CONFIRMATION_BODY: &str = "subscription-confirmation-body";
+pub const ERROR_PAGE_RETRY_BUTTON: &str = "error-page-retry-button";
+pub const ERROR_PAGE_MAILTO_LINK: &str = "error-page-mailto-link";
+
+/// The IDs of all messages which the handler shows to visitors. Each shipped catalogue must define
+/// all of them.
+#[cfg(test)]
+const MESSAGE_IDS: [&str; 18] = [
+    INTERNAL_ERROR,
+    MISSING_FIELDS,
+    INVALID_EMAIL,
+    INVALID_FIELD,
+    INVALID_LANGUAGE,
+    INVALID_FORM_ID,
+    REQUEST_TOO_LARGE,
+    CAPTCHA_INVALID,
+    CAPTCHA_EXPIRED,
+    RETRY_TOKEN_INVALID,
+    MISSING_CONSENT,
+    SENDER_BLOCKED,
+    SUBMISSION_IN_PROGRESS,
+    SUBSCRIPTION_TOKEN_INVALID,
+    SUBSCRIPTION_CONFIRMATION_SUBJECT,
+    SUBSCRIPTION_CONFIRMATION_BODY,
+    ERROR_PAGE_RETRY_BUTTON,
+    ERROR_PAGE_MAILTO_LINK,
+];
+
+const BUNDLED_CATALOGUES: [(&str, &str); 2] = [
+    (
+        "en",
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/locales/en.ftl"
+        )),
+    ),
+    (
+        "de",
+        include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/locales/de.ftl"
+        )),
+    ),
+];
+
+/// The Fluent message catalogue with the user-facing text in each language, bundled with the
+/// binary from `assets/locales/<language>.ftl`.
+pub struct Messages {
+    bundles: HashMap<String, FluentBundle<FluentResource>>,
+}
+
+impl Messages {
+    pub fn bundled() -> Result<Self, Error> {
+        Self::from_sources(&BUNDLED_CATALOGUES)
+    }
+
+    fn from_sources(sources: &[(&str, &str)]) -> Result<Self, Error> {
+        let mut bundles = HashMap::new();
+        for (language, source) in sources {
+            let identifier: LanguageIdentifier = language
+                .parse()
+                .map_err(|error| format!("Invalid language {language}: {error}"))?;
+            let resource = FluentResource::try_new(source.to_string())
+                .map_err(|(_, errors)| format!("Invalid catalogue {language}.ftl: {errors:?}"))?;
+            let mut bundle = FluentBundle::new_concurrent(vec![identifier]);
+            // Unicode isolation marks would end up verbatim in plain text and JSON responses.
+            bundle.set_use_isolating(false);
+            bundle
+                .add_resource(resource)
+                .map_err(|errors| format!("Invalid catalogue {language}.ftl: {errors:?}"))?;
+            bundles.insert(language.to_string(), bundle);
+        }
+        Ok(Self { bundles })
+    }
+
+    /// Returns the message with the given ID in the given language, falling back to its more
+    /// general forms and then to the default language. Returns the ID itself if no catalogue
+    /// defines the message.
+    pub fn get(&self, language: &str, id: &str) -> String {
+        self.get_with_args(language, id, &[])
+    }
+
+    /// Returns the message as [`Messages::get`] does, with the given values for its variables.
+    pub fn get_with_args(&self, language: &str, id: &str, args: &[(&str, &str)]) -> String {
+        let message = fallback_chain(language)
+            .chain([DEFAULT_LANGUAGE])
+            .filter_map(|language| self.bundles.get(language))
+            .find_map(|bundle| Some((bundle, bundle.get_message(id)?.value()?)));
+        let Some((bundle, pattern)) = message else {
+            error!("Message {id} is missing from the catalogue");
+            return id.into();
+        };
+        let args: FluentArgs = args.iter().map(|(name, value)| (*name, *value)).collect();
+        let mut errors = vec![];
+        let text = bundle.format_pattern(pattern, Some(&args), &mut errors);
+        if !errors.is_empty() {
+            error!("Errors formatting message {id}: {errors:?}");
+        }
+        text.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Messages, BUNDLED_CATALOGUES, MESSAGE_IDS, MISSING_FIELDS};
+    use fluent_bundle::FluentResource;
+    use fluent_syntax::ast::Entry;
+    use googletest::prelude::*;
+    use std::collections::BTreeSet;
+
+    #[googletest::test]
+    fn shipped_catalogues_define_all_messages() {
+        let defined: Vec<(&str, BTreeSet<String>)> = BUNDLED_CATALOGUES
+            .iter()
+            .map(|(language, source)| {
+                let resource = FluentResource::try_new(source.to_string()).unwrap();
+                let ids = resource
+                    .entries()
+                    .filter_map(|entry| match entry {
+                        Entry::Message(message) => Some(message.id.name.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                (*language, ids)
+            })
+            .collect();
+        let all_ids: BTreeSet<String> = MESSAGE_IDS
+            .iter()
+            .map(|id| id.to_string())
+            .chain(defined.iter().flat_map(|(_, ids)| ids.iter().cloned()))
+            .collect();
+
+        for (language, ids) in &defined {
+            for id in &all_ids {
+                expect_that!(
+                    ids.contains(id),
+                    eq(true),
+                    "Message {id} is missing from {language}.ftl"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn returns_message_in_requested_language() -> Result<()> {
+        let subject = Messages::bundled().unwrap();
+
+        verify_that!(
+            subject.get("de", MISSING_FIELDS),
+            eq("Einige Pflichtfelder des Formulars fehlen.")
+        )
+    }
+
+    #[test]
+    fn falls_back_to_default_language() -> Result<()> {
+        let subject = Messages::from_sources(&[
+            ("en", "greeting = Hello\nfarewell = Goodbye"),
+            ("de", "greeting = Hallo"),
+        ])
+        .unwrap();
+
+        verify_that!(subject.get("de-AT", "farewell"), eq("Goodbye"))
+    }
+
+    #[test]
+    fn substitutes_variables() -> Result<()> {
+        let subject = Messages::from_sources(&[("en", "greeting = Hello, { $name }!")]).unwrap();
+
+        verify_that!(
+            subject.get_with_args("en", "greeting", &[("name", "visitor")]),
+            eq("Hello, visitor!")
+        )
+    }
+
+    #[test]
+    fn returns_id_when_message_is_missing() -> Result<()> {
+        let subject = Messages::from_sources(&[("en", "greeting = Hello")]).unwrap();
+
+        verify_that!(subject.get("en", "farewell"), eq("farewell"))
+    }
+}
@@ -0,0 +1,167 @@
+use lambda_http::Request;
+use percent_encoding::percent_decode_str;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::PathBuf, time::Duration};
+
+/// The path, relative to the handler, of the endpoint which visitors reach through the link in
+/// the confirmation email.
+pub const CONFIRM_SUBSCRIPTION_PATH: &str = "/confirm-subscription";
+
+const DEFAULT_SUBSCRIPTION_TOKEN_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Returns how long the link in the confirmation email stays valid, as configured by the
+/// environment variable `SUBSCRIPTION_TOKEN_LIFETIME_SECS`.
+pub fn subscription_token_lifetime() -> Duration {
+    std::env::var("SUBSCRIPTION_TOKEN_LIFETIME_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SUBSCRIPTION_TOKEN_LIFETIME)
+}
+
+/// Returns the URL of the confirmation endpoint, as configured by the environment variable
+/// `SUBSCRIPTION_CONFIRMATION_URL`.
+///
+/// The URL is not derived from the request, since its `Host` header is under the control of the
+/// client, who could otherwise have the signed confirmation token sent to a host of their choice.
+pub fn confirmation_url() -> Option<String> {
+    std::env::var("SUBSCRIPTION_CONFIRMATION_URL").ok()
+}
+
+/// Returns the token from the query of a request to the confirmation endpoint.
+pub fn confirmation_token(event: &Request) -> Option<String> {
+    event.uri().query()?.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        (name == "token").then(|| percent_decode_str(value).decode_utf8_lossy().into_owned())
+    })
+}
+
+/// A visitor who asked to receive the newsletter. It is the payload of the token in the
+/// confirmation link and what is handed to the subscriber list once the visitor confirms.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Subscriber {
+    pub email: String,
+    pub language: String,
+}
+
+// The trait is only implemented within this package, where the concrete futures are known.
+#[allow(async_fn_in_trait)]
+pub trait SubscriberList {
+    /// Adds the confirmed subscriber to the list.
+    async fn add(&self, subscriber: &Subscriber) -> Result<(), lambda_http::Error>;
+}
+
+/// The subscriber list selected by the environment:
+///
+///  * `NEWSLETTER_WEBHOOK_URL`: [`WebhookSubscriberList`];
+///  * `NEWSLETTER_SUBSCRIBERS_FILE`: [`FileSubscriberList`].
+///
+/// If neither is set, the newsletter is disabled: the opt-in field is ignored and the
+/// confirmation endpoint does not exist.
+pub enum ConfiguredSubscriberList {
+    Webhook(WebhookSubscriberList),
+    File(FileSubscriberList),
+}
+
+impl ConfiguredSubscriberList {
+    pub fn from_environment() -> Option<Self> {
+        if let Ok(url) = std::env::var("NEWSLETTER_WEBHOOK_URL") {
+            Some(Self::Webhook(WebhookSubscriberList {
+                client: Client::new(),
+                url,
+            }))
+        } else if let Ok(path) = std::env::var("NEWSLETTER_SUBSCRIBERS_FILE") {
+            Some(Self::File(FileSubscriberList { path: path.into() }))
+        } else {
+            None
+        }
+    }
+}
+
+impl SubscriberList for ConfiguredSubscriberList {
+    async fn add(&self, subscriber: &Subscriber) -> Result<(), lambda_http::Error> {
+        match self {
+            Self::Webhook(list) => list.add(subscriber).await,
+            Self::File(list) => list.add(subscriber).await,
+        }
+    }
+}
+
+/// Posts each subscriber as a JSON document `{"email": ..., "language": ...}` to a URL, such as
+/// the subscription API of a mailing list service.
+pub struct WebhookSubscriberList {
+    client: Client,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    email: &'a str,
+    language: &'a str,
+}
+
+impl SubscriberList for WebhookSubscriberList {
+    async fn add(&self, subscriber: &Subscriber) -> Result<(), lambda_http::Error> {
+        self.client
+            .post(&self.url)
+            .json(&WebhookPayload {
+                email: &subscriber.email,
+                language: &subscriber.language,
+            })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Appends each subscriber as a line of the form `<email>\t<language>` to a local file. Intended
+/// for tests and local development.
+pub struct FileSubscriberList {
+    path: PathBuf,
+}
+
+impl SubscriberList for FileSubscriberList {
+    async fn add(&self, subscriber: &Subscriber) -> Result<(), lambda_http::Error> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}\t{}", subscriber.email, subscriber.language)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileSubscriberList, Subscriber, SubscriberList};
+    use googletest::prelude::*;
+
+    #[tokio::test]
+    async fn file_subscriber_list_appends_subscribers() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("{}-subscribers.tsv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let subject = FileSubscriberList { path: path.clone() };
+
+        subject
+            .add(&subscriber("one@example.com", "en"))
+            .await
+            .unwrap();
+        subject
+            .add(&subscriber("two@example.com", "de"))
+            .await
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        verify_that!(content, eq("one@example.com\ten\ntwo@example.com\tde\n"))
+    }
+
+    fn subscriber(email: &str, language: &str) -> Subscriber {
+        Subscriber {
+            email: email.into(),
+            language: language.into(),
+        }
+    }
+}
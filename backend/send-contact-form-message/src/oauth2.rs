@@ -0,0 +1,134 @@
+use lambda_http::Error;
+use reqwest::Client;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Access tokens are refreshed this long before they expire, so that a token does not expire
+/// while a message is being sent.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Client credentials and refresh token for obtaining access tokens from an OAuth2 provider such
+/// as Google Workspace (`https://oauth2.googleapis.com/token`) or Microsoft 365
+/// (`https://login.microsoftonline.com/<tenant>/oauth2/v2.0/token`).
+#[derive(Deserialize)]
+pub struct OAuth2Credentials {
+    #[serde(rename = "OAUTH2_TOKEN_URL")]
+    token_url: String,
+    #[serde(rename = "OAUTH2_CLIENT_ID")]
+    client_id: String,
+    #[serde(rename = "OAUTH2_CLIENT_SECRET")]
+    client_secret: String,
+    #[serde(rename = "OAUTH2_REFRESH_TOKEN")]
+    refresh_token: String,
+    #[serde(rename = "OAUTH2_SCOPE", default)]
+    scope: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AccessToken {
+    pub token: String,
+    /// The time after which the token should no longer be used.
+    pub expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl OAuth2Credentials {
+    /// Exchanges the refresh token for a new access token.
+    pub async fn fetch_access_token(&self) -> Result<AccessToken, Error> {
+        info!("Fetching OAuth2 access token from {}", self.token_url);
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.as_str()),
+            ("refresh_token", self.refresh_token.as_str()),
+        ];
+        if let Some(scope) = self.scope.as_deref() {
+            form.push(("scope", scope));
+        }
+        let requested_at = Instant::now();
+        let response = Client::new()
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(format!(
+                "OAuth2 token endpoint returned {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )
+            .into());
+        }
+        let response: TokenResponse = response.json().await?;
+        Ok(AccessToken {
+            token: response.access_token,
+            expires_at: requested_at
+                + Duration::from_secs(response.expires_in).saturating_sub(EXPIRY_MARGIN),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessToken, OAuth2Credentials};
+    use googletest::prelude::*;
+    use serial_test::serial;
+    use std::time::{Duration, Instant};
+    use test_support::fake_oauth2::{
+        FakeOAuth2Server, FAKE_ACCESS_TOKEN, FAKE_ACCESS_TOKEN_LIFETIME_SECS,
+    };
+
+    const REFRESH_TOKEN: &str = "refresh token";
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn fetches_access_token_with_refresh_token() {
+        FakeOAuth2Server::new(REFRESH_TOKEN).start().await;
+        let subject = credentials(REFRESH_TOKEN);
+
+        let access_token = subject.fetch_access_token().await;
+
+        expect_that!(
+            access_token,
+            ok(all!(
+                field!(AccessToken.token, eq(FAKE_ACCESS_TOKEN)),
+                field!(
+                    AccessToken.expires_at,
+                    lt(Instant::now() + Duration::from_secs(FAKE_ACCESS_TOKEN_LIFETIME_SECS))
+                )
+            ))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn returns_error_when_refresh_token_is_rejected() {
+        FakeOAuth2Server::new(REFRESH_TOKEN).start().await;
+        let subject = credentials("revoked refresh token");
+
+        let access_token = subject.fetch_access_token().await;
+
+        expect_that!(
+            access_token.map(|token| token.token),
+            err(displays_as(contains_substring("invalid_grant")))
+        );
+    }
+
+    fn credentials(refresh_token: &str) -> OAuth2Credentials {
+        OAuth2Credentials {
+            token_url: FakeOAuth2Server::token_url(),
+            client_id: "client id".into(),
+            client_secret: "client secret".into(),
+            refresh_token: refresh_token.into(),
+            scope: None,
+        }
+    }
+}
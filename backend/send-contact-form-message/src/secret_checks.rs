@@ -0,0 +1,171 @@
+use crate::{
+    consent::{IpHashKey, IP_HASH_KEY_NAME},
+    dkim::{dkim_signing_enabled, DkimSigningKeySecret, DKIM_SIGNING_KEY_NAME},
+    friendlycaptcha::{FriendlyCaptchaData, FRIENDLYCAPTCHA_DATA_NAME},
+    health_check::{HealthCheckToken, HEALTH_CHECK_TOKEN_NAME},
+    mailer::{RelayConfig, SmtpCredentials},
+    secrets::{parse_secret, EnvironmentError, SecretRepository},
+    token::{TokenKey, TOKEN_KEY_NAME},
+};
+use lambda_http::Error;
+use serde::de::DeserializeOwned;
+use tracing::{error, info, warn};
+
+/// A secret which the handler uses, with a check that its value has the expected form.
+pub struct SecretCheck {
+    pub name: String,
+    /// Whether the handler cannot work without the secret.
+    pub required: bool,
+    pub parse: fn(&str, &str) -> Result<(), EnvironmentError>,
+}
+
+impl SecretCheck {
+    /// Retrieves the secret from the repository and checks its form.
+    pub async fn run(&self, secrets: &impl SecretRepository) -> Result<(), EnvironmentError> {
+        let value = secrets
+            .get_secret_string(&self.name)
+            .await
+            .map_err(|error| EnvironmentError::classify(&self.name, error))?;
+        (self.parse)(&self.name, &value)
+    }
+}
+
+fn parses_as<T: DeserializeOwned>(name: &str, value: &str) -> Result<(), EnvironmentError> {
+    parse_secret::<T>(name, value).map(|_| ())
+}
+
+/// Returns the secrets which the handler uses in the current configuration.
+pub fn secret_checks() -> Result<Vec<SecretCheck>, Error> {
+    let relays = RelayConfig::from_environment()?;
+    let mut checks: Vec<_> = RelayConfig::credentials_names(&relays)
+        .into_iter()
+        .map(|name| SecretCheck {
+            name: name.into(),
+            required: true,
+            parse: parses_as::<SmtpCredentials>,
+        })
+        .collect();
+    checks.push(SecretCheck {
+        name: FRIENDLYCAPTCHA_DATA_NAME.into(),
+        required: true,
+        parse: parses_as::<FriendlyCaptchaData>,
+    });
+    checks.push(SecretCheck {
+        name: DKIM_SIGNING_KEY_NAME.into(),
+        required: dkim_signing_enabled(),
+        parse: parses_as::<DkimSigningKeySecret>,
+    });
+    for (name, parse) in [
+        (TOKEN_KEY_NAME, parses_as::<TokenKey> as fn(&str, &str) -> _),
+        (HEALTH_CHECK_TOKEN_NAME, parses_as::<HealthCheckToken>),
+        (IP_HASH_KEY_NAME, parses_as::<IpHashKey>),
+    ] {
+        checks.push(SecretCheck {
+            name: name.into(),
+            required: false,
+            parse,
+        });
+    }
+    Ok(checks)
+}
+
+/// Fetches and checks every secret which the handler uses, logging one line per secret, and
+/// returns the number of required secrets which are unusable.
+///
+/// Fetching through a caching repository also warms its cache for the first submission.
+pub async fn prefetch_secrets(secrets: &impl SecretRepository) -> usize {
+    let checks = match secret_checks() {
+        Ok(checks) => checks,
+        Err(error) => {
+            error!("Unable to determine the secrets to check: {error}");
+            return 0;
+        }
+    };
+    let mut failures = 0;
+    for check in checks {
+        match check.run(secrets).await {
+            Ok(()) => info!("Secret {} is available", check.name),
+            Err(error) if check.required => {
+                failures += 1;
+                error!("Required secret {} is unusable: {error}", check.name);
+            }
+            Err(error) => warn!("Optional secret {} is unusable: {error}", check.name),
+        }
+    }
+    failures
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parses_as, prefetch_secrets};
+    use crate::{
+        friendlycaptcha::{FriendlyCaptchaData, FRIENDLYCAPTCHA_DATA_NAME},
+        mailer::{SmtpCredentials, SMTP_CREDENTIALS_NAME},
+        secrets::{test_support::FakeSecretRepsitory, EnvironmentError, SecretRepository},
+        token::TOKEN_KEY_NAME,
+    };
+    use googletest::prelude::*;
+    use serial_test::serial;
+
+    #[googletest::test]
+    fn checks_form_of_required_secrets() {
+        expect_that!(
+            parses_as::<SmtpCredentials>(
+                SMTP_CREDENTIALS_NAME,
+                r#"{"SMTP_USERNAME": "user", "SMTP_PASSWORD": "password"}"#
+            ),
+            ok(anything())
+        );
+        expect_that!(
+            parses_as::<SmtpCredentials>(SMTP_CREDENTIALS_NAME, r#"{"SMTP_USERNAME": "user"}"#),
+            err(matches_pattern!(EnvironmentError::SchemaMismatch {
+                name: eq(SMTP_CREDENTIALS_NAME),
+                field: some(eq("SMTP_PASSWORD")),
+                detail: eq("missing field"),
+            }))
+        );
+        expect_that!(
+            parses_as::<SmtpCredentials>(
+                SMTP_CREDENTIALS_NAME,
+                r#"{"SMTP_USERNAME": "user", "OAUTH2_CLIENT_ID": "client"}"#
+            ),
+            err(matches_pattern!(EnvironmentError::SchemaMismatch {
+                name: eq(SMTP_CREDENTIALS_NAME),
+                field: some(eq("OAUTH2_TOKEN_URL")),
+                detail: eq("missing field"),
+            }))
+        );
+        expect_that!(
+            parses_as::<FriendlyCaptchaData>(
+                FRIENDLYCAPTCHA_DATA_NAME,
+                r#"{"FRIENDLYCAPTCHA_SITEKEY": "sitekey"}"#
+            ),
+            err(matches_pattern!(EnvironmentError::SchemaMismatch {
+                name: eq(FRIENDLYCAPTCHA_DATA_NAME),
+                field: some(eq("FRIENDLYCAPTCHA_SECRET")),
+                detail: anything(),
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn prefetch_counts_unusable_required_secrets() {
+        let mut secrets = FakeSecretRepsitory::open().await;
+        secrets.add_secret(SMTP_CREDENTIALS_NAME, r#"{"SMTP_USERNAME": "user"}"#);
+        secrets.remove_secret(FRIENDLYCAPTCHA_DATA_NAME);
+
+        expect_that!(prefetch_secrets(&secrets).await, eq(2));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn prefetch_ignores_unusable_optional_secrets() {
+        let mut secrets = FakeSecretRepsitory::open().await;
+        secrets.remove_secret(TOKEN_KEY_NAME);
+
+        expect_that!(prefetch_secrets(&secrets).await, eq(0));
+    }
+}
@@ -0,0 +1,217 @@
+//! Deserialises the JSON value of a secret with an error type which records what was wrong with
+//! which field, without ever holding the value itself.
+
+use serde::de::{
+    self,
+    value::{MapAccessDeserializer, MapDeserializer, SeqDeserializer},
+    DeserializeOwned, Deserializer, Expected, IntoDeserializer, Unexpected, Visitor,
+};
+use serde_json::Value;
+use std::fmt::Display;
+
+/// How a secret deviates from the expected form.
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    /// A required field is absent. It is named relative to the path at which the error occurred.
+    MissingField(&'static str),
+    /// A field is present which the secret does not allow.
+    UnknownField(String),
+    /// A value has the wrong type or content; the description says what was expected instead.
+    Expected(String),
+    /// Any other error, whose message is discarded since it might quote the value.
+    Other,
+}
+
+impl Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SchemaError::MissingField(_) => write!(f, "missing field"),
+            SchemaError::UnknownField(_) => write!(f, "unknown field"),
+            SchemaError::Expected(expected) => write!(f, "expected {expected}"),
+            SchemaError::Other => write!(f, "unexpected value"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+impl de::Error for SchemaError {
+    fn custom<T: Display>(_: T) -> Self {
+        SchemaError::Other
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        SchemaError::MissingField(field)
+    }
+
+    fn unknown_field(field: &str, _: &'static [&'static str]) -> Self {
+        SchemaError::UnknownField(field.into())
+    }
+
+    fn unknown_variant(_: &str, expected: &'static [&'static str]) -> Self {
+        SchemaError::Expected(format!("one of {}", expected.join(", ")))
+    }
+
+    fn invalid_type(_: Unexpected, expected: &dyn Expected) -> Self {
+        SchemaError::Expected(expected.to_string())
+    }
+
+    fn invalid_value(_: Unexpected, expected: &dyn Expected) -> Self {
+        SchemaError::Expected(expected.to_string())
+    }
+
+    fn invalid_length(_: usize, expected: &dyn Expected) -> Self {
+        SchemaError::Expected(expected.to_string())
+    }
+}
+
+/// Deserialises `T` from the parsed value, returning the path to the offending field on failure.
+/// The path is `None` if the error concerns the value as a whole.
+pub fn deserialize_value<T: DeserializeOwned>(
+    value: &Value,
+) -> Result<T, (Option<String>, SchemaError)> {
+    serde_path_to_error::deserialize(JsonValue(value)).map_err(|error| {
+        let path = error.path().to_string();
+        let path = (path != ".").then_some(path);
+        (path, error.into_inner())
+    })
+}
+
+struct JsonValue<'a>(&'a Value);
+
+impl<'de, 'a> IntoDeserializer<'de, SchemaError> for JsonValue<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de, 'a> Deserializer<'de> for JsonValue<'a> {
+    type Error = SchemaError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(value) => visitor.visit_bool(*value),
+            Value::Number(number) => match (number.as_u64(), number.as_i64(), number.as_f64()) {
+                (Some(value), _, _) => visitor.visit_u64(value),
+                (None, Some(value), _) => visitor.visit_i64(value),
+                (None, None, Some(value)) => visitor.visit_f64(value),
+                (None, None, None) => Err(SchemaError::Other),
+            },
+            Value::String(value) => visitor.visit_str(value),
+            Value::Array(items) => {
+                let mut items = SeqDeserializer::new(items.iter().map(JsonValue));
+                let result = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(result)
+            }
+            Value::Object(fields) => {
+                let mut fields = MapDeserializer::new(
+                    fields
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), JsonValue(value))),
+                );
+                let result = visitor.visit_map(&mut fields)?;
+                fields.end()?;
+                Ok(result)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, SchemaError> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, SchemaError> {
+        match self.0 {
+            Value::String(variant) => visitor.visit_enum(variant.as_str().into_deserializer()),
+            Value::Object(fields) => {
+                visitor.visit_enum(MapAccessDeserializer::new(MapDeserializer::new(
+                    fields
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), JsonValue(value))),
+                )))
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deserialize_value, SchemaError};
+    use googletest::prelude::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Relay {
+        #[allow(dead_code)]
+        url: String,
+        #[allow(dead_code)]
+        port: Option<u16>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Relays {
+        #[allow(dead_code)]
+        relays: Vec<Relay>,
+    }
+
+    #[test]
+    fn names_missing_field_and_its_parent() -> Result<()> {
+        verify_that!(
+            deserialize_value::<Relays>(&json!({"relays": [{"port": 25}]})),
+            err(eq((
+                Some("relays[0]".to_string()),
+                SchemaError::MissingField("url")
+            )))
+        )
+    }
+
+    #[test]
+    fn describes_expected_type_without_value() -> Result<()> {
+        verify_that!(
+            deserialize_value::<Relay>(&json!({"url": "smtp://a", "port": "secret"})),
+            err(eq((
+                Some("port".to_string()),
+                SchemaError::Expected("u16".into())
+            )))
+        )
+    }
+
+    #[test]
+    fn names_unknown_field() -> Result<()> {
+        verify_that!(
+            deserialize_value::<Relay>(&json!({"url": "smtp://a", "password": "secret"})),
+            err(eq((
+                Some("password".to_string()),
+                SchemaError::UnknownField("password".into())
+            )))
+        )
+    }
+}
@@ -1,71 +1,675 @@
-use crate::EnvironmentError;
-use aws_config::BehaviorVersion;
+use crate::secret_schema::{deserialize_value, SchemaError};
+use aws_config::{BehaviorVersion, SdkConfig};
+use aws_sdk_secretsmanager::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::{get_secret_value::GetSecretValueError, put_secret_value::PutSecretValueError},
+};
+use aws_sdk_ssm::operation::get_parameter::GetParameterError;
 use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Display,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
 
+const DEFAULT_SECRETS_REGION: &str = "eu-north-1";
+const DEFAULT_SECRETS_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The version of a secret to retrieve, corresponding to the AWS Secrets Manager staging labels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionStage {
+    /// The version in use, `AWSCURRENT`.
+    Current,
+    /// The version being introduced by an ongoing rotation, `AWSPENDING`.
+    Pending,
+}
+
+impl VersionStage {
+    fn label(self) -> &'static str {
+        match self {
+            VersionStage::Current => "AWSCURRENT",
+            VersionStage::Pending => "AWSPENDING",
+        }
+    }
+}
+
+impl Display for VersionStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.label())
+    }
+}
+
+/// The reasons why a secret cannot be used, each naming the secret in the backend.
+///
+/// None of the messages contains the value of the secret, so that they can be logged safely.
+#[derive(Debug)]
+pub enum EnvironmentError {
+    /// The secret does not exist.
+    NotFound(String),
+    /// The backend refused to return the secret, typically for lack of IAM or KMS permissions.
+    AccessDenied {
+        name: String,
+        detail: String,
+    },
+    /// The secret holds a binary value rather than a string.
+    BinarySecret(String),
+    /// The value of the secret does not have the expected form. The field is the path to the
+    /// offending field, where it is known.
+    SchemaMismatch {
+        name: String,
+        field: Option<String>,
+        detail: String,
+    },
+    /// The backend could not be reached or failed for another reason.
+    Unavailable {
+        name: String,
+        detail: String,
+    },
+    UnsupportedVersionStage(String, VersionStage),
+}
+
+impl EnvironmentError {
+    /// Returns the error as an [`EnvironmentError`] if it is one, and otherwise wraps it as
+    /// [`EnvironmentError::Unavailable`].
+    pub fn classify(name: &str, error: lambda_http::Error) -> Self {
+        match error.downcast::<Self>() {
+            Ok(error) => *error,
+            Err(error) => Self::Unavailable {
+                name: name.into(),
+                detail: error.to_string(),
+            },
+        }
+    }
+
+    fn schema_mismatch(name: &str, path: Option<String>, error: SchemaError) -> Self {
+        let field = match (path, &error) {
+            (None, SchemaError::MissingField(field)) => Some(field.to_string()),
+            (Some(path), SchemaError::MissingField(field)) => Some(format!("{path}.{field}")),
+            (path, _) => path,
+        };
+        Self::SchemaMismatch {
+            name: name.into(),
+            field,
+            detail: error.to_string(),
+        }
+    }
+
+    /// The value is not JSON at all. serde_json's messages are not used, since they may quote
+    /// the value.
+    fn invalid_json(name: &str, error: &serde_json::Error) -> Self {
+        Self::SchemaMismatch {
+            name: name.into(),
+            field: None,
+            detail: match error.classify() {
+                Category::Eof => "JSON ends unexpectedly".into(),
+                Category::Syntax | Category::Io | Category::Data => "not valid JSON".into(),
+            },
+        }
+    }
+}
+
+impl Display for EnvironmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotFound(name) => write!(f, "Secret {name} does not exist"),
+            Self::AccessDenied { name, detail } => {
+                write!(f, "Access to secret {name} was denied: {detail}")
+            }
+            Self::BinarySecret(name) => {
+                write!(f, "Secret {name} is binary, but a JSON string is expected")
+            }
+            Self::SchemaMismatch {
+                name,
+                field: Some(field),
+                detail,
+            } => write!(f, "Secret {name} has an invalid field {field}: {detail}"),
+            Self::SchemaMismatch {
+                name,
+                field: None,
+                detail,
+            } => write!(f, "Secret {name} is invalid: {detail}"),
+            Self::Unavailable { name, detail } => {
+                write!(f, "Secret {name} could not be retrieved: {detail}")
+            }
+            Self::UnsupportedVersionStage(name, stage) => {
+                write!(f, "Version stage {stage} of secret {name} is not supported")
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvironmentError {}
+
+/// Parses the value of the secret with the given name from JSON, reporting which field does not
+/// match the expected form.
+pub(crate) fn parse_secret<T: DeserializeOwned>(
+    name: &str,
+    value: &str,
+) -> Result<T, EnvironmentError> {
+    let value: serde_json::Value = serde_json::from_str(value)
+        .map_err(|error| EnvironmentError::invalid_json(name, &error))?;
+    let secret = deserialize_value(&value)
+        .map_err(|(path, error)| EnvironmentError::schema_mismatch(name, path, error))?;
+    Ok(secret)
+}
+
+// The trait is only implemented within this package, where the concrete futures are known.
+#[allow(async_fn_in_trait)]
 pub trait SecretRepository {
     async fn open() -> Self
     where
         Self: Sized;
 
-    async fn get_secret<T: DeserializeOwned>(
+    /// Returns the raw value of the secret with the given name.
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error>;
+
+    /// Returns the raw value of the given version of the secret with the given name.
+    ///
+    /// Backends without a notion of versions only support [`VersionStage::Current`].
+    async fn get_secret_string_at_stage(
+        &self,
+        name: &str,
+        stage: VersionStage,
+    ) -> Result<String, lambda_http::Error> {
+        match stage {
+            VersionStage::Current => self.get_secret_string(name).await,
+            VersionStage::Pending => Err(Box::new(EnvironmentError::UnsupportedVersionStage(
+                name.into(),
+                stage,
+            ))),
+        }
+    }
+
+    /// Returns the secret with the given name, parsed from JSON.
+    async fn get_secret<T: DeserializeOwned>(&self, name: &str) -> Result<T, lambda_http::Error> {
+        Ok(parse_secret(name, &self.get_secret_string(name).await?)?)
+    }
+
+    /// Returns the given version of the secret with the given name, parsed from JSON.
+    async fn get_secret_at_stage<T: DeserializeOwned>(
         &self,
-        name: &'static str,
-    ) -> Result<T, lambda_http::Error>;
+        name: &str,
+        stage: VersionStage,
+    ) -> Result<T, lambda_http::Error> {
+        Ok(parse_secret(
+            name,
+            &self.get_secret_string_at_stage(name, stage).await?,
+        )?)
+    }
+
+    /// Discards any cached value of the secret with the given name, so that the next retrieval
+    /// fetches it from the backend.
+    ///
+    /// This should be called when a service rejects the secret, since it may have been rotated.
+    async fn invalidate(&self, _name: &str) {}
 }
 
+/// The secret repository selected by the environment variable `SECRETS_BACKEND`.
+///
+/// The possible values are:
+///
+///  * `secretsmanager` (the default): [`AwsSecretsManagerSecretRepository`],
+///  * `ssm`: [`SsmParameterStoreSecretRepository`],
+///  * `env`: [`EnvironmentSecretRepository`],
+///  * `file`: [`FileSecretRepository`].
+///
+/// The name under which any secret is looked up in the backend can be overridden with an
+/// environment variable `SECRET_NAME_<NAME>`, where `<NAME>` is the secret name in upper case
+/// with dashes replaced by underscores. For example, setting
+/// `SECRET_NAME_SMTP_SES_CREDENTIALS=prod/smtp` causes the SMTP credentials to be read from the
+/// secret `prod/smtp`.
+#[derive(Clone)]
+pub enum ConfiguredSecretRepository {
+    AwsSecretsManager(AwsSecretsManagerSecretRepository),
+    SsmParameterStore(SsmParameterStoreSecretRepository),
+    Environment(EnvironmentSecretRepository),
+    File(FileSecretRepository),
+}
+
+impl SecretRepository for ConfiguredSecretRepository {
+    async fn open() -> Self {
+        match std::env::var("SECRETS_BACKEND").as_deref() {
+            Ok("secretsmanager") | Err(_) => {
+                Self::AwsSecretsManager(AwsSecretsManagerSecretRepository::open().await)
+            }
+            Ok("ssm") => Self::SsmParameterStore(SsmParameterStoreSecretRepository::open().await),
+            Ok("env") => Self::Environment(EnvironmentSecretRepository::open().await),
+            Ok("file") => Self::File(FileSecretRepository::open().await),
+            Ok(other) => panic!(
+                "Unrecognised SECRETS_BACKEND {other}, expected one of secretsmanager, ssm, env, file"
+            ),
+        }
+    }
+
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        self.get_secret_string_at_stage(name, VersionStage::Current)
+            .await
+    }
+
+    async fn get_secret_string_at_stage(
+        &self,
+        name: &str,
+        stage: VersionStage,
+    ) -> Result<String, lambda_http::Error> {
+        let name = resolve_secret_name(name);
+        match self {
+            Self::AwsSecretsManager(repository) => {
+                repository.get_secret_string_at_stage(&name, stage).await
+            }
+            Self::SsmParameterStore(repository) => {
+                repository.get_secret_string_at_stage(&name, stage).await
+            }
+            Self::Environment(repository) => {
+                repository.get_secret_string_at_stage(&name, stage).await
+            }
+            Self::File(repository) => repository.get_secret_string_at_stage(&name, stage).await,
+        }
+    }
+}
+
+pub(crate) fn resolve_secret_name(name: &str) -> Cow<'_, str> {
+    std::env::var(format!("SECRET_NAME_{}", environment_key(name)))
+        .map(Cow::Owned)
+        .unwrap_or(Cow::Borrowed(name))
+}
+
+fn environment_key(name: &str) -> String {
+    name.to_uppercase().replace(['-', '/', '.'], "_")
+}
+
+pub(crate) async fn load_aws_config(region: Option<String>) -> SdkConfig {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = region {
+        loader = loader.region(aws_config::Region::new(region));
+    }
+    if let Ok(url) = std::env::var("AWS_ENDPOINT_URL") {
+        loader = loader.endpoint_url(url);
+    }
+    loader.load().await
+}
+
+fn secrets_region() -> String {
+    std::env::var("SECRETS_REGION").unwrap_or(DEFAULT_SECRETS_REGION.into())
+}
+
+/// Returns how long secrets may be cached before they are fetched again, configured through
+/// `SECRETS_CACHE_TTL_SECS` and defaulting to five minutes.
+pub fn secrets_cache_ttl() -> Duration {
+    std::env::var("SECRETS_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_SECRETS_CACHE_TTL)
+}
+
+/// Caches the current version of secrets from another repository for a limited time, so that
+/// rotated secrets are picked up by a warm instance without overloading the backend.
+///
+/// Each secret is cached behind its own lock, so that fetching one secret holds up concurrent
+/// lookups of the same secret, which would otherwise fetch it again, but not of any other.
+///
+/// Clones share the same cache.
+#[derive(Clone)]
+pub struct CachingSecretRepository<SecretRepositoryT> {
+    inner: SecretRepositoryT,
+    ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+/// The cached value of one secret, if it has been fetched.
+type CacheEntry = Arc<Mutex<Option<CachedSecret>>>;
+
+struct CachedSecret {
+    value: String,
+    fetched_at: Instant,
+}
+
+impl<SecretRepositoryT: SecretRepository> CachingSecretRepository<SecretRepositoryT> {
+    pub fn new(inner: SecretRepositoryT, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            cache: Default::default(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn inner_mut(&mut self) -> &mut SecretRepositoryT {
+        &mut self.inner
+    }
+}
+
+impl<SecretRepositoryT: SecretRepository> SecretRepository
+    for CachingSecretRepository<SecretRepositoryT>
+{
+    async fn open() -> Self {
+        Self::new(SecretRepositoryT::open().await, secrets_cache_ttl())
+    }
+
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        let entry = self
+            .cache
+            .lock()
+            .await
+            .entry(name.into())
+            .or_default()
+            .clone();
+        let mut entry = entry.lock().await;
+        if let Some(cached) = entry.as_ref() {
+            if cached.fetched_at.elapsed() < self.ttl {
+                return Ok(cached.value.clone());
+            }
+        }
+        let value = self.inner.get_secret_string(name).await?;
+        *entry = Some(CachedSecret {
+            value: value.clone(),
+            fetched_at: Instant::now(),
+        });
+        Ok(value)
+    }
+
+    async fn get_secret_string_at_stage(
+        &self,
+        name: &str,
+        stage: VersionStage,
+    ) -> Result<String, lambda_http::Error> {
+        match stage {
+            VersionStage::Current => self.get_secret_string(name).await,
+            // Pending versions are only consulted as a fallback during rotation, so they are not
+            // worth caching.
+            VersionStage::Pending => self.inner.get_secret_string_at_stage(name, stage).await,
+        }
+    }
+
+    async fn invalidate(&self, name: &str) {
+        self.cache.lock().await.remove(name);
+    }
+}
+
+/// Reads secrets from AWS Secrets Manager in the region `SECRETS_REGION` (by default
+/// `eu-north-1`).
 #[derive(Clone)]
 pub struct AwsSecretsManagerSecretRepository(aws_sdk_secretsmanager::Client);
 
+impl AwsSecretsManagerSecretRepository {
+    /// Stores a new current version of the secret with the given name, creating the secret if it
+    /// does not exist yet. The name is resolved as for retrieval.
+    pub async fn put_secret_string(
+        &self,
+        name: &str,
+        value: &str,
+    ) -> Result<(), lambda_http::Error> {
+        let name = resolve_secret_name(name);
+        let result = self
+            .0
+            .put_secret_value()
+            .secret_id(name.as_ref())
+            .secret_string(value)
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(error) => match error.into_service_error() {
+                PutSecretValueError::ResourceNotFoundException(_) => {
+                    self.0
+                        .create_secret()
+                        .name(name.as_ref())
+                        .secret_string(value)
+                        .send()
+                        .await?;
+                    Ok(())
+                }
+                error => Err(error.into()),
+            },
+        }
+    }
+}
+
 impl SecretRepository for AwsSecretsManagerSecretRepository {
     async fn open() -> Self {
-        let mut loader = aws_config::defaults(BehaviorVersion::latest()).region("eu-north-1");
-        if let Ok(url) = std::env::var("AWS_ENDPOINT_URL") {
-            loader = loader.endpoint_url(url);
-        }
-        let config = loader.load().await;
+        let config = load_aws_config(Some(secrets_region())).await;
         let secrets_client = aws_sdk_secretsmanager::Client::new(&config);
         Self(secrets_client)
     }
 
-    async fn get_secret<T: DeserializeOwned>(
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        self.get_secret_string_at_stage(name, VersionStage::Current)
+            .await
+    }
+
+    async fn get_secret_string_at_stage(
         &self,
-        name: &'static str,
-    ) -> Result<T, lambda_http::Error> {
-        let secret = self.0.get_secret_value().secret_id(name).send().await?;
+        name: &str,
+        stage: VersionStage,
+    ) -> Result<String, lambda_http::Error> {
+        let secret = self
+            .0
+            .get_secret_value()
+            .secret_id(name)
+            .version_stage(stage.label())
+            .send()
+            .await
+            .map_err(|error| secrets_manager_error(name, error))?;
         let Some(secret_value) = secret.secret_string() else {
-            return Err(Box::new(EnvironmentError::MissingSecret(name)));
+            return Err(Box::new(EnvironmentError::BinarySecret(name.into())));
+        };
+        Ok(secret_value.into())
+    }
+}
+
+/// Reads secrets from encrypted parameters in the AWS Systems Manager Parameter Store in the region
+/// `SECRETS_REGION` (by default `eu-north-1`).
+///
+/// The parameter name is the secret name prefixed with `SSM_PARAMETER_PREFIX`, which defaults to
+/// `/`.
+#[derive(Clone)]
+pub struct SsmParameterStoreSecretRepository {
+    client: aws_sdk_ssm::Client,
+    prefix: String,
+}
+
+impl SecretRepository for SsmParameterStoreSecretRepository {
+    async fn open() -> Self {
+        let config = load_aws_config(Some(secrets_region())).await;
+        Self {
+            client: aws_sdk_ssm::Client::new(&config),
+            prefix: std::env::var("SSM_PARAMETER_PREFIX").unwrap_or("/".into()),
+        }
+    }
+
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        let output = self
+            .client
+            .get_parameter()
+            .name(format!("{}{name}", self.prefix))
+            .with_decryption(true)
+            .send()
+            .await
+            .map_err(|error| parameter_store_error(name, error))?;
+        let Some(value) = output.parameter().and_then(|parameter| parameter.value()) else {
+            return Err(Box::new(EnvironmentError::NotFound(name.into())));
+        };
+        Ok(value.into())
+    }
+}
+
+fn secrets_manager_error(
+    name: &str,
+    error: SdkError<GetSecretValueError, impl std::fmt::Debug>,
+) -> EnvironmentError {
+    match error.as_service_error() {
+        Some(GetSecretValueError::ResourceNotFoundException(_)) => {
+            EnvironmentError::NotFound(name.into())
+        }
+        Some(error) if is_access_denied(error) => EnvironmentError::AccessDenied {
+            name: name.into(),
+            detail: error.message().unwrap_or("no details").into(),
+        },
+        Some(error) => EnvironmentError::Unavailable {
+            name: name.into(),
+            detail: error.to_string(),
+        },
+        None => EnvironmentError::Unavailable {
+            name: name.into(),
+            detail: aws_sdk_secretsmanager::error::DisplayErrorContext(&error).to_string(),
+        },
+    }
+}
+
+fn parameter_store_error(
+    name: &str,
+    error: SdkError<GetParameterError, impl std::fmt::Debug>,
+) -> EnvironmentError {
+    match error.as_service_error() {
+        Some(GetParameterError::ParameterNotFound(_)) => EnvironmentError::NotFound(name.into()),
+        Some(error) if is_access_denied(error) => EnvironmentError::AccessDenied {
+            name: name.into(),
+            detail: error.message().unwrap_or("no details").into(),
+        },
+        Some(error) => EnvironmentError::Unavailable {
+            name: name.into(),
+            detail: error.to_string(),
+        },
+        None => EnvironmentError::Unavailable {
+            name: name.into(),
+            detail: aws_sdk_ssm::error::DisplayErrorContext(&error).to_string(),
+        },
+    }
+}
+
+/// Returns whether the error is due to missing permissions. A failure to decrypt the secret is
+/// almost always a missing permission on the KMS key.
+fn is_access_denied(error: &impl ProvideErrorMetadata) -> bool {
+    matches!(
+        error.code(),
+        Some("AccessDeniedException" | "DecryptionFailure" | "KMSAccessDeniedException")
+    )
+}
+
+/// Reads secrets from environment variables `SECRET_<NAME>`, where `<NAME>` is the secret name in
+/// upper case with dashes replaced by underscores. For example, the secret `smtp-ses-credentials`
+/// is read from `SECRET_SMTP_SES_CREDENTIALS`.
+///
+/// This is intended for local development and CI.
+#[derive(Clone)]
+pub struct EnvironmentSecretRepository;
+
+impl SecretRepository for EnvironmentSecretRepository {
+    async fn open() -> Self {
+        Self
+    }
+
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        std::env::var(format!("SECRET_{}", environment_key(name)))
+            .map_err(|_| EnvironmentError::NotFound(name.into()).into())
+    }
+}
+
+/// Reads secrets from the JSON or TOML file given by the environment variable `SECRETS_FILE`. The
+/// format is determined by the file extension, defaulting to JSON.
+///
+/// The file contains a table mapping each secret name to its value, which may be given either as
+/// a nested table or as a string containing JSON:
+///
+/// ```toml
+/// [smtp-ses-credentials]
+/// SMTP_USERNAME = "username"
+/// SMTP_PASSWORD = "password"
+/// ```
+///
+/// The file is read once when the repository is opened.
+#[derive(Clone)]
+pub struct FileSecretRepository(Arc<Result<HashMap<String, String>, String>>);
+
+impl FileSecretRepository {
+    pub(crate) fn load(path: &Path) -> Result<HashMap<String, String>, lambda_http::Error> {
+        let content = std::fs::read_to_string(path)?;
+        let table: HashMap<String, serde_json::Value> = if path
+            .extension()
+            .is_some_and(|extension| extension == "toml")
+        {
+            toml::from_str(&content)?
+        } else {
+            serde_json::from_str(&content)?
+        };
+        Ok(table
+            .into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(value) => (name, value),
+                value => (name, value.to_string()),
+            })
+            .collect())
+    }
+}
+
+impl SecretRepository for FileSecretRepository {
+    async fn open() -> Self {
+        let secrets = match std::env::var("SECRETS_FILE") {
+            Ok(path) => Self::load(Path::new(&path))
+                .map_err(|error| format!("Unable to read secrets file {path}: {error}")),
+            Err(_) => Err("SECRETS_FILE is not set".into()),
         };
-        Ok(serde_json::from_str(secret_value)?)
+        Self(Arc::new(secrets))
+    }
+
+    async fn get_secret_string(&self, name: &str) -> Result<String, lambda_http::Error> {
+        match self.0.as_ref() {
+            Ok(secrets) => secrets
+                .get(name)
+                .cloned()
+                .ok_or_else(|| EnvironmentError::NotFound(name.into()).into()),
+            Err(error) => Err(error.as_str().into()),
+        }
     }
 }
 
 #[cfg(test)]
 pub mod test_support {
-    use super::SecretRepository;
-    use crate::{friendlycaptcha::FRIENDLYCAPTCHA_DATA_NAME, SMTP_CREDENTIALS_NAME};
-    use aws_sdk_secretsmanager::types::error::ResourceNotFoundException;
-    use serde::de::DeserializeOwned;
+    use super::{EnvironmentError, SecretRepository, VersionStage};
+    use crate::{
+        consent::IP_HASH_KEY_NAME, dkim::DKIM_SIGNING_KEY_NAME,
+        friendlycaptcha::FRIENDLYCAPTCHA_DATA_NAME, health_check::HEALTH_CHECK_TOKEN_NAME,
+        mailer::SMTP_CREDENTIALS_NAME, token::TOKEN_KEY_NAME,
+    };
     use std::collections::HashMap;
 
     pub const FAKE_FRIENDLYCAPTCHA_SITEKEY: &str = "arbitrary sitekey";
     pub const FAKE_FRIENDLYCAPTCHA_SECRET: &str = "arbitrary secret";
+    pub const FAKE_HEALTH_CHECK_TOKEN: &str = "arbitrary health check token";
+    pub const FAKE_DKIM_SELECTOR: &str = "arbitrary-selector";
+    const FAKE_DKIM_PRIVATE_KEY: &str = "jwp7AdezCRVME2mTAE46CtFQ+z9iAhT5nLedegXidRU=";
+    const FAKE_TOKEN_KEY: &str = "arbitrary token signing key";
+    const FAKE_IP_HASH_KEY: &str = "arbitrary IP hash key";
+    pub const FAKE_DKIM_PUBLIC_KEY: &str = "gxmfNZkm30gF5IW0HQk6KR8EfsWiOJLeaZoD4x3qCD0=";
 
     #[derive(Clone)]
-    pub struct FakeSecretRepsitory(HashMap<&'static str, String>);
+    pub struct FakeSecretRepsitory {
+        current: HashMap<&'static str, String>,
+        pending: HashMap<&'static str, String>,
+    }
 
     impl FakeSecretRepsitory {
         pub fn remove_secret(&mut self, name: &'static str) {
-            self.0.remove(name);
+            self.current.remove(name);
         }
 
         pub fn add_secret(&mut self, name: &'static str, value: impl Into<String>) {
-            self.0.insert(name, value.into());
+            self.current.insert(name, value.into());
+        }
+
+        pub fn add_pending_secret(&mut self, name: &'static str, value: impl Into<String>) {
+            self.pending.insert(name, value.into());
         }
     }
 
     impl SecretRepository for FakeSecretRepsitory {
         async fn open() -> Self {
-            Self(HashMap::from([
+            let current = HashMap::from([
                 (
                     SMTP_CREDENTIALS_NAME,
                     r#"{
@@ -83,21 +687,352 @@ pub mod test_support {
                         }}"#
                     ),
                 ),
-            ]))
+                (
+                    HEALTH_CHECK_TOKEN_NAME,
+                    format!(r#"{{"HEALTH_CHECK_TOKEN": "{FAKE_HEALTH_CHECK_TOKEN}"}}"#),
+                ),
+                (
+                    TOKEN_KEY_NAME,
+                    format!(r#"{{"TOKEN_SIGNING_KEY": "{FAKE_TOKEN_KEY}"}}"#),
+                ),
+                (
+                    IP_HASH_KEY_NAME,
+                    format!(r#"{{"CONSENT_IP_HASH_KEY": "{FAKE_IP_HASH_KEY}"}}"#),
+                ),
+                (
+                    DKIM_SIGNING_KEY_NAME,
+                    format!(
+                        r#"{{
+                            "DKIM_SELECTOR": "{FAKE_DKIM_SELECTOR}",
+                            "DKIM_PRIVATE_KEY": "{FAKE_DKIM_PRIVATE_KEY}",
+                            "DKIM_ALGORITHM": "ed25519"
+                        }}"#
+                    ),
+                ),
+            ]);
+            Self {
+                current,
+                pending: HashMap::new(),
+            }
         }
 
-        async fn get_secret<T: DeserializeOwned>(
+        async fn get_secret_string(
             &self,
-            name: &'static str,
-        ) -> std::result::Result<T, lambda_http::Error> {
-            let string_value = self.0.get(name).ok_or(Box::new(
-                aws_sdk_secretsmanager::Error::ResourceNotFoundException(
-                    ResourceNotFoundException::builder()
-                        .message(format!("No such secret {name}"))
-                        .build(),
-                ),
-            ))?;
-            Ok(serde_json::from_str(string_value)?)
+            name: &str,
+        ) -> std::result::Result<String, lambda_http::Error> {
+            self.get_secret_string_at_stage(name, VersionStage::Current)
+                .await
         }
+
+        async fn get_secret_string_at_stage(
+            &self,
+            name: &str,
+            stage: VersionStage,
+        ) -> std::result::Result<String, lambda_http::Error> {
+            let secrets = match stage {
+                VersionStage::Current => &self.current,
+                VersionStage::Pending => &self.pending,
+            };
+            let string_value = secrets
+                .get(name)
+                .ok_or_else(|| Box::new(EnvironmentError::NotFound(name.into())))?;
+            Ok(string_value.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_secret, test_support::FakeSecretRepsitory, CachingSecretRepository,
+        ConfiguredSecretRepository, EnvironmentError, SecretRepository,
+    };
+    use googletest::prelude::*;
+    use serde::Deserialize;
+    use serial_test::serial;
+    use std::time::Duration;
+    use test_support::TemporaryEnv;
+    use tokio::time::timeout;
+
+    const SECRET_NAME: &str = "arbitrary-secret";
+    const STALLING_SECRET_NAME: &str = "stalling-secret";
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Credentials {
+        #[serde(rename = "SMTP_USERNAME")]
+        username: String,
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reads_secret_from_environment() {
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "env");
+        let _secret = TemporaryEnv::new(
+            "SECRET_SMTP_SES_CREDENTIALS",
+            r#"{"SMTP_USERNAME": "username"}"#,
+        );
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("smtp-ses-credentials")
+            .await;
+
+        expect_that!(
+            secret,
+            ok(matches_pattern!(Credentials {
+                username: eq("username")
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reports_missing_secret_in_environment() {
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "env");
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("nonexistent-secret")
+            .await;
+
+        expect_that!(
+            secret.map_err(|error| EnvironmentError::classify("nonexistent-secret", error)),
+            err(matches_pattern!(EnvironmentError::NotFound(eq(
+                "nonexistent-secret"
+            ))))
+        );
+    }
+
+    #[test]
+    fn reports_missing_field_of_secret() -> Result<()> {
+        verify_that!(
+            parse_secret::<Credentials>("smtp-ses-credentials", r#"{"username": "username"}"#),
+            err(matches_pattern!(EnvironmentError::SchemaMismatch {
+                name: eq("smtp-ses-credentials"),
+                field: some(eq("SMTP_USERNAME")),
+                detail: eq("missing field"),
+            }))
+        )
+    }
+
+    #[test]
+    fn reports_field_of_wrong_type_without_its_value() -> Result<()> {
+        verify_that!(
+            parse_secret::<Credentials>("smtp-ses-credentials", r#"{"SMTP_USERNAME": 12345}"#),
+            err(all!(
+                displays_as(contains_substring("SMTP_USERNAME")),
+                displays_as(contains_substring("expected a string")),
+                not(displays_as(contains_substring("12345")))
+            ))
+        )
+    }
+
+    #[test]
+    fn reports_secret_which_is_not_json() -> Result<()> {
+        verify_that!(
+            parse_secret::<Credentials>("smtp-ses-credentials", "username=hunter2"),
+            err(all!(
+                displays_as(eq("Secret smtp-ses-credentials is invalid: not valid JSON")),
+                not(displays_as(contains_substring("hunter2")))
+            ))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reports_schema_mismatch_in_secret_from_environment() {
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "env");
+        let _secret = TemporaryEnv::new("SECRET_SMTP_SES_CREDENTIALS", r#"{"SMTP_USER": "x"}"#);
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("smtp-ses-credentials")
+            .await;
+
+        expect_that!(
+            secret.map_err(|error| EnvironmentError::classify("smtp-ses-credentials", error)),
+            err(matches_pattern!(EnvironmentError::SchemaMismatch {
+                field: some(eq("SMTP_USERNAME")),
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reads_nested_table_from_toml_file() {
+        let path = write_secrets_file(
+            "secrets.toml",
+            r#"
+                [smtp-ses-credentials]
+                SMTP_USERNAME = "username"
+            "#,
+        );
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "file");
+        let _file = TemporaryEnv::new("SECRETS_FILE", path.to_string_lossy());
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("smtp-ses-credentials")
+            .await;
+
+        expect_that!(
+            secret,
+            ok(matches_pattern!(Credentials {
+                username: eq("username")
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reads_string_value_from_json_file() {
+        let path = write_secrets_file(
+            "secrets.json",
+            r#"{"smtp-ses-credentials": "{\"SMTP_USERNAME\": \"username\"}"}"#,
+        );
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "file");
+        let _file = TemporaryEnv::new("SECRETS_FILE", path.to_string_lossy());
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("smtp-ses-credentials")
+            .await;
+
+        expect_that!(
+            secret,
+            ok(matches_pattern!(Credentials {
+                username: eq("username")
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    #[serial]
+    async fn reads_secret_under_overridden_name() {
+        let _backend = TemporaryEnv::new("SECRETS_BACKEND", "env");
+        let _name = TemporaryEnv::new("SECRET_NAME_SMTP_SES_CREDENTIALS", "prod/smtp");
+        let _secret = TemporaryEnv::new("SECRET_PROD_SMTP", r#"{"SMTP_USERNAME": "username"}"#);
+        let subject = ConfiguredSecretRepository::open().await;
+
+        let secret = subject
+            .get_secret::<Credentials>("smtp-ses-credentials")
+            .await;
+
+        expect_that!(
+            secret,
+            ok(matches_pattern!(Credentials {
+                username: eq("username")
+            }))
+        );
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn caching_repository_returns_cached_value_within_ttl() {
+        let mut subject = caching_repository_with_ttl(Duration::from_secs(60)).await;
+        subject.inner_mut().add_secret(SECRET_NAME, "old value");
+        subject.get_secret_string(SECRET_NAME).await.unwrap();
+        subject.inner_mut().add_secret(SECRET_NAME, "new value");
+
+        let secret = subject.get_secret_string(SECRET_NAME).await;
+
+        expect_that!(secret, ok(eq("old value")));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn caching_repository_fetches_value_again_after_ttl() {
+        let mut subject = caching_repository_with_ttl(Duration::ZERO).await;
+        subject.inner_mut().add_secret(SECRET_NAME, "old value");
+        subject.get_secret_string(SECRET_NAME).await.unwrap();
+        subject.inner_mut().add_secret(SECRET_NAME, "new value");
+
+        let secret = subject.get_secret_string(SECRET_NAME).await;
+
+        expect_that!(secret, ok(eq("new value")));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn caching_repository_fetches_value_again_after_invalidation() {
+        let mut subject = caching_repository_with_ttl(Duration::from_secs(60)).await;
+        subject.inner_mut().add_secret(SECRET_NAME, "old value");
+        subject.get_secret_string(SECRET_NAME).await.unwrap();
+        subject.inner_mut().add_secret(SECRET_NAME, "new value");
+
+        subject.invalidate(SECRET_NAME).await;
+        let secret = subject.get_secret_string(SECRET_NAME).await;
+
+        expect_that!(secret, ok(eq("new value")));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn caching_repository_does_not_cache_failures() {
+        let mut subject = caching_repository_with_ttl(Duration::from_secs(60)).await;
+        subject.get_secret_string(SECRET_NAME).await.unwrap_err();
+        subject.inner_mut().add_secret(SECRET_NAME, "value");
+
+        let secret = subject.get_secret_string(SECRET_NAME).await;
+
+        expect_that!(secret, ok(eq("value")));
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn caching_repository_does_not_hold_up_other_secrets_while_fetching() {
+        let mut inner = FakeSecretRepsitory::open().await;
+        inner.add_secret(SECRET_NAME, "value");
+        let subject =
+            CachingSecretRepository::new(StallingSecretRepository(inner), Duration::from_secs(60));
+
+        let result = tokio::select! {
+            biased;
+            _ = subject.get_secret_string(STALLING_SECRET_NAME) => unreachable!(),
+            result = timeout(
+                Duration::from_secs(1),
+                subject.get_secret_string(SECRET_NAME),
+            ) => result,
+        };
+
+        expect_that!(result, ok(ok(eq("value"))));
+    }
+
+    /// Never answers for one secret, like a backend which hangs.
+    struct StallingSecretRepository(FakeSecretRepsitory);
+
+    impl SecretRepository for StallingSecretRepository {
+        async fn open() -> Self {
+            Self(FakeSecretRepsitory::open().await)
+        }
+
+        async fn get_secret_string(
+            &self,
+            name: &str,
+        ) -> std::result::Result<String, lambda_http::Error> {
+            if name == STALLING_SECRET_NAME {
+                std::future::pending().await
+            } else {
+                self.0.get_secret_string(name).await
+            }
+        }
+    }
+
+    async fn caching_repository_with_ttl(
+        ttl: Duration,
+    ) -> CachingSecretRepository<FakeSecretRepsitory> {
+        CachingSecretRepository::new(FakeSecretRepsitory::open().await, ttl)
+    }
+
+    fn write_secrets_file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
     }
 }
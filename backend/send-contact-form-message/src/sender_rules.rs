@@ -0,0 +1,491 @@
+use crate::secrets::{load_aws_config, SecretRepository};
+use ipnet::IpNet;
+use lambda_http::Error;
+use regex::Regex;
+use serde::Deserialize;
+use std::{
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::info;
+
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The rules for senders of submissions, as given in the rules document:
+///
+/// ```json
+/// {
+///   "block": {
+///     "emails": ["spammer@example.com"],
+///     "domains": ["spam.example"],
+///     "ips": ["203.0.113.0/24", "2001:db8::1"],
+///     "body_patterns": ["(?i)cheap watches"]
+///   },
+///   "allow": {
+///     "emails": ["client@example.com"],
+///     "domains": ["client.example"],
+///     "ips": ["198.51.100.7"]
+///   }
+/// }
+/// ```
+///
+/// Addresses and domains are compared without regard to case, and a domain also covers its
+/// subdomains. Body patterns are regular expressions which may match anywhere in the body.
+///
+/// Allowed senders are never blocked. Only clients from allowed IP addresses also skip the
+/// captcha: the sender's address is whatever the visitor typed in, so an allowed address or domain
+/// must not let anyone who knows it past the captcha. IP addresses are those determined by
+/// `consent::client_ip`, which never takes an address which the client supplied in
+/// `X-Forwarded-For`. Behind proxies other than API
+/// Gateway or an Application Load Balancer, IP rules only see the client's address if the proxies
+/// are listed in `TRUSTED_PROXIES`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RuleSet {
+    #[serde(default)]
+    block: BlockRules,
+    #[serde(default)]
+    allow: AllowRules,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct BlockRules {
+    #[serde(default)]
+    emails: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default, with = "ip_networks")]
+    ips: Vec<IpNet>,
+    #[serde(default, with = "regexes")]
+    body_patterns: Vec<Regex>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AllowRules {
+    #[serde(default)]
+    emails: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+    #[serde(default, with = "ip_networks")]
+    ips: Vec<IpNet>,
+}
+
+/// What the rules say about a submission.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The client's IP address is allowed, so the submission is not blocked and the captcha is
+    /// skipped.
+    Trusted,
+    /// The sender's address or domain is allowed, so the submission is not blocked. The captcha is
+    /// still required.
+    Allowed,
+    /// The submission matches the given block rule.
+    Blocked(String),
+    /// No rule applies.
+    Unmatched,
+}
+
+/// The parts of a submission which the rules look at.
+pub struct Sender<'a> {
+    pub email: &'a str,
+    pub client_ip: Option<&'a str>,
+    pub body: &'a str,
+}
+
+impl RuleSet {
+    pub fn verdict(&self, sender: &Sender) -> Verdict {
+        let domain = sender
+            .email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or_default();
+        let ip = sender.client_ip.and_then(|ip| ip.parse::<IpAddr>().ok());
+        if matches_ip(&self.allow.ips, ip).is_some() {
+            return Verdict::Trusted;
+        }
+        if matches_email(&self.allow.emails, sender.email)
+            || matches_domain(&self.allow.domains, domain).is_some()
+        {
+            return Verdict::Allowed;
+        }
+        let blocked_by = if matches_email(&self.block.emails, sender.email) {
+            Some(format!("email {}", sender.email))
+        } else if let Some(blocked) = matches_domain(&self.block.domains, domain) {
+            Some(format!("domain {blocked}"))
+        } else if let Some(network) = matches_ip(&self.block.ips, ip) {
+            Some(format!("IP {network}"))
+        } else {
+            self.block
+                .body_patterns
+                .iter()
+                .find(|pattern| pattern.is_match(sender.body))
+                .map(|pattern| format!("body pattern {pattern}"))
+        };
+        blocked_by.map_or(Verdict::Unmatched, Verdict::Blocked)
+    }
+
+    fn parse(content: &str, format: Format) -> Result<Self, Error> {
+        Ok(match format {
+            Format::Json => serde_json::from_str(content)?,
+            Format::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+fn matches_email(emails: &[String], email: &str) -> bool {
+    emails.iter().any(|entry| entry.eq_ignore_ascii_case(email))
+}
+
+fn matches_domain<'a>(domains: &'a [String], domain: &str) -> Option<&'a str> {
+    let domain = domain.to_ascii_lowercase();
+    domains.iter().map(|entry| entry.as_str()).find(|entry| {
+        let entry = entry.to_ascii_lowercase();
+        domain == entry || domain.ends_with(&format!(".{entry}"))
+    })
+}
+
+fn matches_ip(networks: &[IpNet], ip: Option<IpAddr>) -> Option<&IpNet> {
+    let ip = ip?;
+    networks.iter().find(|network| network.contains(&ip))
+}
+
+mod ip_networks {
+    use ipnet::IpNet;
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::net::IpAddr;
+
+    /// Accepts networks in CIDR notation as well as single addresses.
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| D::Error::custom(format!("Invalid IP address or network {entry}")))
+            })
+            .collect()
+    }
+}
+
+mod regexes {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|pattern| {
+                Regex::new(&pattern).map_err(|error| {
+                    D::Error::custom(format!("Invalid body pattern {pattern}: {error}"))
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    fn of(name: &str) -> Self {
+        if name.ends_with(".toml") {
+            Self::Toml
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Where the rules are loaded from, configured through the environment:
+///
+///  * `SENDER_RULES_SECRET`: a secret with the given name, containing JSON;
+///  * `SENDER_RULES_S3_URI`: an S3 object of the form `s3://<bucket>/<key>`;
+///  * `SENDER_RULES_FILE`: a local file.
+///
+/// Files and S3 objects are read as TOML if their name ends in `.toml` and as JSON otherwise.
+enum RuleSource {
+    Secret(String),
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        key: String,
+    },
+    File(PathBuf),
+}
+
+impl RuleSource {
+    async fn from_environment() -> Result<Option<Self>, Error> {
+        if let Ok(name) = std::env::var("SENDER_RULES_SECRET") {
+            Ok(Some(Self::Secret(name)))
+        } else if let Ok(uri) = std::env::var("SENDER_RULES_S3_URI") {
+            let Some((bucket, key)) = uri
+                .strip_prefix("s3://")
+                .and_then(|location| location.split_once('/'))
+            else {
+                return Err(format!("Invalid SENDER_RULES_S3_URI {uri}").into());
+            };
+            let config = load_aws_config(None).await;
+            Ok(Some(Self::S3 {
+                client: aws_sdk_s3::Client::new(&config),
+                bucket: bucket.into(),
+                key: key.into(),
+            }))
+        } else if let Ok(path) = std::env::var("SENDER_RULES_FILE") {
+            Ok(Some(Self::File(path.into())))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn load(&self, secrets: &impl SecretRepository) -> Result<RuleSet, Error> {
+        match self {
+            Self::Secret(name) => {
+                RuleSet::parse(&secrets.get_secret_string(name).await?, Format::Json)
+            }
+            Self::S3 {
+                client,
+                bucket,
+                key,
+            } => {
+                let content = client
+                    .get_object()
+                    .bucket(bucket)
+                    .key(key)
+                    .send()
+                    .await?
+                    .body
+                    .collect()
+                    .await?
+                    .into_bytes();
+                RuleSet::parse(std::str::from_utf8(&content)?, Format::of(key))
+            }
+            Self::File(path) => RuleSet::parse(
+                &std::fs::read_to_string(path)?,
+                Format::of(&path.to_string_lossy()),
+            ),
+        }
+    }
+}
+
+/// The sender rules, loaded from the [`RuleSource`] and loaded again once they are older than
+/// `SENDER_RULES_REFRESH_SECS` (five minutes by default), so that they can be changed without
+/// redeploying. If no source is configured, no rules apply.
+pub struct SenderRules {
+    source: Option<RuleSource>,
+    refresh_interval: Duration,
+    loaded: Mutex<Option<LoadedRules>>,
+}
+
+struct LoadedRules {
+    rules: Arc<RuleSet>,
+    loaded_at: Instant,
+}
+
+impl SenderRules {
+    pub async fn from_environment() -> Result<Self, Error> {
+        let refresh_interval = std::env::var("SENDER_RULES_REFRESH_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+        Ok(Self {
+            source: RuleSource::from_environment().await?,
+            refresh_interval,
+            loaded: Default::default(),
+        })
+    }
+
+    /// Returns the current rules, loading them if they are due to be refreshed.
+    ///
+    /// If loading fails, the rules loaded before stay in effect, or none if there are none, and the
+    /// error is returned alongside them. The failed attempt counts as a refresh, so that a broken
+    /// source is not consulted on every request.
+    pub async fn current(&self, secrets: &impl SecretRepository) -> (Arc<RuleSet>, Option<Error>) {
+        let Some(source) = &self.source else {
+            return (Default::default(), None);
+        };
+        let mut loaded = self.loaded.lock().await;
+        if let Some(loaded) = loaded.as_ref() {
+            if loaded.loaded_at.elapsed() < self.refresh_interval {
+                return (loaded.rules.clone(), None);
+            }
+        }
+        let (rules, error) = match source.load(secrets).await {
+            Ok(rules) => {
+                info!("Loaded sender rules");
+                (Arc::new(rules), None)
+            }
+            Err(error) => (
+                loaded
+                    .as_ref()
+                    .map(|loaded| loaded.rules.clone())
+                    .unwrap_or_default(),
+                Some(error),
+            ),
+        };
+        *loaded = Some(LoadedRules {
+            rules: rules.clone(),
+            loaded_at: Instant::now(),
+        });
+        (rules, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, RuleSet, RuleSource, Sender, SenderRules, Verdict};
+    use crate::secrets::{test_support::FakeSecretRepsitory, SecretRepository};
+    use googletest::prelude::*;
+    use std::time::Duration;
+
+    const RULES: &str = r#"{
+        "block": {
+            "emails": ["Spammer@Example.com"],
+            "domains": ["spam.example"],
+            "ips": ["203.0.113.0/24", "2001:db8::1"],
+            "body_patterns": ["(?i)cheap watches"]
+        },
+        "allow": {
+            "domains": ["client.example"],
+            "ips": ["198.51.100.7"]
+        }
+    }"#;
+
+    #[test]
+    fn blocks_email_regardless_of_case() -> Result<()> {
+        verify_that!(
+            rules().verdict(&sender("spammer@example.COM", None, "Hello")),
+            matches_pattern!(Verdict::Blocked(anything()))
+        )
+    }
+
+    #[googletest::test]
+    fn blocks_domain_and_its_subdomains() {
+        for email in ["a@spam.example", "a@mail.SPAM.example"] {
+            expect_that!(
+                rules().verdict(&sender(email, None, "Hello")),
+                eq(Verdict::Blocked("domain spam.example".into())),
+                "{email}"
+            );
+        }
+        expect_that!(
+            rules().verdict(&sender("a@notspam.example", None, "Hello")),
+            eq(Verdict::Unmatched)
+        );
+    }
+
+    #[googletest::test]
+    fn blocks_ip_addresses_in_network() {
+        expect_that!(
+            rules().verdict(&sender("a@example.com", Some("203.0.113.42"), "Hello")),
+            eq(Verdict::Blocked("IP 203.0.113.0/24".into()))
+        );
+        expect_that!(
+            rules().verdict(&sender("a@example.com", Some("2001:db8::1"), "Hello")),
+            matches_pattern!(Verdict::Blocked(anything()))
+        );
+        expect_that!(
+            rules().verdict(&sender("a@example.com", Some("203.0.114.1"), "Hello")),
+            eq(Verdict::Unmatched)
+        );
+    }
+
+    #[test]
+    fn blocks_body_matching_pattern() -> Result<()> {
+        verify_that!(
+            rules().verdict(&sender("a@example.com", None, "Buy CHEAP WATCHES now")),
+            matches_pattern!(Verdict::Blocked(anything()))
+        )
+    }
+
+    #[googletest::test]
+    fn allows_sender_even_if_blocked() {
+        expect_that!(
+            rules().verdict(&sender("a@client.example", None, "cheap watches")),
+            eq(Verdict::Allowed)
+        );
+        expect_that!(
+            rules().verdict(&sender(
+                "a@example.com",
+                Some("198.51.100.7"),
+                "cheap watches"
+            )),
+            eq(Verdict::Trusted)
+        );
+    }
+
+    #[googletest::test]
+    fn rejects_invalid_rules() {
+        for content in [
+            r#"{"block": {"body_patterns": ["("]}}"#,
+            r#"{"block": {"ips": ["300.0.0.1"]}}"#,
+            r#"{"allow": {"body_patterns": ["x"]}}"#,
+        ] {
+            expect_that!(
+                RuleSet::parse(content, Format::Json).map(|_| ()),
+                err(anything()),
+                "{content}"
+            );
+        }
+    }
+
+    #[test]
+    fn reads_rules_from_toml() -> Result<()> {
+        let rules = RuleSet::parse("[block]\ndomains = [\"spam.example\"]", Format::Toml).unwrap();
+
+        verify_that!(
+            rules.verdict(&sender("a@spam.example", None, "Hello")),
+            matches_pattern!(Verdict::Blocked(anything()))
+        )
+    }
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn keeps_previous_rules_when_refresh_fails() {
+        let path = std::env::temp_dir().join(format!("{}-sender-rules.json", std::process::id()));
+        std::fs::write(&path, RULES).unwrap();
+        let subject = SenderRules {
+            source: Some(RuleSource::File(path.clone())),
+            refresh_interval: Duration::ZERO,
+            loaded: Default::default(),
+        };
+        let secrets = FakeSecretRepsitory::open().await;
+        let blocked = sender("a@spam.example", None, "Hello");
+        let (rules, _) = subject.current(&secrets).await;
+        expect_that!(
+            rules.verdict(&blocked),
+            matches_pattern!(Verdict::Blocked(anything()))
+        );
+
+        std::fs::write(&path, "{").unwrap();
+        let (rules, error) = subject.current(&secrets).await;
+
+        std::fs::remove_file(&path).unwrap();
+        expect_that!(error, some(anything()));
+        expect_that!(
+            rules.verdict(&blocked),
+            matches_pattern!(Verdict::Blocked(anything()))
+        );
+    }
+
+    fn rules() -> RuleSet {
+        RuleSet::parse(RULES, Format::Json).unwrap()
+    }
+
+    fn sender<'a>(email: &'a str, client_ip: Option<&'a str>, body: &'a str) -> Sender<'a> {
+        Sender {
+            email,
+            client_ip,
+            body,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::BASE_HOST;
+use std::fmt::Display;
+use uuid::Uuid;
+
+/// Identifies a submission in the notification email and in the URL of the success page, so that
+/// replies and enquiries can be correlated with the original submission.
+#[derive(Clone, Debug)]
+pub struct SubmissionId(String);
+
+impl SubmissionId {
+    pub fn generate() -> Self {
+        let (_, random) = Uuid::new_v4().as_u64_pair();
+        Self(format!("{random:016x}"))
+    }
+
+    /// Returns the value of the `Message-ID` header of the notification email.
+    pub fn message_id(&self) -> String {
+        format!("<{}@{BASE_HOST}>", self.0)
+    }
+}
+
+impl Display for SubmissionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returns whether the form ID is safe to use in the `List-Id` and `X-Form-Id` headers.
+pub fn is_valid_form_id(form_id: &str) -> bool {
+    !form_id.is_empty()
+        && form_id.len() <= 64
+        && form_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// Returns the value of the `List-Id` header (RFC 2919) for messages from the given form.
+pub fn list_id(form_id: &str) -> String {
+    format!("Contact form {form_id} <{form_id}.contact-form.{BASE_HOST}>")
+}
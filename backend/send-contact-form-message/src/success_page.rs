@@ -0,0 +1,328 @@
+use crate::{
+    error_page::DEFAULT_LANGUAGE,
+    submission::SubmissionId,
+    template_source::{TemplateFile, TemplateVariants},
+    BASE_HOST,
+};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tinytemplate::TinyTemplate;
+use tracing::{info, warn};
+
+const EMAIL_SENT_TEMPLATE_NAME: &str = "email-sent";
+
+/// What the visitor sees once their message was sent, configured in the `success` section of the
+/// schema of the form:
+///
+///  * `redirect_url`: a template of the URL to redirect to, with the placeholders `{language}`,
+///    `{language_suffix}` (empty for the default language, otherwise `.` and the language) and
+///    `{submission}`. By default, the visitor is redirected to
+///    `https://hovinen.tech/email-sent{language_suffix}.html?submission={submission}`.
+///  * `return_to`: the URLs to which the form may ask to return the visitor through the
+///    `return-to` field. A return URL is allowed if it has the same origin as one of these and its
+///    path lies below that one's path. The submission reference is added to its query as
+///    `submission`.
+///  * `inline_page`: show the success page template `email-sent[.<language>].html` in the
+///    response instead of redirecting.
+///
+/// An allowed return URL takes precedence over the other settings.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SuccessBehaviour {
+    redirect_url: Option<String>,
+    #[serde(default)]
+    return_to: Vec<String>,
+    #[serde(default)]
+    inline_page: bool,
+}
+
+/// How to respond to a submission which was sent.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Success {
+    Redirect(String),
+    InlinePage,
+}
+
+impl SuccessBehaviour {
+    pub fn check(&self) -> Result<(), String> {
+        for url in &self.return_to {
+            parse_http_url(url).ok_or_else(|| format!("Invalid return URL {url}"))?;
+        }
+        if let Some(template) = &self.redirect_url {
+            let example = expand(template, DEFAULT_LANGUAGE, "0123456789abcdef");
+            parse_http_url(&example)
+                .ok_or_else(|| format!("Invalid redirect URL template {template}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn uses_inline_page(&self) -> bool {
+        self.inline_page
+    }
+
+    /// Returns how to respond to a submission in the given language, which asked to return the
+    /// visitor to `return_to` if that is given.
+    pub fn outcome(
+        &self,
+        return_to: Option<&str>,
+        language: &str,
+        submission_id: &SubmissionId,
+    ) -> Success {
+        if let Some(return_to) = return_to {
+            match self.allowed_return_url(return_to) {
+                Some(mut url) => {
+                    url.query_pairs_mut()
+                        .append_pair("submission", &submission_id.to_string());
+                    return Success::Redirect(url.into());
+                }
+                None => warn!("Ignoring return URL {return_to}, which is not allowed"),
+            }
+        }
+        if self.inline_page {
+            return Success::InlinePage;
+        }
+        let template = self
+            .redirect_url
+            .clone()
+            .unwrap_or_else(default_redirect_url_template);
+        Success::Redirect(expand(&template, language, &submission_id.to_string()))
+    }
+
+    fn allowed_return_url(&self, return_to: &str) -> Option<Url> {
+        let url = parse_http_url(return_to)?;
+        self.return_to
+            .iter()
+            .filter_map(|allowed| parse_http_url(allowed))
+            .any(|allowed| {
+                let prefix = allowed.path().trim_end_matches('/');
+                url.origin() == allowed.origin()
+                    && (url.path() == prefix || url.path().starts_with(&format!("{prefix}/")))
+            })
+            .then_some(url)
+    }
+}
+
+fn default_redirect_url_template() -> String {
+    format!("https://{BASE_HOST}/email-sent{{language_suffix}}.html?submission={{submission}}")
+}
+
+/// Parses an absolute HTTP(S) URL without credentials.
+fn parse_http_url(url: &str) -> Option<Url> {
+    Url::parse(url).ok().filter(|url| {
+        matches!(url.scheme(), "http" | "https")
+            && url.has_host()
+            && url.username().is_empty()
+            && url.password().is_none()
+    })
+}
+
+/// Fills in the placeholders of a redirect URL template. The language must be one of the
+/// supported languages, since it is not escaped.
+fn expand(template: &str, language: &str, submission: &str) -> String {
+    let language_suffix = if language == DEFAULT_LANGUAGE {
+        String::new()
+    } else {
+        format!(".{language}")
+    };
+    template
+        .replace("{language_suffix}", &language_suffix)
+        .replace("{language}", language)
+        .replace("{submission}", submission)
+}
+
+#[derive(Serialize)]
+struct Context {
+    site_root: String,
+    submission: String,
+}
+
+/// The success page templates for each site and language, loaded at cold start from the same
+/// source as the error page templates: `[sites/<host>/]email-sent[.<language>].html`.
+pub struct SuccessPages {
+    templates: TemplateVariants,
+}
+
+impl SuccessPages {
+    /// Collects the success page templates from the loaded template files and checks that each of
+    /// them renders.
+    pub fn from_files(files: &[TemplateFile]) -> Result<Self, lambda_http::Error> {
+        let templates = TemplateVariants::collect(EMAIL_SENT_TEMPLATE_NAME, files);
+        for file in templates.files() {
+            render_template(&file.content, &Context::sample())
+                .map_err(|error| format!("Invalid template {}: {error}", file.path))?;
+        }
+        info!(
+            "Loaded success pages for languages {:?}",
+            templates.languages()
+        );
+        Ok(Self { templates })
+    }
+
+    /// Returns whether there is a template for the default language, which is needed to show
+    /// success pages inline.
+    pub fn is_available(&self) -> bool {
+        self.templates.has_default()
+    }
+
+    /// Renders the success page in the given language, with the same fallbacks and site
+    /// overrides as the error page.
+    pub fn render(
+        &self,
+        submission_id: &SubmissionId,
+        language: &str,
+        site: Option<&str>,
+    ) -> Option<String> {
+        let template = self.templates.select(language, site)?;
+        let context = Context {
+            site_root: format!(
+                "https://{}",
+                self.templates.known_site(site).unwrap_or(BASE_HOST)
+            ),
+            submission: submission_id.to_string(),
+        };
+        Some(render_template(template, &context).expect("Templates are checked when loading"))
+    }
+}
+
+impl Context {
+    fn sample() -> Self {
+        Self {
+            site_root: format!("https://{BASE_HOST}"),
+            submission: "0123456789abcdef".into(),
+        }
+    }
+}
+
+fn render_template(
+    template: &str,
+    context: &Context,
+) -> Result<String, tinytemplate::error::Error> {
+    let mut tt = TinyTemplate::new();
+    tt.add_template(EMAIL_SENT_TEMPLATE_NAME, template)?;
+    tt.render(EMAIL_SENT_TEMPLATE_NAME, context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Success, SuccessBehaviour, SuccessPages};
+    use crate::{
+        submission::SubmissionId,
+        template_source::{bundled_templates, TemplateFile},
+    };
+    use googletest::prelude::*;
+
+    #[test]
+    fn redirects_to_default_success_page() -> Result<()> {
+        let submission_id = SubmissionId::generate();
+
+        verify_that!(
+            SuccessBehaviour::default().outcome(None, "de", &submission_id),
+            eq(Success::Redirect(format!(
+                "https://hovinen.tech/email-sent.de.html?submission={submission_id}"
+            )))
+        )
+    }
+
+    #[test]
+    fn redirects_to_configured_url_template() -> Result<()> {
+        let subject =
+            behaviour(r#"redirect_url = "https://example.com/{language}/thanks?ref={submission}""#);
+        let submission_id = SubmissionId::generate();
+
+        verify_that!(
+            subject.outcome(None, "en", &submission_id),
+            eq(Success::Redirect(format!(
+                "https://example.com/en/thanks?ref={submission_id}"
+            )))
+        )
+    }
+
+    #[test]
+    fn redirects_to_allowed_return_url() -> Result<()> {
+        let subject = behaviour(r#"return_to = ["https://example.com/shop/"]"#);
+        let submission_id = SubmissionId::generate();
+
+        verify_that!(
+            subject.outcome(
+                Some("https://example.com/shop/item?id=1"),
+                "en",
+                &submission_id
+            ),
+            eq(Success::Redirect(format!(
+                "https://example.com/shop/item?id=1&submission={submission_id}"
+            )))
+        )
+    }
+
+    #[googletest::test]
+    fn ignores_return_url_which_is_not_allowed() {
+        let subject = behaviour(r#"return_to = ["https://example.com/shop"]"#);
+        let submission_id = SubmissionId::generate();
+        let default = Success::Redirect(format!(
+            "https://hovinen.tech/email-sent.html?submission={submission_id}"
+        ));
+
+        for return_to in [
+            "https://example.com.evil.example/shop/item",
+            "https://evil.example/?https://example.com/shop",
+            "http://example.com/shop/item",
+            "https://example.com:8443/shop/item",
+            "https://example.com/shopping",
+            "https://user@example.com/shop/item",
+            "javascript:alert(1)",
+            "/shop/item",
+        ] {
+            expect_that!(
+                subject.outcome(Some(return_to), "en", &submission_id),
+                eq(default.clone()),
+                "{return_to}"
+            );
+        }
+    }
+
+    #[test]
+    fn shows_inline_page_when_configured() -> Result<()> {
+        let subject = behaviour("inline_page = true");
+
+        verify_that!(
+            subject.outcome(None, "en", &SubmissionId::generate()),
+            eq(Success::InlinePage)
+        )
+    }
+
+    #[test]
+    fn rejects_invalid_return_url() -> Result<()> {
+        verify_that!(
+            behaviour(r#"return_to = ["example.com"]"#).check(),
+            err(anything())
+        )
+    }
+
+    #[test]
+    fn renders_submission_reference_in_success_page() -> Result<()> {
+        let subject = SuccessPages::from_files(&bundled_templates()).unwrap();
+        let submission_id = SubmissionId::generate();
+
+        verify_that!(
+            subject.render(&submission_id, "de", None),
+            some(all!(
+                contains_substring("Nachricht gesendet"),
+                contains_substring(submission_id.to_string())
+            ))
+        )
+    }
+
+    #[test]
+    fn rejects_success_page_with_unknown_variable() -> Result<()> {
+        let result = SuccessPages::from_files(&[TemplateFile {
+            path: "email-sent.html".into(),
+            content: "{unknown}".into(),
+        }]);
+
+        verify_that!(result.map(|_| ()), err(anything()))
+    }
+
+    fn behaviour(toml: &str) -> SuccessBehaviour {
+        toml::from_str(toml).unwrap()
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{error_page::DEFAULT_LANGUAGE, language::fallback_chain, secrets::load_aws_config};
+use lambda_http::Error;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+const SITES_DIRECTORY: &str = "sites";
+
+const BUNDLED_SEND_ERROR_TEMPLATE_EN: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/send-error.html"
+));
+const BUNDLED_SEND_ERROR_TEMPLATE_DE: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/send-error.de.html"
+));
+const BUNDLED_EMAIL_SENT_TEMPLATE_EN: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/email-sent.html"
+));
+const BUNDLED_EMAIL_SENT_TEMPLATE_DE: &str = include_str!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/assets/email-sent.de.html"
+));
+
+/// A template file, identified by its path relative to the root of the template source with `/`
+/// as the separator.
+#[derive(Debug)]
+pub struct TemplateFile {
+    pub path: String,
+    pub content: String,
+}
+
+/// Where templates are loaded from at cold start, configured through the environment:
+///
+///  * `TEMPLATES_DIR`: a local directory;
+///  * `TEMPLATES_S3_URI`: an S3 prefix of the form `s3://<bucket>/<prefix>`.
+///
+/// If neither is set, the templates bundled with the binary are used.
+pub enum TemplateSource {
+    Bundled,
+    Directory(PathBuf),
+    S3 {
+        client: aws_sdk_s3::Client,
+        bucket: String,
+        prefix: String,
+    },
+}
+
+impl TemplateSource {
+    /// Loads the templates from the source configured in the environment.
+    pub async fn load_from_environment() -> Result<Vec<TemplateFile>, Error> {
+        Self::from_environment().await?.load().await
+    }
+
+    pub async fn from_environment() -> Result<Self, Error> {
+        if let Ok(directory) = std::env::var("TEMPLATES_DIR") {
+            Ok(Self::Directory(directory.into()))
+        } else if let Ok(uri) = std::env::var("TEMPLATES_S3_URI") {
+            let Some((bucket, prefix)) = parse_s3_prefix(&uri) else {
+                return Err(format!("Invalid TEMPLATES_S3_URI {uri}").into());
+            };
+            let config = load_aws_config(None).await;
+            Ok(Self::S3 {
+                client: aws_sdk_s3::Client::new(&config),
+                bucket,
+                prefix,
+            })
+        } else {
+            Ok(Self::Bundled)
+        }
+    }
+
+    pub async fn load(&self) -> Result<Vec<TemplateFile>, Error> {
+        match self {
+            TemplateSource::Bundled => Ok(bundled_templates()),
+            TemplateSource::Directory(directory) => {
+                info!("Loading templates from {}", directory.display());
+                let mut files = vec![];
+                read_directory(directory, "", &mut files)?;
+                Ok(files)
+            }
+            TemplateSource::S3 {
+                client,
+                bucket,
+                prefix,
+            } => {
+                info!("Loading templates from s3://{bucket}/{prefix}");
+                let mut files = vec![];
+                let mut pages = client
+                    .list_objects_v2()
+                    .bucket(bucket)
+                    .prefix(prefix.as_str())
+                    .into_paginator()
+                    .send();
+                while let Some(page) = pages.next().await {
+                    for object in page?.contents() {
+                        let Some(key) = object.key() else {
+                            continue;
+                        };
+                        let content = client
+                            .get_object()
+                            .bucket(bucket)
+                            .key(key)
+                            .send()
+                            .await?
+                            .body
+                            .collect()
+                            .await?
+                            .into_bytes();
+                        files.push(TemplateFile {
+                            path: key
+                                .strip_prefix(prefix.as_str())
+                                .unwrap_or(key)
+                                .trim_start_matches('/')
+                                .into(),
+                            content: String::from_utf8(content.to_vec())
+                                .map_err(|error| format!("Template {key}: {error}"))?,
+                        });
+                    }
+                }
+                Ok(files)
+            }
+        }
+    }
+}
+
+/// Splits an S3 URI into the bucket and the key prefix. A non-empty prefix is taken to name a
+/// folder, so that `s3://bucket/templates` does not also match `templates-old/...`.
+fn parse_s3_prefix(uri: &str) -> Option<(String, String)> {
+    let location = uri.strip_prefix("s3://")?;
+    let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+    let prefix = if prefix.is_empty() || prefix.ends_with('/') {
+        prefix.into()
+    } else {
+        format!("{prefix}/")
+    };
+    Some((bucket.into(), prefix))
+}
+
+pub fn bundled_templates() -> Vec<TemplateFile> {
+    vec![
+        TemplateFile {
+            path: "send-error.html".into(),
+            content: BUNDLED_SEND_ERROR_TEMPLATE_EN.into(),
+        },
+        TemplateFile {
+            path: "send-error.de.html".into(),
+            content: BUNDLED_SEND_ERROR_TEMPLATE_DE.into(),
+        },
+        TemplateFile {
+            path: "email-sent.html".into(),
+            content: BUNDLED_EMAIL_SENT_TEMPLATE_EN.into(),
+        },
+        TemplateFile {
+            path: "email-sent.de.html".into(),
+            content: BUNDLED_EMAIL_SENT_TEMPLATE_DE.into(),
+        },
+    ]
+}
+
+/// The variants of one template for each site and language.
+///
+/// The variant for the default language (English) is in `<name>.html`, those for any further
+/// languages in `<name>.<language>.html`. The variants in `sites/<host>/` override these for the
+/// site at `<host>`.
+pub struct TemplateVariants {
+    templates: HashMap<TemplateKey, TemplateFile>,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct TemplateKey {
+    site: Option<String>,
+    language: String,
+}
+
+impl TemplateVariants {
+    /// Collects the variants of the template with the given name, ignoring all other files.
+    pub fn collect(name: &str, files: &[TemplateFile]) -> Self {
+        let templates = files
+            .iter()
+            .filter_map(|file| {
+                let key = TemplateKey::parse(name, &file.path)?;
+                Some((
+                    key,
+                    TemplateFile {
+                        path: file.path.clone(),
+                        content: file.content.clone(),
+                    },
+                ))
+            })
+            .collect();
+        Self { templates }
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &TemplateFile> {
+        self.templates.values()
+    }
+
+    pub fn has_default(&self) -> bool {
+        self.templates
+            .contains_key(&TemplateKey::new(None, DEFAULT_LANGUAGE))
+    }
+
+    /// Returns the languages for which there is a variant, not counting site overrides.
+    pub fn languages(&self) -> BTreeSet<&str> {
+        self.templates
+            .keys()
+            .filter(|key| key.site.is_none())
+            .map(|key| key.language.as_str())
+            .collect()
+    }
+
+    /// Returns the given site if there are variants for it.
+    pub fn known_site<'a>(&self, site: Option<&'a str>) -> Option<&'a str> {
+        site.filter(|site| {
+            self.templates
+                .keys()
+                .any(|key| key.site.as_deref() == Some(*site))
+        })
+    }
+
+    /// Returns the variant in the given language, falling back to its more general forms and then
+    /// to the default language, e.g. `de-AT`, `de`, `en`. The variants for the given site take
+    /// precedence.
+    pub fn select(&self, language: &str, site: Option<&str>) -> Option<&str> {
+        let site = self.known_site(site);
+        let languages: Vec<&str> = fallback_chain(language).chain([DEFAULT_LANGUAGE]).collect();
+        [site, None]
+            .iter()
+            .flat_map(|site| {
+                languages
+                    .iter()
+                    .map(move |language| TemplateKey::new(*site, language))
+            })
+            .find_map(|key| self.templates.get(&key))
+            .map(|file| file.content.as_str())
+    }
+}
+
+impl TemplateKey {
+    fn new(site: Option<&str>, language: &str) -> Self {
+        Self {
+            site: site.map(String::from),
+            language: language.into(),
+        }
+    }
+
+    /// Parses a path of the form `[sites/<host>/]<name>[.<language>].html`.
+    fn parse(name: &str, path: &str) -> Option<Self> {
+        let (site, file_name) = match path.split('/').collect::<Vec<_>>()[..] {
+            [file_name] => (None, file_name),
+            [SITES_DIRECTORY, site, file_name] if !site.is_empty() => (Some(site), file_name),
+            _ => return None,
+        };
+        let language = match file_name.strip_prefix(name)?.strip_suffix(".html")? {
+            "" => DEFAULT_LANGUAGE,
+            suffix => suffix.strip_prefix('.').filter(|language| {
+                !language.is_empty()
+                    && language
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })?,
+        };
+        Some(Self::new(site, language))
+    }
+}
+
+fn read_directory(
+    directory: &Path,
+    relative_path: &str,
+    files: &mut Vec<TemplateFile>,
+) -> Result<(), Error> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if relative_path.is_empty() {
+            name
+        } else {
+            format!("{relative_path}/{name}")
+        };
+        if entry.file_type()?.is_dir() {
+            read_directory(&entry.path(), &path, files)?;
+        } else {
+            let content = std::fs::read_to_string(entry.path())
+                .map_err(|error| format!("Template {path}: {error}"))?;
+            files.push(TemplateFile { path, content });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_s3_prefix, TemplateFile, TemplateSource};
+    use googletest::prelude::*;
+
+    #[googletest::test]
+    #[tokio::test]
+    async fn loads_templates_from_directory_with_relative_paths() {
+        let directory = std::env::temp_dir().join(format!("templates-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("sites/example.com")).unwrap();
+        std::fs::write(directory.join("send-error.html"), "Default").unwrap();
+        std::fs::write(
+            directory.join("sites/example.com/send-error.html"),
+            "Example",
+        )
+        .unwrap();
+
+        let files = TemplateSource::Directory(directory.clone()).load().await;
+
+        std::fs::remove_dir_all(directory).unwrap();
+        expect_that!(
+            files,
+            ok(unordered_elements_are![
+                field!(TemplateFile.path, eq("send-error.html")),
+                field!(TemplateFile.path, eq("sites/example.com/send-error.html")),
+            ])
+        );
+    }
+
+    #[googletest::test]
+    fn treats_s3_prefix_as_folder() {
+        expect_that!(
+            parse_s3_prefix("s3://bucket/templates"),
+            some(eq(("bucket".to_string(), "templates/".to_string())))
+        );
+        expect_that!(
+            parse_s3_prefix("s3://bucket/templates/"),
+            some(eq(("bucket".to_string(), "templates/".to_string())))
+        );
+        expect_that!(
+            parse_s3_prefix("s3://bucket"),
+            some(eq(("bucket".to_string(), "".to_string())))
+        );
+        expect_that!(parse_s3_prefix("https://bucket/templates"), none());
+    }
+}
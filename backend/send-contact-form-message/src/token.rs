@@ -0,0 +1,212 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const TOKEN_KEY_NAME: &str = "token-signing-key";
+
+const DEFAULT_RETRY_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+
+/// Returns how long a retry token stays valid, as configured by the environment variable
+/// `RETRY_TOKEN_LIFETIME_SECS`.
+pub fn retry_token_lifetime() -> Duration {
+    std::env::var("RETRY_TOKEN_LIFETIME_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_TOKEN_LIFETIME)
+}
+
+/// The key with which tokens for every [`TokenPurpose`] are signed, stored in the secret
+/// `token-signing-key`.
+#[derive(Deserialize)]
+pub struct TokenKey {
+    #[serde(rename = "TOKEN_SIGNING_KEY")]
+    key: String,
+}
+
+/// What a token is for. Tokens are only accepted for the purpose for which they were issued, so
+/// that one kind of token cannot stand in for another which happens to have a compatible payload.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenPurpose {
+    /// Sending a message again from the error page.
+    Retry,
+    /// Confirming a newsletter subscription.
+    Subscription,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims<T> {
+    purpose: TokenPurpose,
+    expires_at: u64,
+    payload: T,
+}
+
+impl TokenKey {
+    /// Returns a token of the form `<claims>.<signature>` which carries the payload for the given
+    /// purpose until the token expires.
+    ///
+    /// The payload is only signed, not encrypted, so it must not contain anything which the
+    /// holder of the token may not see.
+    pub fn issue<T: Serialize>(
+        &self,
+        purpose: TokenPurpose,
+        payload: T,
+        lifetime: Duration,
+    ) -> String {
+        let claims = Claims {
+            purpose,
+            expires_at: unix_time() + lifetime.as_secs(),
+            payload,
+        };
+        let claims = URL_SAFE_NO_PAD
+            .encode(serde_json::to_vec(&claims).expect("Serialising the claims should not fail"));
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&claims).finalize().into_bytes());
+        format!("{claims}.{signature}")
+    }
+
+    /// Returns the payload of the token if the token was signed with this key for the given
+    /// purpose and has not expired.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        purpose: TokenPurpose,
+        token: &str,
+    ) -> Result<T, TokenError> {
+        let (claims, signature) = token.split_once('.').ok_or(TokenError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        self.mac(claims)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+        let claims: Claims<T> = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(TokenError::Malformed)?;
+        if claims.purpose != purpose {
+            return Err(TokenError::WrongPurpose);
+        }
+        if claims.expires_at <= unix_time() {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims.payload)
+    }
+
+    fn mac(&self, claims: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.key.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(claims.as_bytes());
+        mac
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time should be after the epoch")
+        .as_secs()
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    Malformed,
+    InvalidSignature,
+    WrongPurpose,
+    Expired,
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Malformed => write!(f, "Malformed token"),
+            TokenError::InvalidSignature => write!(f, "Invalid token signature"),
+            TokenError::WrongPurpose => write!(f, "Token was issued for another purpose"),
+            TokenError::Expired => write!(f, "Expired token"),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{TokenError, TokenKey, TokenPurpose};
+    use googletest::prelude::*;
+    use std::time::Duration;
+
+    #[test]
+    fn returns_payload_of_issued_token() -> Result<()> {
+        let key = key("arbitrary key");
+        let token = key.issue(TokenPurpose::Retry, "A payload", Duration::from_secs(60));
+
+        verify_that!(
+            key.verify::<String>(TokenPurpose::Retry, &token),
+            ok(eq("A payload"))
+        )
+    }
+
+    #[test]
+    fn rejects_token_signed_with_other_key() -> Result<()> {
+        let token =
+            key("arbitrary key").issue(TokenPurpose::Retry, "A payload", Duration::from_secs(60));
+
+        verify_that!(
+            key("other key").verify::<String>(TokenPurpose::Retry, &token),
+            err(matches_pattern!(TokenError::InvalidSignature))
+        )
+    }
+
+    #[test]
+    fn rejects_token_with_altered_payload() -> Result<()> {
+        let key = key("arbitrary key");
+        let token = key.issue(TokenPurpose::Retry, "A payload", Duration::from_secs(60));
+        let (_, signature) = token.split_once('.').unwrap();
+        let other_claims = key.issue(
+            TokenPurpose::Retry,
+            "Other payload",
+            Duration::from_secs(60),
+        );
+        let (other_claims, _) = other_claims.split_once('.').unwrap();
+
+        verify_that!(
+            key.verify::<String>(TokenPurpose::Retry, &format!("{other_claims}.{signature}")),
+            err(matches_pattern!(TokenError::InvalidSignature))
+        )
+    }
+
+    #[test]
+    fn rejects_expired_token() -> Result<()> {
+        let key = key("arbitrary key");
+        let token = key.issue(TokenPurpose::Retry, "A payload", Duration::ZERO);
+
+        verify_that!(
+            key.verify::<String>(TokenPurpose::Retry, &token),
+            err(matches_pattern!(TokenError::Expired))
+        )
+    }
+
+    #[test]
+    fn rejects_token_issued_for_other_purpose() -> Result<()> {
+        let key = key("arbitrary key");
+        let token = key.issue(
+            TokenPurpose::Subscription,
+            "A payload",
+            Duration::from_secs(60),
+        );
+
+        verify_that!(
+            key.verify::<String>(TokenPurpose::Retry, &token),
+            err(matches_pattern!(TokenError::WrongPurpose))
+        )
+    }
+
+    fn key(key: &str) -> TokenKey {
+        TokenKey { key: key.into() }
+    }
+}
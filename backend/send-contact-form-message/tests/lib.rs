@@ -75,7 +75,9 @@ async fn sends_email_to_recipient() {
             status_code: some(eq(303)),
             multi_value_headers: has_entry(
                 "location".to_string(),
-                elements_are![eq("https://hovinen.tech/email-sent.html")]
+                elements_are![starts_with(
+                    "https://hovinen.tech/email-sent.html?submission="
+                )]
             ),
             error_message: none(),
         }))
@@ -0,0 +1,151 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// Verifies the `ed25519-sha256` DKIM signature of a raw message against the given base64-encoded
+/// public key, as described in RFC 6376 and RFC 8463.
+///
+/// Only relaxed body canonicalization is supported.
+pub fn verify_dkim_signature(raw_message: &str, public_key: &str) -> Result<(), String> {
+    let (header_section, body) = raw_message
+        .split_once("\r\n\r\n")
+        .ok_or("Message has no body")?;
+    let headers = split_headers(header_section);
+    let signature_header = headers
+        .iter()
+        .rev()
+        .find(|header| header_name(header).eq_ignore_ascii_case("DKIM-Signature"))
+        .ok_or("Message has no DKIM-Signature header")?;
+    let tags = parse_tags(signature_header);
+    let tag = |name: &str| {
+        tags.get(name)
+            .map(String::as_str)
+            .ok_or(format!("DKIM-Signature has no {name} tag"))
+    };
+
+    if tag("a")? != "ed25519-sha256" {
+        return Err(format!("Unsupported algorithm {}", tag("a")?));
+    }
+    let canonicalize_header = match tag("c")? {
+        "simple/relaxed" => |header: &str| header.to_string(),
+        "relaxed/relaxed" => canonicalize_header_relaxed,
+        canonicalization => return Err(format!("Unsupported canonicalization {canonicalization}")),
+    };
+
+    let body_hash = STANDARD.encode(Sha256::digest(canonicalize_body_relaxed(body)));
+    if body_hash != tag("bh")? {
+        return Err(format!(
+            "Body hash {body_hash} does not match {}",
+            tag("bh")?
+        ));
+    }
+
+    let mut signed_data = String::new();
+    let mut remaining_headers: Vec<&String> = headers.iter().collect();
+    for name in tag("h")?.split(':') {
+        // Headers are consumed from the bottom up, as required for repeated headers.
+        if let Some(index) = remaining_headers
+            .iter()
+            .rposition(|header| header_name(header).eq_ignore_ascii_case(name))
+        {
+            signed_data.push_str(&canonicalize_header(remaining_headers.remove(index)));
+        }
+    }
+    signed_data.push_str(canonicalize_header(without_signature(signature_header)?).trim_end());
+
+    let public_key: [u8; 32] = STANDARD
+        .decode(public_key)
+        .map_err(|error| error.to_string())?
+        .try_into()
+        .map_err(|_| "Public key has the wrong length")?;
+    let signature: [u8; 64] = STANDARD
+        .decode(tag("b")?)
+        .map_err(|error| error.to_string())?
+        .try_into()
+        .map_err(|_| "Signature has the wrong length")?;
+    VerifyingKey::from_bytes(&public_key)
+        .map_err(|error| error.to_string())?
+        .verify(
+            &Sha256::digest(signed_data.as_bytes()),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|error| error.to_string())
+}
+
+/// Splits the header section into unfolded headers, each retaining its line breaks.
+fn split_headers(header_section: &str) -> Vec<String> {
+    let mut headers: Vec<String> = vec![];
+    for line in header_section.split("\r\n") {
+        match headers.last_mut() {
+            Some(header) if line.starts_with([' ', '\t']) => header.push_str(line),
+            _ => headers.push(line.into()),
+        }
+        headers.last_mut().unwrap().push_str("\r\n");
+    }
+    headers
+}
+
+fn header_name(header: &str) -> &str {
+    header
+        .split_once(':')
+        .map_or(header, |(name, _)| name)
+        .trim()
+}
+
+fn parse_tags(header: &str) -> HashMap<String, String> {
+    let (_, value) = header.split_once(':').unwrap_or_default();
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    value
+        .split(';')
+        .filter_map(|tag| tag.split_once('='))
+        .map(|(name, value)| (name.into(), value.into()))
+        .collect()
+}
+
+/// Returns the header with the value of the `b` tag removed, as it was when the signature was
+/// computed.
+fn without_signature(header: &str) -> Result<&str, String> {
+    let position = header
+        .match_indices("b=")
+        .map(|(position, _)| position)
+        .find(|&position| header[..position].trim_end().ends_with([';', ':']))
+        .ok_or("DKIM-Signature has no b tag")?;
+    if header[position..].contains(';') {
+        return Err("Only a trailing b tag is supported".into());
+    }
+    Ok(&header[..position + 2])
+}
+
+fn canonicalize_header_relaxed(header: &str) -> String {
+    let (name, value) = header.split_once(':').unwrap_or((header, ""));
+    format!(
+        "{}:{}\r\n",
+        name.trim().to_lowercase(),
+        compress_whitespace(&value.replace("\r\n", "")).trim()
+    )
+}
+
+fn canonicalize_body_relaxed(body: &str) -> String {
+    let mut lines: Vec<String> = body
+        .split("\r\n")
+        .map(|line| compress_whitespace(line).trim_end().to_string())
+        .collect();
+    while lines.last().is_some_and(String::is_empty) {
+        lines.pop();
+    }
+    lines.iter().map(|line| format!("{line}\r\n")).collect()
+}
+
+/// Replaces each sequence of spaces and tabs with a single space.
+fn compress_whitespace(text: &str) -> String {
+    let mut compressed = String::new();
+    for c in text.chars() {
+        if c != ' ' && c != '\t' {
+            compressed.push(c);
+        } else if !compressed.ends_with(' ') {
+            compressed.push(' ');
+        }
+    }
+    compressed
+}
@@ -61,13 +61,27 @@ impl FakeFriendlyCaptcha {
     }
 
     pub async fn serve(self) {
-        let app = Router::new()
-            .route(VERIFY_PATH, post(verify))
-            .with_state(self);
-        let listener = TcpListener::bind(format!("0.0.0.0:{FRIENDLYCAPTCHA_PORT}"))
+        let listener = Self::bind().await;
+        axum::serve(listener, self.into_router()).await.unwrap();
+    }
+
+    /// Starts serving in the background, returning once the server accepts connections.
+    pub async fn start(self) {
+        let listener = Self::bind().await;
+        let app = self.into_router();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    }
+
+    async fn bind() -> TcpListener {
+        TcpListener::bind(format!("0.0.0.0:{FRIENDLYCAPTCHA_PORT}"))
             .await
-            .unwrap();
-        axum::serve(listener, app).await.unwrap();
+            .unwrap()
+    }
+
+    fn into_router(self) -> Router {
+        Router::new()
+            .route(VERIFY_PATH, post(verify))
+            .with_state(self)
     }
 
     pub fn require_solution(self, required_solution: impl AsRef<str>) -> Self {
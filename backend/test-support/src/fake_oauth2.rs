@@ -0,0 +1,65 @@
+use axum::{extract::State, http::StatusCode, routing::post, Form, Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+const OAUTH2_PORT: u16 = 5285;
+const TOKEN_PATH: &str = "/token";
+
+pub const FAKE_ACCESS_TOKEN: &str = "fake access token";
+pub const FAKE_ACCESS_TOKEN_LIFETIME_SECS: u64 = 3600;
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    grant_type: String,
+    refresh_token: String,
+}
+
+/// A fake OAuth2 token endpoint which exchanges a given refresh token for [`FAKE_ACCESS_TOKEN`].
+pub struct FakeOAuth2Server {
+    refresh_token: Arc<String>,
+}
+
+impl FakeOAuth2Server {
+    pub fn new(refresh_token: impl Into<String>) -> Self {
+        Self {
+            refresh_token: Arc::new(refresh_token.into()),
+        }
+    }
+
+    pub async fn start(self) {
+        let app = Router::new()
+            .route(TOKEN_PATH, post(issue_token))
+            .with_state(self.refresh_token);
+        let listener = TcpListener::bind(format!("0.0.0.0:{OAUTH2_PORT}"))
+            .await
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    }
+
+    pub fn token_url() -> String {
+        format!("http://localhost:{OAUTH2_PORT}{TOKEN_PATH}")
+    }
+}
+
+async fn issue_token(
+    State(refresh_token): State<Arc<String>>,
+    Form(request): Form<TokenRequest>,
+) -> (StatusCode, Json<Value>) {
+    if request.grant_type == "refresh_token" && request.refresh_token == *refresh_token {
+        (
+            StatusCode::OK,
+            Json(json!({
+                "access_token": FAKE_ACCESS_TOKEN,
+                "token_type": "Bearer",
+                "expires_in": FAKE_ACCESS_TOKEN_LIFETIME_SECS,
+            })),
+        )
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+    }
+}
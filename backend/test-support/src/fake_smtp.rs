@@ -8,6 +8,8 @@ use tokio::{
 
 pub const SMTP_PORT: u16 = 4567;
 pub const POISONED_SMTP_PORT: u16 = 4568;
+/// A port on which no SMTP server is started, for simulating connection failures.
+pub const UNUSED_SMTP_PORT: u16 = 4569;
 
 #[derive(Clone)]
 struct SmtpHandler(Vec<u8>, Arc<Sender<String>>);
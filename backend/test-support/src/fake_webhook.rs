@@ -0,0 +1,74 @@
+use axum::{extract::State, http::StatusCode, routing::post, Router};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{
+    net::TcpListener,
+    sync::watch::{self, error::RecvError, Receiver, Sender},
+};
+
+const WEBHOOK_PORT: u16 = 5284;
+const WEBHOOK_PATH: &str = "/webhook";
+
+#[derive(Clone)]
+struct WebhookState {
+    sender: Arc<Sender<String>>,
+    request_count: Arc<AtomicUsize>,
+}
+
+pub struct FakeWebhook {
+    state: WebhookState,
+    receiver: tokio::sync::Mutex<Receiver<String>>,
+}
+
+impl FakeWebhook {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel("".into());
+        Self {
+            state: WebhookState {
+                sender: Arc::new(sender),
+                request_count: Default::default(),
+            },
+            receiver: tokio::sync::Mutex::new(receiver),
+        }
+    }
+
+    pub async fn start(&self) {
+        let app = Router::new()
+            .route(WEBHOOK_PATH, post(receive))
+            .with_state(self.state.clone());
+        let listener = TcpListener::bind(format!("0.0.0.0:{WEBHOOK_PORT}"))
+            .await
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    }
+
+    pub async fn last_request_body(&self) -> Result<String, RecvError> {
+        let mut receiver = self.receiver.lock().await;
+        receiver.changed().await?;
+        let content = receiver.borrow_and_update().clone();
+        drop(receiver);
+        Ok(content)
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state.request_count.load(Ordering::SeqCst)
+    }
+
+    pub fn url() -> String {
+        format!("http://localhost:{WEBHOOK_PORT}{WEBHOOK_PATH}")
+    }
+}
+
+impl Default for FakeWebhook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn receive(State(state): State<WebhookState>, body: String) -> StatusCode {
+    state.request_count.fetch_add(1, Ordering::SeqCst);
+    let _ = state.sender.send(body);
+    StatusCode::OK
+}
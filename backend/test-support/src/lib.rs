@@ -1,5 +1,8 @@
+pub mod dkim;
 pub mod fake_friendlycaptcha;
+pub mod fake_oauth2;
 pub mod fake_smtp;
+pub mod fake_webhook;
 pub mod localstack_config;
 pub mod secrets;
 
@@ -12,7 +15,7 @@ use std::borrow::Cow;
 // is a fixed IP address for Docker in Linux.
 pub const HOST_IP: &str = "172.17.0.1";
 
-pub fn clean_payload(raw: &str) -> Cow<str> {
+pub fn clean_payload(raw: &str) -> Cow<'_, str> {
     let line_break = Regex::new("\n +").unwrap();
     line_break.replace_all(raw, "")
 }
@@ -26,3 +29,25 @@ pub fn setup_logging() {
     )])
     .unwrap();
 }
+
+/// Sets an environment variable for the lifetime of this object, restoring the previous value
+/// when dropped.
+pub struct TemporaryEnv(&'static str, Option<String>);
+
+impl TemporaryEnv {
+    pub fn new(key: &'static str, value: impl AsRef<str>) -> Self {
+        let old_value = std::env::var(key).ok();
+        std::env::set_var(key, value.as_ref());
+        Self(key, old_value)
+    }
+}
+
+impl Drop for TemporaryEnv {
+    fn drop(&mut self) {
+        if let Some(value) = self.1.as_ref() {
+            std::env::set_var(self.0, value);
+        } else {
+            std::env::remove_var(self.0);
+        }
+    }
+}